@@ -3,6 +3,9 @@
 //! Implements two-phase matching:
 //! 1. Top-down: Match identical subtrees by hash
 //! 2. Bottom-up: Match remaining nodes by structural similarity
+//!
+//! If the trees provide identity keys (see [`DiffTree::identity_key`]), a keyed
+//! phase runs first and pairs nodes with the same unique key.
 
 use crate::{debug, trace};
 
@@ -173,8 +176,12 @@ where
     );
     let mut matching = Matching::new();
 
+    // Phase 0: Keyed matching (nodes with the same unique identity key)
+    let keyed_pairs = keyed_phase(tree_a, tree_b, &mut matching);
+    debug!(matched = matching.len(), "after keyed_phase");
+
     // Phase 1: Top-down matching (identical subtrees by hash)
-    top_down_phase(tree_a, tree_b, &mut matching, config);
+    top_down_phase(tree_a, tree_b, &mut matching, &keyed_pairs, config);
     debug!(matched = matching.len(), "after top_down_phase");
 
     // Phase 2: Bottom-up matching (similar nodes by Dice coefficient)
@@ -184,15 +191,102 @@ where
     matching
 }
 
+/// Phase 0: Keyed matching.
+///
+/// Pairs non-root nodes of the same kind that share an identity key, as long as
+/// that key appears exactly once in each tree. Duplicate keys are ignored, since
+/// they don't identify anything. Nodes inside opaque subtrees are never keyed.
+///
+/// Returns the keyed pairs so the top-down phase can descend into them.
+fn keyed_phase<TA, TB>(tree_a: &TA, tree_b: &TB, matching: &mut Matching) -> Vec<(NodeId, NodeId)>
+where
+    TA: DiffTree,
+    TB: DiffTree<Types = TA::Types>,
+{
+    let keys_a = unique_keys(tree_a);
+    if keys_a.is_empty() {
+        return Vec::new();
+    }
+    let keys_b = unique_keys(tree_b);
+
+    let mut pairs = Vec::new();
+    for a_id in tree_a.iter() {
+        let Some(key) = tree_a.identity_key(a_id) else {
+            continue;
+        };
+        let (Some(Some(a)), Some(Some(b_id))) = (keys_a.get(key), keys_b.get(key)) else {
+            continue;
+        };
+        if *a != a_id || tree_a.kind(a_id) != tree_b.kind(*b_id) {
+            continue;
+        }
+        if matching.contains_a(a_id) || matching.contains_b(*b_id) {
+            continue;
+        }
+
+        trace!(
+            a = usize::from(a_id),
+            b = usize::from(*b_id),
+            key,
+            "keyed: match"
+        );
+        if tree_a.hash(a_id) == tree_b.hash(*b_id) {
+            match_subtrees(tree_a, tree_b, a_id, *b_id, matching);
+        } else {
+            matching.add(a_id, *b_id);
+        }
+        pairs.push((a_id, *b_id));
+    }
+
+    pairs
+}
+
+/// Map each identity key to its node, or to `None` if the key is not unique.
+fn unique_keys<T: DiffTree>(tree: &T) -> HashMap<&str, Option<NodeId>> {
+    let opaque = opaque_descendants(tree);
+    let mut keys: HashMap<&str, Option<NodeId>> = HashMap::default();
+    for id in tree.iter() {
+        if id == tree.root() || opaque.contains(&id) {
+            continue;
+        }
+        if let Some(key) = tree.identity_key(id) {
+            keys.entry(key)
+                .and_modify(|existing| *existing = None)
+                .or_insert(Some(id));
+        }
+    }
+    keys
+}
+
+/// Collect all nodes that are strict descendants of opaque nodes.
+fn opaque_descendants<T: DiffTree>(tree: &T) -> HashSet<NodeId> {
+    let mut result: HashSet<NodeId> = HashSet::default();
+    for id in tree.iter() {
+        if tree.is_opaque(id) {
+            // Skip the opaque node itself, but mark all its descendants
+            for desc in tree.descendants(id) {
+                if desc != id {
+                    result.insert(desc);
+                }
+            }
+        }
+    }
+    result
+}
+
 /// Phase 1: Top-down matching.
 ///
 /// Greedily matches nodes with identical subtree hashes, starting from the roots
 /// and working down. When two nodes have the same hash, their entire subtrees
 /// are identical and can be matched recursively.
+///
+/// Pairs already matched by the keyed phase are used as additional starting points,
+/// so their children get the same treatment as the roots'.
 fn top_down_phase<TA, TB>(
     tree_a: &TA,
     tree_b: &TB,
     matching: &mut Matching,
+    keyed_pairs: &[(NodeId, NodeId)],
     config: &MatchingConfig,
 ) where
     TA: DiffTree,
@@ -203,6 +297,7 @@ fn top_down_phase<TA, TB>(
     // Priority queue: process nodes by height (descending)
     // Higher nodes = larger subtrees = more valuable to match first
     let mut candidates: Vec<(NodeId, NodeId)> = vec![(tree_a.root(), tree_b.root())];
+    candidates.extend_from_slice(keyed_pairs);
 
     // Sort by height descending
     candidates.sort_by(|a, b| {
@@ -212,8 +307,13 @@ fn top_down_phase<TA, TB>(
     });
 
     while let Some((a_id, b_id)) = candidates.pop() {
-        // Skip if already matched
-        if matching.contains_a(a_id) || matching.contains_b(b_id) {
+        // Skip if already matched (unless the keyed phase matched them to each other,
+        // in which case we still need to look at their children)
+        let keyed = matching.get_b(a_id) == Some(b_id);
+        if keyed && tree_a.hash(a_id) == tree_b.hash(b_id) {
+            continue;
+        }
+        if !keyed && (matching.contains_a(a_id) || matching.contains_b(b_id)) {
             continue;
         }
 
@@ -408,27 +508,8 @@ fn bottom_up_phase<TA, TB>(
 
     // Build a set of nodes that are descendants of opaque nodes (excluding the opaque
     // nodes themselves). These should be skipped entirely in bottom-up matching.
-    let mut opaque_descendants_a: HashSet<NodeId> = HashSet::default();
-    for a_id in tree_a.iter() {
-        if tree_a.is_opaque(a_id) {
-            // Skip the opaque node itself, but mark all its descendants
-            for desc in tree_a.descendants(a_id) {
-                if desc != a_id {
-                    opaque_descendants_a.insert(desc);
-                }
-            }
-        }
-    }
-    let mut opaque_descendants_b: HashSet<NodeId> = HashSet::default();
-    for b_id in tree_b.iter() {
-        if tree_b.is_opaque(b_id) {
-            for desc in tree_b.descendants(b_id) {
-                if desc != b_id {
-                    opaque_descendants_b.insert(desc);
-                }
-            }
-        }
-    }
+    let opaque_descendants_a = opaque_descendants(tree_a);
+    let opaque_descendants_b = opaque_descendants(tree_b);
 
     // Build index for tree B by kind (excluding opaque descendants)
     let mut b_by_kind: HashMap<<TA::Types as TreeTypes>::Kind, Vec<NodeId>> = HashMap::default();
@@ -987,4 +1068,84 @@ mod tests {
         );
         assert_eq!(matching.get_b(normal_child_a), Some(normal_child_b));
     }
+
+    /// Properties carrying only an optional identity key.
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    struct Key(Option<&'static str>);
+
+    impl Properties for Key {
+        type Key = crate::tree::NoKey;
+        type Value = crate::tree::NoVal;
+
+        fn similarity(&self, _other: &Self) -> f64 {
+            1.0
+        }
+
+        fn diff(
+            &self,
+            _other: &Self,
+        ) -> Vec<crate::tree::PropertyInFinalState<Self::Key, Self::Value>> {
+            Vec::new()
+        }
+
+        fn is_empty(&self) -> bool {
+            self.0.is_none()
+        }
+
+        fn len(&self) -> usize {
+            usize::from(self.0.is_some())
+        }
+
+        fn identity_key(&self) -> Option<&str> {
+            self.0
+        }
+    }
+
+    type KeyedTypes = SimpleTypes<&'static str, Key>;
+
+    fn keyed(hash: u64, key: Option<&'static str>) -> NodeData<KeyedTypes> {
+        NodeData::element(hash.into(), "li", Key(key))
+    }
+
+    #[test]
+    fn test_keyed_reorder_matches_by_key() {
+        // Tree A: root -> [li#a (changed content), li#b]
+        // Tree B: root -> [li#b, li#a]
+        // Position-based matching would pair li#a with li#b; keys must win.
+        let mut tree_a: Tree<KeyedTypes> =
+            Tree::new(NodeData::element(100.into(), "ul", Key(None)));
+        let a_a = tree_a.add_child(tree_a.root, keyed(1, Some("a")));
+        let b_a = tree_a.add_child(tree_a.root, keyed(2, Some("b")));
+
+        let mut tree_b: Tree<KeyedTypes> =
+            Tree::new(NodeData::element(200.into(), "ul", Key(None)));
+        let b_b = tree_b.add_child(tree_b.root, keyed(3, Some("b")));
+        let a_b = tree_b.add_child(tree_b.root, keyed(4, Some("a")));
+
+        let matching = compute_matching(&tree_a, &tree_b, &MatchingConfig::default());
+
+        assert_eq!(matching.get_b(a_a), Some(a_b));
+        assert_eq!(matching.get_b(b_a), Some(b_b));
+    }
+
+    #[test]
+    fn test_keyed_duplicate_keys_are_ignored() {
+        // "dup" appears twice in tree B, so it identifies nothing and the
+        // regular phases decide (here: position-based, li#dup -> first li).
+        let mut tree_a: Tree<KeyedTypes> =
+            Tree::new(NodeData::element(100.into(), "ul", Key(None)));
+        let dup_a = tree_a.add_child(tree_a.root, keyed(1, Some("dup")));
+
+        let mut tree_b: Tree<KeyedTypes> =
+            Tree::new(NodeData::element(200.into(), "ul", Key(None)));
+        let first_b = tree_b.add_child(tree_b.root, keyed(2, Some("dup")));
+        let _second_b = tree_b.add_child(tree_b.root, keyed(3, Some("dup")));
+
+        let mut matching = Matching::new();
+        let pairs = keyed_phase(&tree_a, &tree_b, &mut matching);
+        assert!(pairs.is_empty(), "duplicate keys must not be matched");
+
+        let matching = compute_matching(&tree_a, &tree_b, &MatchingConfig::default());
+        assert_eq!(matching.get_b(dup_a), Some(first_b));
+    }
 }
@@ -132,6 +132,17 @@ pub trait DiffTree {
     fn is_opaque(&self, _id: NodeId) -> bool {
         false
     }
+
+    /// Stable identity key for this node, if it has one (e.g. an HTML `id` attribute).
+    ///
+    /// Nodes of the same kind whose key is unique within both trees are matched to
+    /// each other before any other matching happens, regardless of position. This
+    /// turns reorderings of keyed siblings into pure moves.
+    ///
+    /// Defaults to [`Properties::identity_key`] of the node's properties.
+    fn identity_key(&self, id: NodeId) -> Option<&str> {
+        self.properties(id).identity_key()
+    }
 }
 
 /// A structural hash of a node and all its descendants (Merkle-tree style).
@@ -206,6 +217,12 @@ pub trait Properties: Clone {
 
     /// Return the number of properties in this set.
     fn len(&self) -> usize;
+
+    /// Identity key derived from these properties, used for keyed matching.
+    /// Returns `None` by default, which opts the node out of the keyed pass.
+    fn identity_key(&self) -> Option<&str> {
+        None
+    }
 }
 
 /// A placeholder type for "no key" that implements Display.
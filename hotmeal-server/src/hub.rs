@@ -0,0 +1,532 @@
+//! Fan-out of live-reload events to connected browsers (requires "vox" feature,
+//! not on wasm32).
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use futures_util::future::join_all;
+
+#[cfg(feature = "tracing")]
+use tracing::debug;
+
+use crate::{LiveReloadBrowserClient, LiveReloadEvent, LiveReloadServer, LiveReloadService};
+
+/// A [`LiveReloadServer`] that also delivers its events.
+///
+/// Keeps track of which browsers subscribed to which route, and of the
+/// version of the page each of them has, and broadcasts the events
+/// [`diff_route`](Self::diff_route) produces to them, diffed from that
+/// version when needed. Browsers that can no longer be reached are dropped
+/// along the way.
+///
+/// Clones share everything. Each browser connection is served by its own
+/// handle from [`connection`](Self::connection), so that `subscribe` knows
+/// who is calling:
+///
+/// ```ignore
+/// let conn = hub.connection();
+/// let browser = vox::initiator_on(link, vox::TransportMode::Bare)
+///     .on_connection(LiveReloadServiceDispatcher::new(conn.clone()))
+///     .establish::<LiveReloadBrowserClient>()
+///     .await?;
+/// conn.attach(browser);
+/// ```
+#[derive(Clone)]
+pub struct LiveReloadHub {
+    shared: Arc<Shared>,
+    /// The browser this handle serves, for handles made by `connection()`.
+    browser: Option<Arc<Browser>>,
+}
+
+struct Shared {
+    server: Mutex<LiveReloadServer>,
+    /// Subscribed browsers per route.
+    subscribers: Mutex<HashMap<String, Vec<Subscriber>>>,
+    next_browser_id: AtomicU64,
+    /// How long a browser gets to take an event before it's dropped.
+    send_timeout: Mutex<Duration>,
+}
+
+/// Default for [`LiveReloadHub::set_send_timeout`].
+const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What became of an event sent to one browser.
+enum Delivery {
+    /// Taken; the browser's page is now at this version, if known.
+    Delivered(Option<u64>),
+    /// Not attached yet: it missed this one.
+    Skipped,
+    /// Gone, or too slow to answer.
+    Dead,
+}
+
+#[derive(Clone)]
+struct Subscriber {
+    browser: Arc<Browser>,
+    /// Version of the browser's page, if known.
+    version: Option<u64>,
+}
+
+struct Browser {
+    id: u64,
+    /// Set by `attach()`, once the connection is established.
+    client: OnceLock<LiveReloadBrowserClient>,
+}
+
+impl Browser {
+    /// Whether the connection is known to be gone. A browser that isn't
+    /// attached yet may still turn up.
+    fn is_dead(&self) -> bool {
+        self.client
+            .get()
+            .is_some_and(|client| !client.caller.is_connected())
+    }
+}
+
+impl LiveReloadHub {
+    pub fn new() -> Self {
+        Self::with_server(LiveReloadServer::new())
+    }
+
+    /// A hub around an already configured server.
+    pub fn with_server(server: LiveReloadServer) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                server: Mutex::new(server),
+                subscribers: Mutex::new(HashMap::new()),
+                next_browser_id: AtomicU64::new(0),
+                send_timeout: Mutex::new(DEFAULT_SEND_TIMEOUT),
+            }),
+            browser: None,
+        }
+    }
+
+    /// The server behind this hub, to cache pages or set mount selectors.
+    ///
+    /// Don't hold on to it across an `.await`.
+    pub fn server(&self) -> MutexGuard<'_, LiveReloadServer> {
+        self.shared
+            .server
+            .lock()
+            .expect("live-reload server lock poisoned")
+    }
+
+    /// How long [`broadcast`](Self::broadcast) waits for a browser to take an
+    /// event before dropping it. Five seconds by default.
+    pub fn set_send_timeout(&self, timeout: Duration) {
+        *self
+            .shared
+            .send_timeout
+            .lock()
+            .expect("live-reload send timeout lock poisoned") = timeout;
+    }
+
+    /// A handle to serve one browser connection with.
+    ///
+    /// Use it as that connection's `LiveReloadService`, then
+    /// [`attach`](Self::attach) the browser's client to it.
+    pub fn connection(&self) -> Self {
+        let id = self.shared.next_browser_id.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+            browser: Some(Arc::new(Browser {
+                id,
+                client: OnceLock::new(),
+            })),
+        }
+    }
+
+    /// Give this connection handle the client to push events to its browser.
+    ///
+    /// Routes the browser subscribed to in the meantime start receiving
+    /// events from now on.
+    ///
+    /// # Panics
+    ///
+    /// If this handle doesn't come from [`connection`](Self::connection),
+    /// or already has a client.
+    pub fn attach(&self, browser: LiveReloadBrowserClient) {
+        let slot = self
+            .browser
+            .as_ref()
+            .expect("attach() needs a handle from LiveReloadHub::connection()");
+        assert!(
+            slot.client.set(browser).is_ok(),
+            "connection already has a browser attached"
+        );
+    }
+
+    /// Number of browsers subscribed to `route`.
+    pub fn subscriber_count(&self, route: &str) -> usize {
+        self.live_subscribers().get(route).map_or(0, Vec::len)
+    }
+
+    /// Drop the cached pages of routes no browser is subscribed to. Returns
+    /// how many were dropped.
+    ///
+    /// Call it now and then on sites with many pages: a route gets cached
+    /// again when it's served.
+    pub fn evict_unsubscribed(&self) -> usize {
+        let subscribed: HashSet<String> = self.live_subscribers().keys().cloned().collect();
+        self.server()
+            .retain_routes(|route| subscribed.contains(route))
+    }
+
+    /// Send `event` to every browser subscribed to `route`. Returns how many
+    /// got something.
+    ///
+    /// Browsers are sent to concurrently, and the ones that don't take the
+    /// event within the [send timeout](Self::set_send_timeout) are dropped
+    /// like unreachable ones. Needs a Tokio runtime with timers enabled.
+    ///
+    /// Patches are adapted to each browser's page, as
+    /// [`LiveReloadServer::event_for`] does: they must come from the server's
+    /// current state.
+    pub async fn broadcast(&self, route: &str, event: LiveReloadEvent) -> usize {
+        let subscribers = self.subscribers().get(route).cloned().unwrap_or_default();
+        let deliveries: Vec<_> = {
+            let server = self.server();
+            // Catching up means a reparse and a diff; do it once per version
+            let mut events: HashMap<Option<u64>, Option<LiveReloadEvent>> = HashMap::new();
+            subscribers
+                .into_iter()
+                .filter_map(|sub| {
+                    let event = events
+                        .entry(sub.version)
+                        .or_insert_with(|| server.event_for(route, &event, sub.version));
+                    Some((sub.browser, event.clone()?))
+                })
+                .collect()
+        };
+
+        let timeout = *self
+            .shared
+            .send_timeout
+            .lock()
+            .expect("live-reload send timeout lock poisoned");
+        let outcomes = join_all(deliveries.into_iter().map(|(browser, event)| async move {
+            let Some(client) = browser.client.get() else {
+                return (browser.id, Delivery::Skipped);
+            };
+            if !client.caller.is_connected() {
+                return (browser.id, Delivery::Dead);
+            }
+            let version = version_after(&event);
+            let outcome = match tokio::time::timeout(timeout, client.on_event(event)).await {
+                Ok(Ok(())) => Delivery::Delivered(version),
+                Ok(Err(_e)) => {
+                    debug!(route, error = ?_e, "browser unreachable, unsubscribing");
+                    Delivery::Dead
+                }
+                Err(_) => {
+                    debug!(route, "browser too slow to answer, unsubscribing");
+                    Delivery::Dead
+                }
+            };
+            (browser.id, outcome)
+        }))
+        .await;
+
+        let mut delivered = 0;
+        let mut dead = Vec::new();
+        for (browser_id, outcome) in outcomes {
+            match outcome {
+                Delivery::Delivered(version) => {
+                    delivered += 1;
+                    self.set_version(route, browser_id, version);
+                }
+                Delivery::Skipped => {}
+                Delivery::Dead => dead.push(browser_id),
+            }
+        }
+
+        if !dead.is_empty() {
+            self.subscribers().retain(|_, browsers| {
+                browsers.retain(|sub| !dead.contains(&sub.browser.id));
+                !browsers.is_empty()
+            });
+        }
+        delivered
+    }
+
+    /// Like [`LiveReloadServer::diff_route`], and broadcast the event, if
+    /// any, to the route's subscribers.
+    pub async fn diff_route(&self, route: &str, new_html: &str) -> Option<LiveReloadEvent> {
+        let event = self.server().diff_route(route, new_html)?;
+        self.broadcast(route, event.clone()).await;
+        Some(event)
+    }
+
+    /// Like [`LiveReloadServer::diff_route_with_head`], and broadcast the
+    /// event, if any, to the route's subscribers.
+    pub async fn diff_route_with_head(
+        &self,
+        route: &str,
+        new_html: &str,
+        head_injections: &str,
+    ) -> Option<LiveReloadEvent> {
+        let event = self
+            .server()
+            .diff_route_with_head(route, new_html, head_injections)?;
+        self.broadcast(route, event.clone()).await;
+        Some(event)
+    }
+
+    /// Record the version of the page a browser has for `route`.
+    fn set_version(&self, route: &str, browser_id: u64, version: Option<u64>) {
+        if let Some(sub) = self
+            .subscribers()
+            .get_mut(route)
+            .and_then(|browsers| browsers.iter_mut().find(|sub| sub.browser.id == browser_id))
+        {
+            sub.version = version;
+        }
+    }
+
+    /// The subscribers, without the browsers known to be gone.
+    fn live_subscribers(&self) -> MutexGuard<'_, HashMap<String, Vec<Subscriber>>> {
+        let mut subscribers = self.subscribers();
+        subscribers.retain(|_, browsers| {
+            browsers.retain(|sub| !sub.browser.is_dead());
+            !browsers.is_empty()
+        });
+        subscribers
+    }
+
+    fn subscribers(&self) -> MutexGuard<'_, HashMap<String, Vec<Subscriber>>> {
+        self.shared
+            .subscribers
+            .lock()
+            .expect("live-reload subscribers lock poisoned")
+    }
+}
+
+impl Default for LiveReloadHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LiveReloadService for LiveReloadHub {
+    async fn subscribe(&self, route: String, version: Option<u64>) -> Option<LiveReloadEvent> {
+        let Some(browser) = &self.browser else {
+            debug!(route, "subscribe on a hub that isn't serving a connection");
+            return None;
+        };
+        debug!(route, browser = browser.id, version, "browser subscribed");
+        // Hold on to the subscribers while catching up, so that a broadcast
+        // can't slip in between and find the browser at the wrong version
+        let mut subscribers = self.subscribers();
+        let catch_up = self.server().catch_up(&route, version);
+        let version = catch_up.as_ref().map_or(version, version_after);
+        let browsers = subscribers.entry(route).or_default();
+        match browsers.iter_mut().find(|sub| sub.browser.id == browser.id) {
+            Some(sub) => sub.version = version,
+            None => browsers.push(Subscriber {
+                browser: browser.clone(),
+                version,
+            }),
+        }
+        catch_up
+    }
+}
+
+/// Version of a browser's page once it handled `event`, if known.
+fn version_after(event: &LiveReloadEvent) -> Option<u64> {
+    match event {
+        LiveReloadEvent::Patches { to_version, .. } => Some(*to_version),
+        LiveReloadEvent::Synced { version, .. } => Some(*version),
+        // The page reloads, and subscribes again
+        LiveReloadEvent::Reload | LiveReloadEvent::HeadChanged { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        LiveReloadBrowser, LiveReloadBrowserDispatcher, LiveReloadServiceClient,
+        LiveReloadServiceDispatcher,
+    };
+
+    /// A browser that records the events it gets.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<LiveReloadEvent>>>);
+
+    impl Recorder {
+        fn count(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+    }
+
+    impl LiveReloadBrowser for Recorder {
+        async fn on_event(&self, event: LiveReloadEvent) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    /// A browser that never finishes taking an event.
+    #[derive(Clone)]
+    struct Stalled;
+
+    impl LiveReloadBrowser for Stalled {
+        async fn on_event(&self, _event: LiveReloadEvent) {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Connect a browser to `hub` over an in-memory link and subscribe it to `route`.
+    async fn connect<B: LiveReloadBrowser + Clone + Send + Sync + 'static>(
+        hub: &LiveReloadHub,
+        recorder: &B,
+        route: &str,
+    ) -> LiveReloadServiceClient {
+        connect_at(hub, recorder, route, None).await.0
+    }
+
+    /// Like `connect`, for a browser whose page is at `version`. Also returns
+    /// the catch-up `subscribe` answered with.
+    async fn connect_at<B: LiveReloadBrowser + Clone + Send + Sync + 'static>(
+        hub: &LiveReloadHub,
+        recorder: &B,
+        route: &str,
+        version: Option<u64>,
+    ) -> (LiveReloadServiceClient, Option<LiveReloadEvent>) {
+        let (server_link, browser_link) = vox::memory_link_pair(16);
+        let conn = hub.connection();
+        let dispatcher = LiveReloadServiceDispatcher::new(conn.clone());
+        let server = tokio::spawn(async move {
+            vox::initiator_on(server_link, vox::TransportMode::Bare)
+                .on_connection(dispatcher)
+                .establish::<LiveReloadBrowserClient>()
+                .await
+                .expect("server establish")
+        });
+        let service = vox::acceptor_on(browser_link)
+            .on_connection(LiveReloadBrowserDispatcher::new(recorder.clone()))
+            .establish::<LiveReloadServiceClient>()
+            .await
+            .expect("browser establish");
+        conn.attach(server.await.expect("server task"));
+        let catch_up = service
+            .subscribe(route.to_owned(), version)
+            .await
+            .expect("subscribe");
+        (service, catch_up)
+    }
+
+    #[tokio::test]
+    async fn diff_route_reaches_subscribers_of_the_route() {
+        let hub = LiveReloadHub::new();
+        hub.server().cache_html("/", "<p>hello</p>");
+        let (a, b, other) = (
+            Recorder::default(),
+            Recorder::default(),
+            Recorder::default(),
+        );
+        let _a = connect(&hub, &a, "/").await;
+        let _b = connect(&hub, &b, "/").await;
+        let _other = connect(&hub, &other, "/other").await;
+        assert_eq!(hub.subscriber_count("/"), 2);
+
+        let event = hub.diff_route("/", "<p>world</p>").await;
+        assert!(matches!(event, Some(LiveReloadEvent::Patches { .. })));
+        assert_eq!((a.count(), b.count(), other.count()), (1, 1, 0));
+
+        // Unchanged: nothing to send
+        assert!(hub.diff_route("/", "<p>world</p>").await.is_none());
+        assert_eq!(a.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn subscribe_catches_up() {
+        let hub = LiveReloadHub::new();
+        let recorder = Recorder::default();
+        let (_, catch_up) = connect_at(&hub, &recorder, "/", None).await;
+        assert!(catch_up.is_none(), "nothing cached yet");
+
+        hub.server().cache_html("/", "<p>hello</p>");
+        let old = hub.server().version("/");
+        hub.diff_route("/", "<p>world</p>").await;
+
+        let (_, catch_up) = connect_at(&hub, &recorder, "/", old).await;
+        assert!(matches!(
+            catch_up,
+            Some(LiveReloadEvent::Patches { from_version, .. }) if Some(from_version) == old
+        ));
+        let (_, catch_up) = connect_at(&hub, &recorder, "/", None).await;
+        assert!(matches!(
+            catch_up,
+            Some(LiveReloadEvent::Synced { version, .. }) if Some(version) == hub.server().version("/")
+        ));
+    }
+
+    #[tokio::test]
+    async fn late_joiner_gets_patches_from_its_version() {
+        let hub = LiveReloadHub::new();
+        hub.server().cache_html("/", "<p>one</p>");
+        let v1 = hub.server().version("/");
+        let (early, also_early, late) = (
+            Recorder::default(),
+            Recorder::default(),
+            Recorder::default(),
+        );
+        let _early = connect_at(&hub, &early, "/", v1).await;
+        let _also_early = connect_at(&hub, &also_early, "/", v1).await;
+
+        // Served again after a rebuild nobody was told about
+        hub.server().cache_html("/", "<p>two</p>");
+        let v2 = hub.server().version("/");
+        let _late = connect_at(&hub, &late, "/", v2).await;
+
+        hub.diff_route("/", "<p>three</p>").await;
+        let from = |recorder: &Recorder| match recorder.0.lock().unwrap().as_slice() {
+            [LiveReloadEvent::Patches { from_version, .. }] => Some(*from_version),
+            other => panic!("expected one Patches, got {other:?}"),
+        };
+        // Browsers at the same version share one catch-up
+        assert_eq!((from(&early), from(&also_early), from(&late)), (v1, v1, v2));
+    }
+
+    #[tokio::test]
+    async fn broadcast_drops_disconnected_browsers() {
+        let hub = LiveReloadHub::new();
+        let (a, b) = (Recorder::default(), Recorder::default());
+        let _a = connect(&hub, &a, "/").await;
+        let b_service = connect(&hub, &b, "/").await;
+
+        drop(b_service);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        assert_eq!(hub.broadcast("/", LiveReloadEvent::Reload).await, 1);
+        assert_eq!(hub.subscriber_count("/"), 1);
+        assert_eq!((a.count(), b.count()), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn broadcast_drops_browsers_that_stall() {
+        let hub = LiveReloadHub::new();
+        hub.set_send_timeout(std::time::Duration::from_millis(50));
+        let recorder = Recorder::default();
+        let _stalled = connect(&hub, &Stalled, "/").await;
+        let _recorder = connect(&hub, &recorder, "/").await;
+
+        assert_eq!(hub.broadcast("/", LiveReloadEvent::Reload).await, 1);
+        assert_eq!(hub.subscriber_count("/"), 1);
+        assert_eq!(recorder.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn evict_unsubscribed_keeps_watched_routes() {
+        let hub = LiveReloadHub::new();
+        hub.server().cache_html("/watched", "<p>a</p>");
+        hub.server().cache_html("/alone", "<p>b</p>");
+        let recorder = Recorder::default();
+        let _service = connect(&hub, &recorder, "/watched").await;
+
+        assert_eq!(hub.evict_unsubscribed(), 1);
+        assert_eq!(hub.server().cached_routes(), ["/watched"]);
+    }
+}
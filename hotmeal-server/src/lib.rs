@@ -5,13 +5,23 @@
 //! `LiveReloadEvent` messages that can be serialized and sent over any transport
 //! (WebSocket, SSE, etc.).
 //!
+//! With the `vox` feature, `LiveReloadHub` also delivers those events: it serves
+//! `LiveReloadService` to every connected browser and broadcasts each route's
+//! events to the browsers subscribed to it.
+//!
+//! Events can also be streamed as Server-Sent Events, for when WebSockets
+//! don't get through: see `encode_sse` and `SseBacklog`.
+//!
 //! The client side lives in `hotmeal-wasm` which deserializes events and applies
 //! patches to a mount point in the browser DOM.
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::Hasher;
 
 use facet::Facet;
-use hotmeal::StrTendril;
+use hotmeal::{Document, NodeKind, QualName, Selector, SelectorError, Stem, StrTendril};
+use rapidhash::RapidHasher;
 
 #[cfg(feature = "tracing")]
 use tracing::debug;
@@ -21,6 +31,21 @@ macro_rules! debug {
     ($($tt:tt)*) => {};
 }
 
+mod sse;
+
+pub use sse::{
+    SSE_CONTENT_TYPE, SSE_KEEPALIVE, SseBacklog, SseDecodeError, SseQuery, decode_sse_data,
+    encode_sse,
+};
+
+// The hub serves browsers, so it has no use in a wasm client (whose vox
+// clients aren't `Send` anyway)
+#[cfg(all(feature = "vox", not(target_arch = "wasm32")))]
+mod hub;
+
+#[cfg(all(feature = "vox", not(target_arch = "wasm32")))]
+pub use hub::LiveReloadHub;
+
 // ============================================================================
 // RPC Service Definitions (requires "vox" feature)
 // ============================================================================
@@ -38,13 +63,17 @@ pub trait LiveReloadBrowser {
 
 /// Service implemented by the server, called by the browser to subscribe.
 ///
-/// After the browser calls `subscribe(route)`, the server will push
+/// After the browser calls `subscribe(route, version)`, the server will push
 /// `LiveReloadEvent`s via `LiveReloadBrowser::on_event()` on that connection.
 #[cfg(feature = "vox")]
 #[vox::service]
 pub trait LiveReloadService {
     /// Subscribe to live-reload events for a route.
-    async fn subscribe(&self, route: String);
+    ///
+    /// `version` is the version of the browser's page, if it knows it (from
+    /// `loader_script_with_version` or an earlier event). Returns what it
+    /// takes to bring the page up to date, as `LiveReloadServer::catch_up` does.
+    async fn subscribe(&self, route: String, version: Option<u64>) -> Option<LiveReloadEvent>;
 }
 
 // ============================================================================
@@ -55,18 +84,28 @@ pub trait LiveReloadService {
 ///
 /// These are serialized with postcard and sent to the browser client,
 /// which deserializes them in `hotmeal-wasm`.
+///
+/// Pages are versioned (see `LiveReloadServer::version`): patches only apply
+/// to the page at `from_version`, and a client that is somewhere else must
+/// catch up first instead of corrupting its DOM.
 #[derive(Debug, Clone, Facet)]
 #[repr(u8)]
 pub enum LiveReloadEvent {
     /// Full page reload needed.
     Reload,
-    /// DOM patches for a route (postcard-serialized `Vec<Patch<'static>>`).
+    /// DOM patches for a route (postcard-serialized `Vec<Patch<'static>>`),
+    /// taking its page from `from_version` to `to_version`.
     Patches {
         route: String,
+        from_version: u64,
+        to_version: u64,
         patches_blob: Vec<u8>,
     },
-    /// Head injections changed — full reload required.
+    /// A head `<script>` changed — full reload required.
     HeadChanged { route: String },
+    /// The route's page is at `version`. Sent to a client catching up that
+    /// has nothing to apply.
+    Synced { route: String, version: u64 },
 }
 
 impl LiveReloadEvent {
@@ -81,143 +120,460 @@ impl LiveReloadEvent {
     }
 }
 
+/// Limits on what a [`LiveReloadServer`] keeps in memory.
+///
+/// [`CacheOptions::default()`] is what [`LiveReloadServer::new`] uses: every
+/// route is kept, with 8 previous versions, as HTML only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheOptions {
+    /// Most routes to keep. Past that, the least recently used route is
+    /// dropped, and its next change sends `Reload`.
+    pub max_routes: Option<usize>,
+
+    /// Most bytes of HTML and head injections to keep, history included,
+    /// dropping the least recently used routes first.
+    pub max_bytes: Option<usize>,
+
+    /// Previous versions of each page to keep, for clients to catch up from.
+    /// Clients at an older version get `Reload`.
+    pub history_len: usize,
+
+    /// Also keep each route's current page parsed, so that diffing a change
+    /// only parses the new page. A parsed page takes several times the memory
+    /// of its HTML, which `max_bytes` doesn't count.
+    pub keep_parsed: bool,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            max_routes: None,
+            max_bytes: None,
+            history_len: 8,
+            keep_parsed: false,
+        }
+    }
+}
+
 /// Server-side live-reload state.
 ///
 /// Caches HTML and head injections per route, diffs new HTML against the cache,
 /// and produces `LiveReloadEvent` messages.
 ///
+/// It also keeps the last few versions of each page, so that a client that
+/// missed events can [`catch_up`](Self::catch_up). How much is kept is up to
+/// its [`CacheOptions`].
+///
 /// Transport-agnostic — callers are responsible for delivering events to clients.
 pub struct LiveReloadServer {
-    /// Cached HTML per route.
-    html_cache: HashMap<String, String>,
-    /// Cached head injections per route.
-    head_cache: HashMap<String, String>,
+    /// What's cached per route.
+    routes: HashMap<String, RouteCache>,
+    /// Mount point per route, for routes that only patch part of the page.
+    mount_selectors: HashMap<String, Selector>,
+    options: CacheOptions,
+    /// Ticks every time a route is used, to tell the least recently used.
+    clock: u64,
+    /// Routes by when they were last used, least recently used first.
+    lru: BTreeMap<u64, String>,
+    /// Bytes of all routes, as counted in their `counted_bytes`.
+    bytes: usize,
+}
+
+/// What's cached for a route.
+#[derive(Default)]
+struct RouteCache {
+    /// HTML of the current page, once it was served or diffed.
+    html: Option<String>,
+    /// Head injections of the current page.
+    injections: String,
+    /// The current page, parsed, with `keep_parsed`.
+    parsed: Option<Document<'static>>,
+    /// Previous versions of the page, oldest first.
+    history: VecDeque<PastPage>,
+    /// The clock when the route was last used.
+    last_used: u64,
+    /// What the route adds to `LiveReloadServer::bytes`, brought up to date
+    /// by `enforce_limits` after every change.
+    counted_bytes: usize,
+}
+
+/// A version of a route's page that has since changed.
+struct PastPage {
+    version: u64,
+    html: String,
+    injections: String,
+}
+
+impl RouteCache {
+    fn version(&self) -> Option<u64> {
+        let html = self.html.as_deref()?;
+        Some(page_version(html, &self.injections))
+    }
+
+    fn bytes(&self) -> usize {
+        let past: usize = self
+            .history
+            .iter()
+            .map(|page| page.html.len() + page.injections.len())
+            .sum();
+        self.html.as_ref().map_or(0, String::len) + self.injections.len() + past
+    }
+
+    /// Add the current page to the history, before it changes.
+    fn remember(&mut self, history_len: usize) {
+        self.parsed = None;
+        let Some(html) = &self.html else {
+            return;
+        };
+        if history_len == 0 {
+            return;
+        }
+        let page = PastPage {
+            version: page_version(html, &self.injections),
+            html: html.clone(),
+            injections: self.injections.clone(),
+        };
+        while self.history.len() >= history_len {
+            self.history.pop_front();
+        }
+        self.history.push_back(page);
+    }
 }
 
 impl LiveReloadServer {
     pub fn new() -> Self {
+        Self::with_options(CacheOptions::default())
+    }
+
+    /// A server that keeps what `options` allow.
+    pub fn with_options(options: CacheOptions) -> Self {
         Self {
-            html_cache: HashMap::new(),
-            head_cache: HashMap::new(),
+            routes: HashMap::new(),
+            mount_selectors: HashMap::new(),
+            options,
+            clock: 0,
+            lru: BTreeMap::new(),
+            bytes: 0,
         }
     }
 
+    /// Only diff the first element matching `selector` on `route`.
+    ///
+    /// Use the same selector as the client's mount point: patches are rooted
+    /// at that element instead of `<body>`. Changes elsewhere in the body are
+    /// left alone, a changed `<head>` sends `Reload`, and so does a page
+    /// where nothing matches.
+    pub fn set_mount_selector(&mut self, route: &str, selector: &str) -> Result<(), SelectorError> {
+        let selector = Selector::parse(selector)?;
+        self.mount_selectors.insert(route.to_owned(), selector);
+        Ok(())
+    }
+
+    /// Go back to diffing the whole page for `route`. Returns true if it had
+    /// a mount selector.
+    pub fn clear_mount_selector(&mut self, route: &str) -> bool {
+        self.mount_selectors.remove(route).is_some()
+    }
+
     /// Cache HTML for a route (call when serving). Returns previous HTML if any.
     pub fn cache_html(&mut self, route: &str, html: &str) -> Option<String> {
-        self.html_cache.insert(route.to_owned(), html.to_owned())
+        let history_len = self.options.history_len;
+        let entry = self.touch(route);
+        if entry.html.as_deref() == Some(html) {
+            return Some(html.to_owned());
+        }
+        entry.remember(history_len);
+        let prev = entry.html.replace(html.to_owned());
+        self.enforce_limits(route);
+        prev
     }
 
     /// Cache head injections. Returns true if they changed.
     pub fn cache_head_injections(&mut self, route: &str, injections: &str) -> bool {
-        let prev = self
-            .head_cache
-            .insert(route.to_owned(), injections.to_owned());
-        match prev {
-            Some(ref old) => old != injections,
-            None => !injections.is_empty(),
+        let history_len = self.options.history_len;
+        let entry = self.touch(route);
+        if entry.injections == injections {
+            return false;
         }
+        entry.remember(history_len);
+        entry.injections = injections.to_owned();
+        self.enforce_limits(route);
+        true
     }
 
-    /// Diff new HTML against cache. Returns event to send, or None if unchanged.
-    pub fn diff_route(&mut self, route: &str, new_html: &str) -> Option<LiveReloadEvent> {
-        let old_html = match self.html_cache.get(route) {
-            Some(old) => old.clone(),
-            None => {
-                debug!(
-                    route,
-                    "no cached HTML for route, caching and returning Reload"
-                );
-                self.html_cache
-                    .insert(route.to_owned(), new_html.to_owned());
-                return Some(LiveReloadEvent::Reload);
-            }
-        };
-
-        if old_html == new_html {
-            return None;
-        }
-
-        let old_tendril = StrTendril::from(old_html.as_str());
-        let new_tendril = StrTendril::from(new_html);
+    /// Version of the page cached for `route`, if any: a hash of its HTML and
+    /// head injections. Changes every time the page does.
+    pub fn version(&self, route: &str) -> Option<u64> {
+        self.routes.get(route)?.version()
+    }
 
-        match hotmeal::diff_html(&old_tendril, &new_tendril) {
-            Ok(patches) => {
-                if patches.is_empty() {
-                    // Diff produced no patches — HTML is semantically identical
-                    self.html_cache
-                        .insert(route.to_owned(), new_html.to_owned());
-                    return None;
+    /// What to send a client of `route` whose page is at `version`, for an
+    /// `event` that [`diff_route`](Self::diff_route) or
+    /// [`diff_route_with_head`](Self::diff_route_with_head) just produced.
+    ///
+    /// Clients don't all have the same page: one may have loaded it between
+    /// two changes, or missed an event. Patches go as they are to clients at
+    /// their `from_version` (or that don't know theirs, and will reload);
+    /// other clients get a diff from their own version instead, and `None` if
+    /// they are already up to date. Other events go to everyone.
+    pub fn event_for(
+        &self,
+        route: &str,
+        event: &LiveReloadEvent,
+        version: Option<u64>,
+    ) -> Option<LiveReloadEvent> {
+        match (event, version) {
+            (LiveReloadEvent::Patches { from_version, .. }, Some(version))
+                if version != *from_version =>
+            {
+                match self.catch_up(route, Some(version))? {
+                    LiveReloadEvent::Synced { .. } => None,
+                    event => Some(event),
                 }
+            }
+            _ => Some(event.clone()),
+        }
+    }
 
-                let owned_patches: Vec<hotmeal::Patch<'static>> =
-                    patches.into_iter().map(|p| p.into_owned()).collect();
-
-                let patches_blob = facet_postcard::to_vec(&owned_patches)
-                    .expect("patch serialization should not fail");
-
-                debug!(
-                    route,
-                    num_patches = owned_patches.len(),
-                    blob_size = patches_blob.len(),
-                    "diff produced patches"
-                );
+    /// What a client whose page is at `version` needs to get to the current
+    /// page of `route`.
+    ///
+    /// That's `Synced` if it's already there, or if it doesn't know its
+    /// version (as with clients started by [`loader_script`]), and
+    /// `Patches` from its version to the current one if that's
+    /// still in the history. Otherwise `Reload`. Returns `None` if nothing is
+    /// cached for the route.
+    pub fn catch_up(&self, route: &str, version: Option<u64>) -> Option<LiveReloadEvent> {
+        let entry = self.routes.get(route)?;
+        let html = entry.html.as_deref()?;
+        let current = page_version(html, &entry.injections);
+        let synced = LiveReloadEvent::Synced {
+            route: route.to_owned(),
+            version: current,
+        };
+        let Some(version) = version.filter(|&version| version != current) else {
+            return Some(synced);
+        };
 
-                // Update cache
-                self.html_cache
-                    .insert(route.to_owned(), new_html.to_owned());
+        let past = entry
+            .history
+            .iter()
+            .rev()
+            .find(|page| page.version == version);
+        let Some(past) = past else {
+            debug!(route, version, "version not in history, sending Reload");
+            return Some(LiveReloadEvent::Reload);
+        };
 
-                Some(LiveReloadEvent::Patches {
-                    route: route.to_owned(),
-                    patches_blob,
-                })
-            }
-            Err(_e) => {
-                debug!(route, error = %_e, "diff failed, sending Reload");
-                self.html_cache
-                    .insert(route.to_owned(), new_html.to_owned());
-                Some(LiveReloadEvent::Reload)
+        let old_tendril = StrTendril::from(page(&past.html, &past.injections).as_ref());
+        let old_doc = hotmeal::parse(&old_tendril);
+        let new_tendril;
+        let parsed;
+        let new_doc = match &entry.parsed {
+            Some(doc) => doc,
+            None => {
+                new_tendril = StrTendril::from(page(html, &entry.injections).as_ref());
+                parsed = hotmeal::parse(&new_tendril);
+                &parsed
             }
-        }
+        };
+        let event = diff_pages(
+            route,
+            (&old_doc, version),
+            (new_doc, current),
+            self.mount_selectors.get(route),
+        );
+        // Nothing to patch: the old page already looks like the current one
+        Some(event.unwrap_or(synced))
+    }
+
+    /// Diff new HTML against cache. Returns event to send, or None if unchanged.
+    pub fn diff_route(&mut self, route: &str, new_html: &str) -> Option<LiveReloadEvent> {
+        let injections = self
+            .routes
+            .get(route)
+            .map(|entry| entry.injections.clone())
+            .unwrap_or_default();
+        self.diff_page(route, new_html, &injections)
     }
 
     /// Diff with head injection tracking combined.
     ///
-    /// If head injections changed, returns `HeadChanged` (requires full reload).
-    /// Otherwise diffs body HTML and returns `Patches` or `None`.
+    /// Both the old and new pages are diffed with their head injections applied, so
+    /// changed stylesheets, titles or meta tags are patched in place. Returns
+    /// `HeadChanged` (requires full reload) only when a `<script>` in the head changed,
+    /// since patching a script into the DOM doesn't re-run it.
     pub fn diff_route_with_head(
         &mut self,
         route: &str,
         new_html: &str,
         head_injections: &str,
     ) -> Option<LiveReloadEvent> {
-        if self.cache_head_injections(route, head_injections) {
-            // Head changed — must full reload, but still update HTML cache
-            self.html_cache
-                .insert(route.to_owned(), new_html.to_owned());
-            return Some(LiveReloadEvent::HeadChanged {
-                route: route.to_owned(),
-            });
-        }
-
-        self.diff_route(route, new_html)
+        self.diff_page(route, new_html, head_injections)
     }
 
     /// All cached route keys.
     pub fn cached_routes(&self) -> Vec<String> {
-        self.html_cache.keys().cloned().collect()
+        self.routes
+            .iter()
+            .filter(|(_, entry)| entry.html.is_some())
+            .map(|(route, _)| route.clone())
+            .collect()
+    }
+
+    /// Bytes of HTML and head injections cached, history included (what
+    /// [`CacheOptions::max_bytes`] limits).
+    pub fn cached_bytes(&self) -> usize {
+        self.bytes
     }
 
     /// Remove a route from cache.
     pub fn remove_route(&mut self, route: &str) -> bool {
-        let html_removed = self.html_cache.remove(route).is_some();
-        let head_removed = self.head_cache.remove(route).is_some();
-        html_removed || head_removed
+        let Some(entry) = self.routes.remove(route) else {
+            return false;
+        };
+        self.lru.remove(&entry.last_used);
+        self.bytes -= entry.counted_bytes;
+        true
+    }
+
+    /// Remove the routes for which `keep` returns false from cache. Returns
+    /// how many were removed.
+    pub fn retain_routes(&mut self, mut keep: impl FnMut(&str) -> bool) -> usize {
+        let before = self.routes.len();
+        self.routes.retain(|route, entry| {
+            let kept = keep(route);
+            if !kept {
+                self.lru.remove(&entry.last_used);
+                self.bytes -= entry.counted_bytes;
+            }
+            kept
+        });
+        before - self.routes.len()
     }
 
     /// Clear all caches.
     pub fn clear(&mut self) {
-        self.html_cache.clear();
-        self.head_cache.clear();
+        self.routes.clear();
+        self.lru.clear();
+        self.bytes = 0;
+    }
+
+    /// The cache of `route`, created if needed, marked as just used.
+    fn touch(&mut self, route: &str) -> &mut RouteCache {
+        self.clock += 1;
+        let entry = self.routes.entry(route.to_owned()).or_default();
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(self.clock, route.to_owned());
+        entry.last_used = self.clock;
+        entry
+    }
+
+    /// Diff the page of `route` against the new one, and cache the new one.
+    fn diff_page(
+        &mut self,
+        route: &str,
+        new_html: &str,
+        injections: &str,
+    ) -> Option<LiveReloadEvent> {
+        let CacheOptions {
+            history_len,
+            keep_parsed,
+            ..
+        } = self.options;
+        self.touch(route);
+        let mount = self.mount_selectors.get(route);
+        let entry = self.routes.get_mut(route).expect("just touched");
+
+        let Some(old_html) = &entry.html else {
+            debug!(
+                route,
+                "no cached HTML for route, caching and returning Reload"
+            );
+            entry.html = Some(new_html.to_owned());
+            entry.injections = injections.to_owned();
+            self.enforce_limits(route);
+            return Some(LiveReloadEvent::Reload);
+        };
+
+        if old_html == new_html && entry.injections == injections {
+            return None;
+        }
+
+        let from_version = page_version(old_html, &entry.injections);
+        let to_version = page_version(new_html, injections);
+        let new_tendril = StrTendril::from(page(new_html, injections).as_ref());
+        let new_doc = hotmeal::parse(&new_tendril);
+        let event = {
+            let old_tendril;
+            let parsed;
+            let old_doc = match &entry.parsed {
+                Some(doc) => doc,
+                None => {
+                    old_tendril = StrTendril::from(page(old_html, &entry.injections).as_ref());
+                    parsed = hotmeal::parse(&old_tendril);
+                    &parsed
+                }
+            };
+            diff_pages(
+                route,
+                (old_doc, from_version),
+                (&new_doc, to_version),
+                mount,
+            )
+        };
+
+        // Update cache
+        entry.remember(history_len);
+        entry.html = Some(new_html.to_owned());
+        entry.injections = injections.to_owned();
+        entry.parsed = keep_parsed.then(|| new_doc.into_owned());
+        self.enforce_limits(route);
+
+        event
+    }
+
+    /// Count the bytes of `route`, which just changed, then drop the least
+    /// recently used routes (but not `route`) until the cache is within its
+    /// limits. If `route` alone is too big, drop its history.
+    fn enforce_limits(&mut self, route: &str) {
+        if let Some(entry) = self.routes.get_mut(route) {
+            let bytes = entry.bytes();
+            self.bytes = self.bytes - entry.counted_bytes + bytes;
+            entry.counted_bytes = bytes;
+        }
+
+        loop {
+            let too_many = self
+                .options
+                .max_routes
+                .is_some_and(|max| self.routes.len() > max);
+            let too_big = self.options.max_bytes.is_some_and(|max| self.bytes > max);
+            if !too_many && !too_big {
+                return;
+            }
+
+            let lru = self.lru.values().find(|other| *other != route).cloned();
+            if let Some(lru) = lru {
+                debug!(route = lru, "evicting route from cache");
+                self.remove_route(&lru);
+                continue;
+            }
+            let trimmed = if too_big {
+                self.routes.get_mut(route).and_then(|entry| {
+                    let page = entry.history.pop_front()?;
+                    let bytes = page.html.len() + page.injections.len();
+                    entry.counted_bytes -= bytes;
+                    Some(bytes)
+                })
+            } else {
+                None
+            };
+            let Some(trimmed) = trimmed else {
+                return;
+            };
+            self.bytes -= trimmed;
+        }
     }
 }
 
@@ -227,6 +583,130 @@ impl Default for LiveReloadServer {
     }
 }
 
+/// Version of a page with the given HTML and head injections.
+///
+/// Uses rapidhash, whose output is fixed, unlike the standard library's
+/// hashers: versions embedded in pages stay valid across server builds.
+fn page_version(html: &str, injections: &str) -> u64 {
+    let mut hasher = RapidHasher::default();
+    hasher.write_u64(html.len() as u64);
+    hasher.write(html.as_bytes());
+    hasher.write(injections.as_bytes());
+    hasher.finish()
+}
+
+/// The page a browser gets: `html` with the head injections.
+fn page<'h>(html: &'h str, injections: &str) -> Cow<'h, str> {
+    if injections.is_empty() {
+        Cow::Borrowed(html)
+    } else {
+        Cow::Owned(inject_into_head(html, injections))
+    }
+}
+
+/// Diff two parsed pages, each with its version, and turn the result into an
+/// event (without touching any cache).
+///
+/// With a `mount` selector, only the subtrees it selects are diffed.
+fn diff_pages(
+    route: &str,
+    (old_doc, from_version): (&Document<'_>, u64),
+    (new_doc, to_version): (&Document<'_>, u64),
+    mount: Option<&Selector>,
+) -> Option<LiveReloadEvent> {
+    if head_scripts(old_doc) != head_scripts(new_doc) {
+        debug!(route, "head scripts changed, sending HeadChanged");
+        return Some(LiveReloadEvent::HeadChanged {
+            route: route.to_owned(),
+        });
+    }
+
+    let roots = match mount {
+        Some(selector) => {
+            let old_root = selector.select(old_doc).next();
+            let new_root = selector.select(new_doc).next();
+            let (Some(old_root), Some(new_root)) = (old_root, new_root) else {
+                debug!(route, "mount point missing, sending Reload");
+                return Some(LiveReloadEvent::Reload);
+            };
+            // Only the mount point gets patched, so a head change needs a reload
+            let old_head = old_doc.head().map(|id| old_doc.serialize_inner_html(id));
+            let new_head = new_doc.head().map(|id| new_doc.serialize_inner_html(id));
+            if old_head != new_head {
+                debug!(
+                    route,
+                    "head changed outside the mount point, sending Reload"
+                );
+                return Some(LiveReloadEvent::Reload);
+            }
+            Some((old_root, new_root))
+        }
+        None => None,
+    };
+
+    let result = match roots {
+        Some((old_root, new_root)) => hotmeal::diff_subtrees(old_doc, old_root, new_doc, new_root),
+        None => hotmeal::diff(old_doc, new_doc),
+    };
+
+    match result {
+        Ok(patches) => {
+            if patches.is_empty() {
+                // Diff produced no patches — HTML is semantically identical
+                return None;
+            }
+
+            let validation = match roots {
+                Some((old_root, _)) => old_doc.validate_patches_at(old_root, &patches),
+                None => old_doc.validate_patches(&patches),
+            };
+            if let Err(_e) = validation {
+                debug!(route, error = %_e, "patches don't apply to the old page, sending Reload");
+                return Some(LiveReloadEvent::Reload);
+            }
+
+            let owned_patches: Vec<hotmeal::Patch<'static>> =
+                patches.into_iter().map(|p| p.into_owned()).collect();
+
+            let patches_blob = facet_postcard::to_vec(&owned_patches)
+                .expect("patch serialization should not fail");
+
+            debug!(
+                route,
+                num_patches = owned_patches.len(),
+                blob_size = patches_blob.len(),
+                "diff produced patches"
+            );
+
+            Some(LiveReloadEvent::Patches {
+                route: route.to_owned(),
+                from_version,
+                to_version,
+                patches_blob,
+            })
+        }
+        Err(_e) => {
+            debug!(route, error = %_e, "diff failed, sending Reload");
+            Some(LiveReloadEvent::Reload)
+        }
+    }
+}
+
+/// Attributes and contents of every `<script>` in the document's head, in order.
+fn head_scripts<'d, 'a>(doc: &'d Document<'a>) -> Vec<(&'d [(QualName, Stem<'a>)], String)> {
+    let Some(head) = doc.head() else {
+        return Vec::new();
+    };
+    head.descendants(&doc.arena)
+        .filter_map(|id| match &doc.get(id).kind {
+            NodeKind::Element(elem) if elem.tag.as_ref() == "script" => {
+                Some((elem.attrs.as_slice(), doc.serialize_inner_html(id)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
 /// Inject content after the opening `<head>` tag.
 ///
 /// Searches for `<head>` (case-insensitive) and injects `content` immediately after it.
@@ -260,17 +740,110 @@ pub fn inject_into_head(html: &str, content: &str) -> String {
 /// - `wasm_url`: URL to the `.wasm` binary
 /// - `mount_selector`: CSS selector for the mount point element (e.g. `"body"` or `"#content"`)
 /// - `ws_url`: WebSocket URL for live-reload connection (e.g. `"ws://localhost:3000/_lr"`)
+///
+/// The client doesn't know which version of the page it has, so the server
+/// takes it to be current when it subscribes: a change made in between is
+/// missed. [`loader_script_with_version`] doesn't have that problem.
+///
+/// Add the script to the page you serve, but not to the HTML or head
+/// injections you give the server: it removes itself once it ran, so that
+/// the page in the browser is the one the server diffs.
 pub fn loader_script(
     wasm_js_url: &str,
     wasm_url: &str,
     mount_selector: &str,
     ws_url: &str,
 ) -> String {
+    loader(
+        "start_live_reload",
+        wasm_js_url,
+        wasm_url,
+        mount_selector,
+        ws_url,
+        None,
+    )
+}
+
+/// Like [`loader_script`], for a page at `version`, from
+/// [`LiveReloadServer::version`].
+///
+/// The client subscribes with `version`, so it gets patches from the page it
+/// really has, even if the page changed between serving it and the client
+/// subscribing.
+pub fn loader_script_with_version(
+    wasm_js_url: &str,
+    wasm_url: &str,
+    mount_selector: &str,
+    ws_url: &str,
+    version: u64,
+) -> String {
+    loader(
+        "start_live_reload",
+        wasm_js_url,
+        wasm_url,
+        mount_selector,
+        ws_url,
+        Some(version),
+    )
+}
+
+/// Like [`loader_script`], for the Server-Sent Events transport.
+///
+/// - `sse_url`: URL of the event stream (e.g. `"/_lr/events"`); the client adds
+///   the query parameters itself
+pub fn sse_loader_script(
+    wasm_js_url: &str,
+    wasm_url: &str,
+    mount_selector: &str,
+    sse_url: &str,
+) -> String {
+    loader(
+        "start_live_reload_sse",
+        wasm_js_url,
+        wasm_url,
+        mount_selector,
+        sse_url,
+        None,
+    )
+}
+
+/// Like [`sse_loader_script`], for a page at `version`, as
+/// [`loader_script_with_version`] does.
+pub fn sse_loader_script_with_version(
+    wasm_js_url: &str,
+    wasm_url: &str,
+    mount_selector: &str,
+    sse_url: &str,
+    version: u64,
+) -> String {
+    loader(
+        "start_live_reload_sse",
+        wasm_js_url,
+        wasm_url,
+        mount_selector,
+        sse_url,
+        Some(version),
+    )
+}
+
+/// The loader `<script>`, calling the wasm export `start` with `url`,
+/// `mount_selector` and `version`, if known.
+fn loader(
+    start: &str,
+    wasm_js_url: &str,
+    wasm_url: &str,
+    mount_selector: &str,
+    url: &str,
+    version: Option<u64>,
+) -> String {
+    // The version goes as a string, since a JS number can't hold every u64
+    let version = version.map_or(String::new(), |version| format!(", \"{version}\""));
     format!(
-        r#"<script type="module">
-import init, {{ start_live_reload }} from "{wasm_js_url}";
+        r#"<script type="module" data-hotmeal-loader>
+import init, {{ {start} }} from "{wasm_js_url}";
+document.querySelector("script[data-hotmeal-loader]").remove();
 await init("{wasm_url}");
-start_live_reload("{ws_url}", "{mount_selector}");
+{start}("{url}", "{mount_selector}"{version});
 </script>"#,
     )
 }
@@ -308,13 +881,19 @@ mod tests {
     fn diff_route_produces_patches() {
         let mut server = LiveReloadServer::new();
         server.cache_html("/", "<p>hello</p>");
+        let old_version = server.version("/").unwrap();
         let event = server.diff_route("/", "<p>world</p>");
         match event {
             Some(LiveReloadEvent::Patches {
                 route,
+                from_version,
+                to_version,
                 patches_blob,
             }) => {
                 assert_eq!(route, "/");
+                assert_eq!(from_version, old_version);
+                assert_eq!(Some(to_version), server.version("/"));
+                assert_ne!(from_version, to_version);
                 assert!(!patches_blob.is_empty());
 
                 // Verify the blob deserializes
@@ -327,7 +906,33 @@ mod tests {
     }
 
     #[test]
-    fn diff_route_with_head_detects_head_change() {
+    fn diff_route_patches_keep_svg_namespace() {
+        let mut server = LiveReloadServer::new();
+        server.cache_html("/", "<p>icon</p>");
+        let event = server.diff_route("/", r#"<p>icon</p><svg><path d="M0 0"></path></svg>"#);
+        let Some(LiveReloadEvent::Patches { patches_blob, .. }) = event else {
+            panic!("expected Patches, got {event:?}");
+        };
+        let patches: Vec<hotmeal::Patch<'static>> =
+            facet_postcard::from_slice(&patches_blob).expect("should deserialize patches");
+        let inserted: Vec<_> = patches
+            .iter()
+            .filter_map(|p| match p {
+                hotmeal::Patch::InsertElement { tag, ns, .. } => Some((tag.to_string(), *ns)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            inserted,
+            [
+                ("svg".to_string(), hotmeal::Namespace::Svg),
+                ("path".to_string(), hotmeal::Namespace::Svg),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_route_with_head_patches_stylesheet_change() {
         let mut server = LiveReloadServer::new();
         server.cache_html("/", "<p>hello</p>");
         server.cache_head_injections("/", "<link rel=\"stylesheet\" href=\"a.css\">");
@@ -337,12 +942,54 @@ mod tests {
             "<p>hello</p>",
             "<link rel=\"stylesheet\" href=\"b.css\">",
         );
+        match event {
+            Some(LiveReloadEvent::Patches { patches_blob, .. }) => {
+                let patches: Vec<hotmeal::Patch<'static>> =
+                    facet_postcard::from_slice(&patches_blob).expect("should deserialize patches");
+                assert!(!patches.is_empty());
+            }
+            other => panic!("expected Patches, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn diff_route_with_head_detects_script_change() {
+        let mut server = LiveReloadServer::new();
+        let html = "<html><head></head><body><p>hello</p></body></html>";
+        server.cache_html("/", html);
+        server.cache_head_injections("/", "<script src=\"/a.js\"></script>");
+
+        let event = server.diff_route_with_head("/", html, "<script src=\"/b.js\"></script>");
         assert!(matches!(
             event,
             Some(LiveReloadEvent::HeadChanged { route }) if route == "/"
         ));
     }
 
+    #[test]
+    fn diff_route_patches_head_title() {
+        let mut server = LiveReloadServer::new();
+        server.cache_html(
+            "/",
+            "<html><head><title>old</title></head><body><p>hi</p></body></html>",
+        );
+        let event = server.diff_route(
+            "/",
+            "<html><head><title>new</title></head><body><p>hi</p></body></html>",
+        );
+        match event {
+            Some(LiveReloadEvent::Patches { patches_blob, .. }) => {
+                let patches: Vec<hotmeal::Patch<'static>> =
+                    facet_postcard::from_slice(&patches_blob).expect("should deserialize patches");
+                assert!(patches.iter().any(|p| matches!(
+                    p,
+                    hotmeal::Patch::SetText { path, .. } if path.0[0] == hotmeal::HEAD_SLOT
+                )));
+            }
+            other => panic!("expected Patches, got {other:?}"),
+        }
+    }
+
     #[test]
     fn diff_route_with_head_unchanged_diffs_body() {
         let mut server = LiveReloadServer::new();
@@ -355,6 +1002,273 @@ mod tests {
         assert!(matches!(event, Some(LiveReloadEvent::Patches { .. })));
     }
 
+    #[test]
+    fn diff_route_with_mount_selector_roots_patches_at_mount() {
+        let mut server = LiveReloadServer::new();
+        server.set_mount_selector("/", "#content").unwrap();
+        let old = r#"<nav>menu</nav><main id="content"><p>hello</p></main>"#;
+        let new = r#"<nav>other menu</nav><main id="content"><p>world</p></main>"#;
+        server.cache_html("/", old);
+
+        let event = server.diff_route("/", new);
+        let Some(LiveReloadEvent::Patches { patches_blob, .. }) = event else {
+            panic!("expected Patches, got {event:?}");
+        };
+        let patches: Vec<hotmeal::Patch<'static>> =
+            facet_postcard::from_slice(&patches_blob).expect("should deserialize patches");
+
+        let tendril = StrTendril::from(old);
+        let mut doc = hotmeal::parse(&tendril);
+        let mount = doc.select_first("#content").unwrap().unwrap();
+        doc.apply_patches_at(mount, patches).unwrap();
+        assert_eq!(doc.serialize_inner_html(mount), "<p>world</p>");
+        // The nav is outside the mount point
+        assert!(doc.to_body_html().contains("<nav>menu</nav>"));
+    }
+
+    #[test]
+    fn diff_route_with_missing_mount_returns_reload() {
+        let mut server = LiveReloadServer::new();
+        server.set_mount_selector("/", "#content").unwrap();
+        server.cache_html("/", r#"<main id="content"><p>hello</p></main>"#);
+        let event = server.diff_route("/", "<main><p>hello</p></main>");
+        assert!(matches!(event, Some(LiveReloadEvent::Reload)));
+
+        assert!(server.clear_mount_selector("/"));
+        assert!(server.set_mount_selector("/", "#").is_err());
+    }
+
+    #[test]
+    fn page_version_is_stable() {
+        // Pinned: versions are embedded in served pages and must not change
+        // between builds
+        assert_eq!(
+            page_version("<p>hello</p>", "<style></style>"),
+            12938231415223884389
+        );
+        assert_ne!(page_version("ab", ""), page_version("a", "b"));
+    }
+
+    #[test]
+    fn catch_up_from_history() {
+        let mut server = LiveReloadServer::new();
+        assert!(server.catch_up("/", None).is_none());
+
+        server.cache_html("/", "<p>one</p>");
+        let v1 = server.version("/").unwrap();
+        server.diff_route("/", "<p>two</p>");
+        server.diff_route("/", "<p>three</p>");
+        let v3 = server.version("/").unwrap();
+
+        // Up to date, or doesn't know: just the current version
+        for version in [Some(v3), None] {
+            assert!(matches!(
+                server.catch_up("/", version),
+                Some(LiveReloadEvent::Synced { version, .. }) if version == v3
+            ));
+        }
+
+        // Missed both changes: one combined diff
+        let Some(LiveReloadEvent::Patches {
+            from_version,
+            to_version,
+            patches_blob,
+            ..
+        }) = server.catch_up("/", Some(v1))
+        else {
+            panic!("expected Patches");
+        };
+        assert_eq!((from_version, to_version), (v1, v3));
+        let patches: Vec<hotmeal::Patch<'static>> =
+            facet_postcard::from_slice(&patches_blob).expect("should deserialize patches");
+        let tendril = StrTendril::from("<p>one</p>");
+        let mut doc = hotmeal::parse(&tendril);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_body_html(), "<p>three</p>");
+
+        // Unknown version
+        assert!(matches!(
+            server.catch_up("/", Some(v1 ^ v3)),
+            Some(LiveReloadEvent::Reload)
+        ));
+    }
+
+    #[test]
+    fn event_for_diffs_from_each_client_version() {
+        let mut server = LiveReloadServer::new();
+        server.cache_html("/", "<p>one</p>");
+        let v1 = server.version("/").unwrap();
+        server.cache_html("/", "<p>two</p>");
+        let v2 = server.version("/").unwrap();
+        let event = server.diff_route("/", "<p>three</p>").unwrap();
+        let v3 = server.version("/").unwrap();
+
+        // A client that loaded the page before it was served again
+        let Some(LiveReloadEvent::Patches {
+            from_version,
+            to_version,
+            ..
+        }) = server.event_for("/", &event, Some(v1))
+        else {
+            panic!("expected Patches");
+        };
+        assert_eq!((from_version, to_version), (v1, v3));
+
+        // Clients that have the page the event starts from, or don't know
+        for version in [Some(v2), None] {
+            let Some(LiveReloadEvent::Patches { from_version, .. }) =
+                server.event_for("/", &event, version)
+            else {
+                panic!("expected Patches");
+            };
+            assert_eq!(from_version, v2);
+        }
+
+        // Already up to date
+        assert!(server.event_for("/", &event, Some(v3)).is_none());
+        assert!(matches!(
+            server.event_for("/", &LiveReloadEvent::Reload, Some(v3)),
+            Some(LiveReloadEvent::Reload)
+        ));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut server = LiveReloadServer::with_options(CacheOptions {
+            history_len: 1,
+            ..CacheOptions::default()
+        });
+        server.cache_html("/", "<p>one</p>");
+        let v1 = server.version("/").unwrap();
+        server.diff_route("/", "<p>two</p>");
+        let v2 = server.version("/").unwrap();
+        server.diff_route_with_head("/", "<p>two</p>", "<title>t</title>");
+        let v3 = server.version("/").unwrap();
+        assert_ne!(v2, v3, "head injections are part of the version");
+
+        assert!(matches!(
+            server.catch_up("/", Some(v1)),
+            Some(LiveReloadEvent::Reload)
+        ));
+        assert!(matches!(
+            server.catch_up("/", Some(v2)),
+            Some(LiveReloadEvent::Patches { from_version, .. }) if from_version == v2
+        ));
+    }
+
+    #[test]
+    fn max_routes_evicts_least_recently_used() {
+        let mut server = LiveReloadServer::with_options(CacheOptions {
+            max_routes: Some(2),
+            ..CacheOptions::default()
+        });
+        server.cache_html("/a", "<p>a</p>");
+        server.cache_html("/b", "<p>b</p>");
+        // Serving /a again makes /b the least recently used
+        server.cache_html("/a", "<p>a</p>");
+        server.cache_html("/c", "<p>c</p>");
+
+        let mut routes = server.cached_routes();
+        routes.sort();
+        assert_eq!(routes, ["/a", "/c"]);
+        assert!(matches!(
+            server.diff_route("/b", "<p>b2</p>"),
+            Some(LiveReloadEvent::Reload)
+        ));
+        assert!(server.version("/a").is_none(), "evicted for /b");
+    }
+
+    #[test]
+    fn max_bytes_evicts_then_trims_history() {
+        let mut server = LiveReloadServer::with_options(CacheOptions {
+            max_bytes: Some(10),
+            ..CacheOptions::default()
+        });
+        server.cache_html("/a", "aaaaaa");
+        server.cache_html("/b", "bbbbbb");
+        assert_eq!(server.cached_routes(), ["/b"]);
+
+        // Too big with its own history: the history goes
+        let v1 = server.version("/b");
+        server.diff_route("/b", "<p>c</p>");
+        assert_eq!(server.cached_bytes(), "<p>c</p>".len());
+        assert!(matches!(
+            server.catch_up("/b", v1),
+            Some(LiveReloadEvent::Reload)
+        ));
+    }
+
+    #[test]
+    fn cached_bytes_tracks_every_change() {
+        let mut server = LiveReloadServer::with_options(CacheOptions {
+            max_routes: Some(3),
+            max_bytes: Some(200),
+            history_len: 2,
+            ..CacheOptions::default()
+        });
+        let check = |server: &LiveReloadServer| {
+            let total: usize = server.routes.values().map(RouteCache::bytes).sum();
+            assert_eq!(server.cached_bytes(), total);
+            assert_eq!(server.lru.len(), server.routes.len());
+        };
+        for i in 0..20 {
+            let route = format!("/{}", i % 5);
+            server.cache_html(&route, &"x".repeat(i * 3));
+            check(&server);
+            server.diff_route_with_head(&route, &format!("<p>{i}</p>"), "<title>t</title>");
+            check(&server);
+            server.cache_head_injections(&route, "");
+            check(&server);
+        }
+        server.retain_routes(|route| route != "/4");
+        check(&server);
+        server.remove_route("/3");
+        check(&server);
+        server.clear();
+        check(&server);
+        assert_eq!(server.cached_bytes(), 0);
+    }
+
+    #[test]
+    fn keep_parsed_gives_the_same_events() {
+        let pages = [
+            "<ul><li>one</li></ul>",
+            "<ul><li>one</li><li>two</li></ul>",
+            "<ul><li>two</li></ul>",
+        ];
+        let blobs = |keep_parsed| {
+            let mut server = LiveReloadServer::with_options(CacheOptions {
+                keep_parsed,
+                ..CacheOptions::default()
+            });
+            server.cache_html("/", pages[0]);
+            let v0 = server.version("/");
+            let mut blobs: Vec<_> = pages[1..]
+                .iter()
+                .map(|page| match server.diff_route("/", page) {
+                    Some(LiveReloadEvent::Patches { patches_blob, .. }) => patches_blob,
+                    other => panic!("expected Patches, got {other:?}"),
+                })
+                .collect();
+            match server.catch_up("/", v0) {
+                Some(LiveReloadEvent::Patches { patches_blob, .. }) => blobs.push(patches_blob),
+                other => panic!("expected Patches, got {other:?}"),
+            }
+            blobs
+        };
+        assert_eq!(blobs(true), blobs(false));
+    }
+
+    #[test]
+    fn retain_routes_works() {
+        let mut server = LiveReloadServer::new();
+        for route in ["/a", "/b", "/c"] {
+            server.cache_html(route, route);
+        }
+        assert_eq!(server.retain_routes(|route| route == "/b"), 2);
+        assert_eq!(server.cached_routes(), ["/b"]);
+    }
+
     #[test]
     fn remove_route_works() {
         let mut server = LiveReloadServer::new();
@@ -399,15 +1313,38 @@ mod tests {
             "#content",
             "ws://localhost:3000/_lr",
         );
-        assert!(script.contains("start_live_reload"));
-        assert!(script.contains("#content"));
-        assert!(script.contains("ws://localhost:3000/_lr"));
+        assert!(script.contains("import init, { start_live_reload } from \"/wasm.js\""));
+        assert!(script.contains("start_live_reload(\"ws://localhost:3000/_lr\", \"#content\");"));
+        assert!(script.contains(".remove()"));
+
+        let script = loader_script_with_version(
+            "/wasm.js",
+            "/wasm.wasm",
+            "#content",
+            "ws://localhost:3000/_lr",
+            u64::MAX,
+        );
+        assert!(script.contains(
+            "start_live_reload(\"ws://localhost:3000/_lr\", \"#content\", \"18446744073709551615\");"
+        ));
+    }
+
+    #[test]
+    fn sse_loader_script_output() {
+        let script = sse_loader_script("/wasm.js", "/wasm.wasm", "#content", "/_lr/events");
+        assert!(script.contains("start_live_reload_sse(\"/_lr/events\", \"#content\");"));
+
+        let script =
+            sse_loader_script_with_version("/wasm.js", "/wasm.wasm", "#content", "/_lr/events", 7);
+        assert!(script.contains("start_live_reload_sse(\"/_lr/events\", \"#content\", \"7\");"));
     }
 
     #[test]
     fn event_postcard_roundtrip() {
         let event = LiveReloadEvent::Patches {
             route: "/test".to_owned(),
+            from_version: 1,
+            to_version: 2,
             patches_blob: vec![1, 2, 3],
         };
         let bytes = event.to_postcard();
@@ -415,9 +1352,12 @@ mod tests {
         match decoded {
             LiveReloadEvent::Patches {
                 route,
+                from_version,
+                to_version,
                 patches_blob,
             } => {
                 assert_eq!(route, "/test");
+                assert_eq!((from_version, to_version), (1, 2));
                 assert_eq!(patches_blob, vec![1, 2, 3]);
             }
             other => panic!("expected Patches, got {other:?}"),
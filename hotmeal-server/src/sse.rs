@@ -0,0 +1,266 @@
+//! Server-Sent Events transport: `text/event-stream` frames carrying
+//! `LiveReloadEvent`s, for deployments where WebSockets don't get through.
+//!
+//! Each frame's data is the event, postcard-encoded then base64-encoded, and
+//! its id is a per-route sequence number. The `hotmeal-wasm` client
+//! (`start_live_reload_sse`) connects with three query parameters:
+//!
+//! - `route`: the page's path, URI-encoded;
+//! - `version`: the version of its page, once it knows it;
+//! - `last_event_id`: the id of the last frame it got, when it reconnects by
+//!   itself after the browser gave up. Otherwise the browser sends it in the
+//!   `Last-Event-ID` header.
+//!
+//! [`SseQuery::parse`] reads them. The first frame of a stream should be
+//! what `LiveReloadServer::catch_up` returns for the route and version: the
+//! client needs it to learn its version, and resyncs by reconnecting when a
+//! patch doesn't start from it. Alternatively, [`SseBacklog::resume`] gives
+//! the frames a client missed since `last_event_id`.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use facet::Facet;
+
+use crate::LiveReloadEvent;
+
+/// `Content-Type` of an SSE response.
+pub const SSE_CONTENT_TYPE: &str = "text/event-stream";
+
+/// A comment frame, to send now and then so that proxies keep the
+/// connection open.
+pub const SSE_KEEPALIVE: &str = ": keepalive\n\n";
+
+/// Errors from decoding the data of an SSE frame.
+#[derive(Facet, Debug, Clone, PartialEq, Eq)]
+#[facet(derive(Error))]
+#[repr(u8)]
+pub enum SseDecodeError {
+    /// frame data is not valid base64
+    InvalidBase64,
+
+    /// frame data is not a live-reload event: {message}
+    InvalidEvent { message: String },
+}
+
+/// Encode `event` as a frame with the given `id`.
+pub fn encode_sse(id: u64, event: &LiveReloadEvent) -> String {
+    let data = BASE64.encode(event.to_postcard());
+    let mut frame = String::with_capacity(data.len() + 32);
+    // base64 has no newlines, so the data fits on a single `data:` line
+    let _ = write!(frame, "id: {id}\ndata: {data}\n\n");
+    frame
+}
+
+/// Decode the data of a frame made by [`encode_sse`] (what `EventSource`
+/// hands to `onmessage` as `event.data`).
+pub fn decode_sse_data(data: &str) -> Result<LiveReloadEvent, SseDecodeError> {
+    let bytes = BASE64
+        .decode(data.trim())
+        .map_err(|_| SseDecodeError::InvalidBase64)?;
+    LiveReloadEvent::from_postcard(&bytes).map_err(|e| SseDecodeError::InvalidEvent {
+        message: e.to_string(),
+    })
+}
+
+/// What a live-reload SSE request asks for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseQuery {
+    /// The route to stream events for.
+    pub route: Option<String>,
+    /// The version of the client's page, if it knows it.
+    pub version: Option<u64>,
+    /// The id of the last frame the client got, if it's resuming.
+    pub last_event_id: Option<u64>,
+}
+
+impl SseQuery {
+    /// Read a request's query string (without the `?`) and its
+    /// `Last-Event-ID` header, which wins over the query parameter.
+    pub fn parse(query: &str, last_event_id_header: Option<&str>) -> Self {
+        let mut parsed = Self::default();
+        for pair in query.split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match key {
+                "route" => parsed.route = Some(percent_decode(value)),
+                "version" => parsed.version = value.parse().ok(),
+                "last_event_id" => parsed.last_event_id = value.parse().ok(),
+                _ => {}
+            }
+        }
+        if let Some(id) = last_event_id_header.and_then(|h| h.trim().parse().ok()) {
+            parsed.last_event_id = Some(id);
+        }
+        parsed
+    }
+}
+
+/// Decode `%XX` escapes and `+` (as produced by `encodeURIComponent` and forms).
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The last frames of a route's event stream, so that a client coming back
+/// with a `Last-Event-ID` gets what it missed.
+///
+/// Keep one per route, and send every event through it.
+#[derive(Debug, Clone)]
+pub struct SseBacklog {
+    /// Id of the next frame.
+    next_id: u64,
+    /// The most recent frames with their ids, oldest first.
+    frames: VecDeque<(u64, String)>,
+    capacity: usize,
+}
+
+impl SseBacklog {
+    /// A backlog that keeps the last `capacity` frames.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            next_id: 1,
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Id of the latest frame, 0 if there's none yet.
+    pub fn last_id(&self) -> u64 {
+        self.next_id - 1
+    }
+
+    /// Encode `event` as the next frame, and keep it. Returns the frame to
+    /// send to every connected client.
+    pub fn push(&mut self, event: &LiveReloadEvent) -> String {
+        let id = self.next_id;
+        self.next_id += 1;
+        let frame = encode_sse(id, event);
+        if self.capacity > 0 {
+            if self.frames.len() == self.capacity {
+                self.frames.pop_front();
+            }
+            self.frames.push_back((id, frame.clone()));
+        }
+        frame
+    }
+
+    /// Frames to send first to a client resuming after `last_event_id`.
+    ///
+    /// The frames it missed, if they're all still kept. Otherwise (or if the
+    /// id is from another server run) a single `Reload` frame, since patches
+    /// can't be applied to a page that missed some.
+    pub fn resume(&self, last_event_id: u64) -> Vec<String> {
+        let last_id = self.last_id();
+        if last_event_id == last_id {
+            return Vec::new();
+        }
+        let oldest_kept = self.frames.front().map_or(self.next_id, |(id, _)| *id);
+        if last_event_id < last_id && last_event_id + 1 >= oldest_kept {
+            return self
+                .frames
+                .iter()
+                .filter(|(id, _)| *id > last_event_id)
+                .map(|(_, frame)| frame.clone())
+                .collect();
+        }
+        vec![encode_sse(last_id, &LiveReloadEvent::Reload)]
+    }
+}
+
+impl Default for SseBacklog {
+    /// A backlog of 64 frames.
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_roundtrip() {
+        let event = LiveReloadEvent::Patches {
+            route: "/docs".to_owned(),
+            from_version: 3,
+            to_version: 4,
+            patches_blob: vec![0, 1, 2, 255],
+        };
+        let frame = encode_sse(7, &event);
+        let data = frame
+            .strip_prefix("id: 7\ndata: ")
+            .and_then(|rest| rest.strip_suffix("\n\n"))
+            .expect("frame should have an id and a single data line");
+        match decode_sse_data(data) {
+            Ok(LiveReloadEvent::Patches {
+                route,
+                patches_blob,
+                ..
+            }) => {
+                assert_eq!(route, "/docs");
+                assert_eq!(patches_blob, [0, 1, 2, 255]);
+            }
+            other => panic!("expected Patches, got {other:?}"),
+        }
+        assert!(matches!(
+            decode_sse_data("not base64!"),
+            Err(SseDecodeError::InvalidBase64)
+        ));
+    }
+
+    #[test]
+    fn query_parse() {
+        let query = SseQuery::parse(
+            "route=%2Fblog%2Fcaf%C3%A9&version=99&last_event_id=12&x=1",
+            None,
+        );
+        assert_eq!(query.route.as_deref(), Some("/blog/café"));
+        assert_eq!(query.version, Some(99));
+        assert_eq!(query.last_event_id, Some(12));
+
+        let query = SseQuery::parse("route=/&last_event_id=12", Some("15"));
+        assert_eq!(query.last_event_id, Some(15));
+        assert_eq!(SseQuery::parse("", None), SseQuery::default());
+    }
+
+    #[test]
+    fn backlog_resume() {
+        let mut backlog = SseBacklog::new(2);
+        let frames: Vec<String> = (0..3)
+            .map(|_| backlog.push(&LiveReloadEvent::Reload))
+            .collect();
+        assert_eq!(backlog.last_id(), 3);
+
+        assert!(backlog.resume(3).is_empty());
+        assert_eq!(backlog.resume(2), frames[2..]);
+        assert_eq!(backlog.resume(1), frames[1..]);
+        // Frame 1 is gone, and so is any id from a previous run
+        let reload = vec![encode_sse(3, &LiveReloadEvent::Reload)];
+        assert_eq!(backlog.resume(0), reload);
+        assert_eq!(backlog.resume(40), reload);
+    }
+}
@@ -19,15 +19,16 @@
 //!
 //! The mount-point variants enable applying patches to a subtree of the page,
 //! which is essential for live-reload systems where hotmeal manages only part
-//! of the document.
+//! of the document. Patches addressed to `HEAD_SLOT` always target
+//! `document.head`, whatever the mount point.
 
-use hotmeal::{InsertContent, NodeId, StrTendril, parse};
+use hotmeal::{HEAD_SLOT, InsertContent, Namespace, NodeId, QualName, StrTendril, parse};
 #[cfg(target_arch = "wasm32")]
 use hotmeal_server::LiveReloadEvent;
 use smallvec::SmallVec;
 use tracing::{debug, trace};
 use wasm_bindgen::prelude::*;
-use web_sys::{Document, Element, Node};
+use web_sys::{CharacterData, Document, Element, HtmlTemplateElement, Node};
 
 /// Initialize tracing subscriber for WASM (sends output to browser console).
 /// Call this once at startup.
@@ -169,6 +170,24 @@ pub fn apply_patches_with_slots_on(
     apply_patches_with_slots_on_root(&document, &root, patches, slots)
 }
 
+/// Check patches against a mount-point element without touching the DOM.
+///
+/// Runs [`hotmeal::Document::validate_patches`] on a mirror of the mount
+/// point's current node structure, so a stale patch list can be turned into
+/// a full reload instead of a half-patched page.
+pub fn validate_patches_on(patches: &[Patch], mount_selector: &str) -> Result<(), JsValue> {
+    mount_mirror(mount_selector)?
+        .validate_patches(patches)
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// A mirror of the mount point's current node structure, see [`mirror_dom`].
+fn mount_mirror(mount_selector: &str) -> Result<hotmeal::Document<'static>, JsValue> {
+    let document = get_document()?;
+    let root = resolve_mount_point(&document, mount_selector)?;
+    Ok(mirror_dom(&root, document.head().map(Node::from).as_ref()))
+}
+
 /// Apply postcard-serialized patches to a mount-point element.
 #[wasm_bindgen]
 pub fn apply_patches_postcard_on(
@@ -187,12 +206,43 @@ pub fn apply_patches_postcard_on(
 #[cfg(target_arch = "wasm32")]
 mod live_reload {
     use super::*;
-    use hotmeal_server::{LiveReloadBrowser, LiveReloadBrowserDispatcher, LiveReloadServiceClient};
-    use std::cell::RefCell;
+    use hotmeal_server::{
+        LiveReloadBrowser, LiveReloadBrowserDispatcher, LiveReloadServiceClient, decode_sse_data,
+    };
+    use std::cell::{Cell, RefCell};
     use std::rc::Rc;
     use vox_core::acceptor_on;
     use vox_websocket::WsLink;
 
+    /// Ends a session, so that the loop reconnects.
+    type SessionEnd = Rc<RefCell<Option<futures_channel::oneshot::Sender<()>>>>;
+
+    fn end_session(end: &SessionEnd) {
+        if let Some(done_tx) = end.borrow_mut().take() {
+            let _ = done_tx.send(());
+        }
+    }
+
+    /// What the client knows about the page it's patching.
+    #[derive(Default)]
+    struct Page {
+        /// The page's version, once known.
+        version: Cell<Option<u64>>,
+        /// The mount point's node structure, kept in step with the patches
+        /// applied to it, to check the next ones against. Built from the DOM
+        /// when missing, and dropped whenever it may have fallen out of step.
+        mirror: RefCell<Option<hotmeal::Document<'static>>>,
+    }
+
+    impl Page {
+        fn new(version: Option<u64>) -> Rc<Self> {
+            Rc::new(Self {
+                version: Cell::new(version),
+                ..Self::default()
+            })
+        }
+    }
+
     /// Browser-side implementation of the `LiveReloadBrowser` service.
     ///
     /// The server calls `on_event()` on this whenever content changes for
@@ -200,40 +250,100 @@ mod live_reload {
     #[derive(Clone)]
     struct LiveReloadBrowserImpl {
         mount_selector: String,
+        page: Rc<Page>,
+        session_end: SessionEnd,
     }
 
     impl LiveReloadBrowser for LiveReloadBrowserImpl {
         async fn on_event(&self, event: LiveReloadEvent) {
-            if let Err(e) = handle_live_reload_event(&event, &self.mount_selector) {
-                log(&format!("[hotmeal-wasm] error handling event: {e:?}"));
+            match handle_live_reload_event(&event, &self.mount_selector, &self.page) {
+                Ok(true) => {}
+                // Resubscribing with our version gets us what we missed
+                Ok(false) => end_session(&self.session_end),
+                Err(e) => log(&format!("[hotmeal-wasm] error handling event: {e:?}")),
             }
         }
     }
 
+    /// Apply `event` to `page`. Returns false if the event doesn't follow
+    /// from the page's version: the client must then resync, by subscribing
+    /// again with its version.
     fn handle_live_reload_event(
         event: &LiveReloadEvent,
         mount_selector: &str,
-    ) -> Result<(), JsValue> {
+        page: &Page,
+    ) -> Result<bool, JsValue> {
         match event {
             LiveReloadEvent::Reload => {
                 log("[hotmeal-wasm] full reload requested");
+                page.mirror.take();
                 let window = web_sys::window().ok_or_else(|| JsValue::from_str("no window"))?;
                 window.location().reload()?;
             }
             LiveReloadEvent::Patches {
                 route: _,
+                from_version,
+                to_version,
                 patches_blob,
             } => {
-                let count = apply_patches_postcard_on(patches_blob, mount_selector)?;
+                match page.version.get() {
+                    Some(current) if current == *from_version => {}
+                    Some(current) => {
+                        log(&format!(
+                            "[hotmeal-wasm] patches are for version {from_version}, page is at {current}, resyncing"
+                        ));
+                        page.mirror.take();
+                        return Ok(false);
+                    }
+                    None => {
+                        log("[hotmeal-wasm] patches for a page of unknown version, reloading");
+                        let window =
+                            web_sys::window().ok_or_else(|| JsValue::from_str("no window"))?;
+                        window.location().reload()?;
+                        return Ok(true);
+                    }
+                }
+                let patches: Vec<Patch<'static>> = facet_postcard::from_slice(patches_blob)
+                    .map_err(|e| {
+                        JsValue::from_str(&format!("Failed to deserialize patches: {e}"))
+                    })?;
+                // Taken out while in use, so that any early return drops it
+                let mut mirror = match page.mirror.take() {
+                    Some(mirror) => mirror,
+                    None => mount_mirror(mount_selector)?,
+                };
+                if let Err(e) = mirror.validate_patches(&patches) {
+                    log(&format!(
+                        "[hotmeal-wasm] patches don't fit the page ({e}), reloading"
+                    ));
+                    let window = web_sys::window().ok_or_else(|| JsValue::from_str("no window"))?;
+                    window.location().reload()?;
+                    return Ok(true);
+                }
+                let count = apply_patches_on(&patches, mount_selector)?;
+                page.version.set(Some(*to_version));
                 log(&format!("[hotmeal-wasm] applied {count} patches"));
+                match mirror.apply_patches(patches) {
+                    Ok(()) => *page.mirror.borrow_mut() = Some(mirror),
+                    Err(e) => log(&format!(
+                        "[hotmeal-wasm] mirror out of step ({e}), rebuilding it next time"
+                    )),
+                }
             }
             LiveReloadEvent::HeadChanged { route: _ } => {
                 log("[hotmeal-wasm] head changed, reloading");
+                page.mirror.take();
                 let window = web_sys::window().ok_or_else(|| JsValue::from_str("no window"))?;
                 window.location().reload()?;
             }
+            LiveReloadEvent::Synced {
+                route: _,
+                version: synced,
+            } => {
+                page.version.set(Some(*synced));
+            }
         }
-        Ok(())
+        Ok(true)
     }
 
     /// Start a live-reload connection via vox RPC over WebSocket.
@@ -241,26 +351,45 @@ mod live_reload {
     /// Connects to the server at `ws_url`, subscribes to events for the current
     /// browser route, and applies patches to the element matched by `mount_selector`.
     /// Handles reconnection with exponential backoff.
+    ///
+    /// `version` is the version of the page as served (a decimal string), as
+    /// `loader_script_with_version` embeds it. Without it, the server takes the page to be
+    /// current, and the client learns the version when subscribing.
     #[wasm_bindgen]
-    pub fn start_live_reload(ws_url: &str, mount_selector: &str) -> Result<(), JsValue> {
+    pub fn start_live_reload(
+        ws_url: &str,
+        mount_selector: &str,
+        version: Option<String>,
+    ) -> Result<(), JsValue> {
         let ws_url = ws_url.to_owned();
         let mount_selector = mount_selector.to_owned();
+        let version = parse_version(version)?;
 
         wasm_bindgen_futures::spawn_local(async move {
-            live_reload_loop(&ws_url, &mount_selector).await;
+            live_reload_loop(&ws_url, &mount_selector, version).await;
         });
 
         Ok(())
     }
 
-    async fn live_reload_loop(ws_url: &str, mount_selector: &str) {
+    fn parse_version(version: Option<String>) -> Result<Option<u64>, JsValue> {
+        version
+            .map(|v| {
+                v.parse()
+                    .map_err(|_| JsValue::from_str(&format!("invalid page version: {v:?}")))
+            })
+            .transpose()
+    }
+
+    async fn live_reload_loop(ws_url: &str, mount_selector: &str, version: Option<u64>) {
         let mut backoff_ms: u32 = 100;
         let max_backoff_ms: u32 = 5000;
+        let page = Page::new(version);
 
         loop {
             log(&format!("[hotmeal-wasm] connecting to {ws_url}"));
 
-            match live_reload_session(ws_url, mount_selector).await {
+            match live_reload_session(ws_url, mount_selector, &page).await {
                 Ok(()) => {
                     log("[hotmeal-wasm] session ended");
                     backoff_ms = 100;
@@ -291,23 +420,25 @@ mod live_reload {
     async fn live_reload_session(
         ws_url: &str,
         mount_selector: &str,
+        page: &Rc<Page>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let link = WsLink::connect(ws_url).await?;
 
-        let dispatcher = LiveReloadBrowserDispatcher::new(LiveReloadBrowserImpl {
-            mount_selector: mount_selector.to_owned(),
-        });
         let (done_tx, done_rx) = futures_channel::oneshot::channel::<()>();
-        let done_tx = Rc::new(RefCell::new(Some(done_tx)));
+        let done_tx: SessionEnd = Rc::new(RefCell::new(Some(done_tx)));
+        let browser = LiveReloadBrowserImpl {
+            mount_selector: mount_selector.to_owned(),
+            page: page.clone(),
+            session_end: done_tx.clone(),
+        };
+        let dispatcher = LiveReloadBrowserDispatcher::new(browser.clone());
         let done_tx_spawn = done_tx.clone();
         let client = acceptor_on(link)
             .spawn_fn(move |fut| {
                 let done_tx_spawn = done_tx_spawn.clone();
                 wasm_bindgen_futures::spawn_local(async move {
                     fut.await;
-                    if let Some(done_tx) = done_tx_spawn.borrow_mut().take() {
-                        let _ = done_tx.send(());
-                    }
+                    end_session(&done_tx_spawn);
                 });
             })
             .on_connection(dispatcher)
@@ -322,17 +453,162 @@ mod live_reload {
             .unwrap_or_else(|| "/".to_owned());
 
         log(&format!("[hotmeal-wasm] subscribing to route: {route}"));
-        client
-            .subscribe(route)
+        let catch_up = client
+            .subscribe(route, page.version.get())
             .await
             .map_err(|e| std::io::Error::other(format!("subscribe failed: {e:?}")))?;
         log("[hotmeal-wasm] subscribed, waiting for events");
+        if let Some(event) = catch_up {
+            browser.on_event(event).await;
+        }
 
         // Wait for session to end (connection closed by server or network)
         let _ = done_rx.await;
 
         Ok(())
     }
+
+    /// Start a live-reload connection via Server-Sent Events.
+    ///
+    /// For when WebSockets don't get through: opens an `EventSource` on `url`,
+    /// with the current browser route as the `route` query parameter, and
+    /// applies patches to the element matched by `mount_selector`.
+    ///
+    /// The browser reconnects by itself, sending `Last-Event-ID`. When it gives
+    /// up, this reconnects with exponential backoff, passing the last event id
+    /// as the `last_event_id` query parameter. Once the page's version is
+    /// known (from `version`, as in `start_live_reload`, or from the stream),
+    /// it's passed as the `version` parameter, and a patch that doesn't start
+    /// from it makes the client reconnect to resync.
+    #[wasm_bindgen]
+    pub fn start_live_reload_sse(
+        url: &str,
+        mount_selector: &str,
+        version: Option<String>,
+    ) -> Result<(), JsValue> {
+        let url = url.to_owned();
+        let mount_selector = mount_selector.to_owned();
+        let version = parse_version(version)?;
+
+        wasm_bindgen_futures::spawn_local(async move {
+            live_reload_sse_loop(&url, &mount_selector, version).await;
+        });
+
+        Ok(())
+    }
+
+    async fn live_reload_sse_loop(url: &str, mount_selector: &str, version: Option<u64>) {
+        let mut backoff_ms: u32 = 100;
+        let max_backoff_ms: u32 = 5000;
+        let last_event_id: Rc<RefCell<Option<String>>> = Rc::default();
+        let page = Page::new(version);
+
+        loop {
+            let stream_url =
+                sse_stream_url(url, page.version.get(), last_event_id.borrow().as_deref());
+            log(&format!("[hotmeal-wasm] connecting to {stream_url}"));
+
+            match live_reload_sse_session(&stream_url, mount_selector, &page, &last_event_id).await
+            {
+                Ok(received) => {
+                    log("[hotmeal-wasm] event stream closed");
+                    if received {
+                        backoff_ms = 100;
+                    }
+                }
+                Err(e) => {
+                    log(&format!("[hotmeal-wasm] event stream error: {e:?}"));
+                }
+            }
+
+            log("[hotmeal-wasm] reconnecting...");
+            sleep_ms(backoff_ms).await;
+            backoff_ms = (backoff_ms * 2).min(max_backoff_ms);
+        }
+    }
+
+    /// `url` with the `route`, `version` and `last_event_id` query parameters added.
+    fn sse_stream_url(url: &str, version: Option<u64>, last_event_id: Option<&str>) -> String {
+        let route = web_sys::window()
+            .and_then(|w| w.location().pathname().ok())
+            .unwrap_or_else(|| "/".to_owned());
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let mut stream_url = format!(
+            "{url}{separator}route={}",
+            String::from(js_sys::encode_uri_component(&route))
+        );
+        if let Some(version) = version {
+            stream_url.push_str(&format!("&version={version}"));
+        }
+        if let Some(id) = last_event_id {
+            stream_url.push_str("&last_event_id=");
+            stream_url.push_str(&String::from(js_sys::encode_uri_component(id)));
+        }
+        stream_url
+    }
+
+    /// Run one `EventSource` until the browser gives up on it. Returns whether
+    /// any event came through.
+    async fn live_reload_sse_session(
+        url: &str,
+        mount_selector: &str,
+        page: &Rc<Page>,
+        last_event_id: &Rc<RefCell<Option<String>>>,
+    ) -> Result<bool, JsValue> {
+        let source = web_sys::EventSource::new(url)?;
+        let received = Rc::new(Cell::new(false));
+        let (done_tx, done_rx) = futures_channel::oneshot::channel::<()>();
+        let done_tx: SessionEnd = Rc::new(RefCell::new(Some(done_tx)));
+
+        let on_message = {
+            let mount_selector = mount_selector.to_owned();
+            let page = page.clone();
+            let last_event_id = last_event_id.clone();
+            let received = received.clone();
+            let done_tx = done_tx.clone();
+            Closure::<dyn FnMut(web_sys::MessageEvent)>::new(
+                move |message: web_sys::MessageEvent| {
+                    received.set(true);
+                    let id = message.last_event_id();
+                    if !id.is_empty() {
+                        *last_event_id.borrow_mut() = Some(id);
+                    }
+                    let Some(data) = message.data().as_string() else {
+                        return;
+                    };
+                    let result = decode_sse_data(&data)
+                        .map_err(|e| JsValue::from_str(&e.to_string()))
+                        .and_then(|event| handle_live_reload_event(&event, &mount_selector, &page));
+                    match result {
+                        Ok(true) => {}
+                        // Reconnecting with our version gets us what we missed
+                        Ok(false) => end_session(&done_tx),
+                        Err(e) => log(&format!("[hotmeal-wasm] error handling event: {e:?}")),
+                    }
+                },
+            )
+        };
+        source.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        let on_error = {
+            let source = source.clone();
+            Closure::<dyn FnMut(web_sys::Event)>::new(move |_: web_sys::Event| {
+                if source.ready_state() == web_sys::EventSource::CLOSED {
+                    end_session(&done_tx);
+                } else {
+                    log("[hotmeal-wasm] event stream interrupted, browser is reconnecting");
+                }
+            })
+        };
+        source.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+
+        let _ = done_rx.await;
+
+        source.close();
+        source.set_onmessage(None);
+        source.set_onerror(None);
+        Ok(received.get())
+    }
 }
 
 // ============================================================================
@@ -390,10 +666,16 @@ fn apply_patches_with_slots_on_root(
     Ok(count)
 }
 
-/// Get the slot root node. Slot 0 uses the provided `root`, higher slots use stored nodes.
+/// Get the slot root node. Slot 0 uses the provided `root`, `HEAD_SLOT` uses
+/// `document.head`, higher slots use stored nodes.
 fn get_slot_root(root: &Node, slot: u32, slots: &Slots) -> Result<Node, JsValue> {
     if slot == 0 {
         Ok(root.clone())
+    } else if slot == HEAD_SLOT {
+        root.owner_document()
+            .and_then(|doc| doc.head())
+            .map(|head| head.into())
+            .ok_or_else(|| JsValue::from_str("no head"))
     } else {
         slots
             .get(slot)
@@ -415,22 +697,28 @@ fn apply_patch(
             node.set_text_content(Some(text));
         }
 
+        Patch::SpliceText {
+            path,
+            offset,
+            delete_len,
+            insert,
+        } => {
+            // replaceData keeps selections and carets outside the changed range
+            let node = find_node(root, path, slots)?;
+            let data = node
+                .dyn_ref::<CharacterData>()
+                .ok_or_else(|| JsValue::from_str("SpliceText target is not a text node"))?;
+            data.replace_data(*offset, *delete_len, insert)?;
+        }
+
         Patch::SetAttribute { path, name, value } => {
             let el = find_element(root, path, slots)?;
-            let attr_name = match name.prefix.as_ref().filter(|p| !p.is_empty()) {
-                Some(prefix) => format!("{}:{}", prefix, name.local),
-                None => name.local.to_string(),
-            };
-            el.set_attribute(&attr_name, value)?;
+            set_attr(&el, name, value)?;
         }
 
         Patch::RemoveAttribute { path, name } => {
             let el = find_element(root, path, slots)?;
-            let attr_name = match name.prefix.as_ref().filter(|p| !p.is_empty()) {
-                Some(prefix) => format!("{}:{}", prefix, name.local),
-                None => name.local.to_string(),
-            };
-            el.remove_attribute(&attr_name)?;
+            remove_attr(&el, name)?;
         }
 
         Patch::Remove { node } => {
@@ -459,6 +747,7 @@ fn apply_patch(
         Patch::InsertElement {
             at,
             tag,
+            ns,
             attrs,
             children,
             detach_to_slot,
@@ -484,15 +773,16 @@ fn apply_patch(
                 .ok_or_else(|| JsValue::from_str("parent is not an element"))?
                 .clone();
 
-            let new_node: Node = match create_element_with_attrs(doc, tag, attrs) {
+            let new_node: Node = match create_element_with_attrs(doc, tag, *ns, attrs) {
                 Ok(el) => {
+                    let host = children_host(&el);
                     for child in children {
                         let child_node = create_insert_content(doc, child)?;
-                        el.append_child(&child_node)?;
+                        host.append_child(&child_node)?;
                     }
                     el.into()
                 }
-                Err(_) => create_element_via_html(doc, tag, attrs, children)?,
+                Err(_) => create_element_via_html(doc, tag, *ns, attrs, children)?,
             };
 
             insert_at_position(
@@ -602,16 +892,12 @@ fn apply_patch(
 
                 for change in changes {
                     if let PropKey::Attr(ref qual_name) = change.name {
-                        let attr_name = if let Some(ref prefix) = qual_name.prefix {
-                            format!("{}:{}", prefix, qual_name.local)
-                        } else {
-                            qual_name.local.to_string()
-                        };
+                        let attr_name = attr_qualified_name(qual_name);
                         let value = match &change.value {
                             Some(v) => v.as_ref().to_string(),
                             None => existing.get(&attr_name).cloned().unwrap_or_default(),
                         };
-                        el.set_attribute(&attr_name, &value)?;
+                        set_attr(el, qual_name, &value)?;
                     }
                 }
             }
@@ -681,6 +967,24 @@ fn apply_patch(
                 web_sys::CustomEvent::new_with_event_init_dict("hotmeal:opaque-changed", &init)?;
             el.dispatch_event(&event)?;
         }
+
+        Patch::Detach { node, to_slot } => {
+            let NodeRef(path) = node;
+            let target = if path.0.len() == 1 {
+                let slot = path.0[0];
+                slots
+                    .take(slot)
+                    .ok_or_else(|| JsValue::from_str(&format!("slot {} is empty", slot)))?
+            } else {
+                find_node(root, path, slots)?
+            };
+            if let Some(parent) = target.parent_node() {
+                parent.remove_child(&target)?;
+            }
+            if let Some(slot) = to_slot {
+                slots.store(*slot, target);
+            }
+        }
     }
 
     Ok(())
@@ -696,7 +1000,8 @@ fn insert_at_position(
     detach_to_slot: Option<u32>,
     slots: &mut Slots,
 ) -> Result<(), JsValue> {
-    let children = parent.child_nodes();
+    let host = children_host(parent);
+    let children = host.child_nodes();
     let current_len = children.length() as usize;
     let pos = position as u32;
 
@@ -715,7 +1020,7 @@ fn insert_at_position(
             existing_text = ?existing.text_content(),
             "replacing existing node"
         );
-        let replaced = parent.replace_child(node, &existing)?;
+        let replaced = host.replace_child(node, &existing)?;
         if let Some(slot) = detach_to_slot {
             trace!(slot, "storing replaced node in slot");
             slots.store(slot, replaced);
@@ -728,9 +1033,9 @@ fn insert_at_position(
         );
         for _ in current_len..position {
             let placeholder = doc.create_text_node("");
-            parent.append_child(&placeholder)?;
+            host.append_child(&placeholder)?;
         }
-        parent.append_child(node)?;
+        host.append_child(node)?;
     }
 
     Ok(())
@@ -741,35 +1046,71 @@ fn insert_at_position(
 fn create_element_with_attrs(
     doc: &Document,
     tag: &str,
+    ns: Namespace,
     attrs: &[hotmeal::AttrPair],
 ) -> Result<Element, JsValue> {
-    let el = doc.create_element(tag)?;
+    let el = match ns {
+        Namespace::Html => doc.create_element(tag)?,
+        _ => doc.create_element_ns(Some(ns.url()), tag)?,
+    };
     for attr in attrs {
-        let attr_name = match attr.name.prefix.as_ref().filter(|p| !p.is_empty()) {
-            Some(prefix) => format!("{}:{}", prefix, attr.name.local),
-            None => attr.name.local.to_string(),
-        };
-        el.set_attribute(&attr_name, &attr.value)?;
+        set_attr(&el, &attr.name, &attr.value)?;
     }
     Ok(el)
 }
 
+/// The qualified name (`prefix:local`) of an attribute.
+fn attr_qualified_name(name: &QualName) -> String {
+    match name.prefix.as_ref().filter(|p| !p.is_empty()) {
+        Some(prefix) => format!("{}:{}", prefix, name.local),
+        None => name.local.to_string(),
+    }
+}
+
+/// Set an attribute, in its namespace if it has one (e.g. `xlink:href`).
+fn set_attr(el: &Element, name: &QualName, value: &str) -> Result<(), JsValue> {
+    let qualified = attr_qualified_name(name);
+    if name.ns.is_empty() {
+        el.set_attribute(&qualified, value)
+    } else {
+        el.set_attribute_ns(Some(&name.ns), &qualified, value)
+    }
+}
+
+/// Remove an attribute, in its namespace if it has one.
+fn remove_attr(el: &Element, name: &QualName) -> Result<(), JsValue> {
+    if name.ns.is_empty() {
+        el.remove_attribute(&attr_qualified_name(name))
+    } else {
+        el.remove_attribute_ns(Some(&name.ns), &name.local)
+    }
+}
+
 /// Create an element via innerHTML when setAttribute fails.
 /// This handles exotic attribute names that the HTML parser accepts but the DOM API rejects.
 fn create_element_via_html(
     doc: &Document,
     tag: &str,
+    ns: Namespace,
     attrs: &[hotmeal::AttrPair],
     children: &[InsertContent],
 ) -> Result<Node, JsValue> {
     use web_sys::HtmlTemplateElement;
 
-    let mut html = format!("<{}", tag);
+    // Foreign elements only parse as such inside their root element
+    let wrapper = match ns {
+        Namespace::Html => None,
+        Namespace::Svg => Some("svg"),
+        Namespace::MathMl => Some("math"),
+    };
+
+    let mut html = String::new();
+    if let Some(wrapper) = wrapper {
+        html.push_str(&format!("<{}>", wrapper));
+    }
+    html.push_str(&format!("<{}", tag));
     for attr in attrs {
-        let attr_name = match attr.name.prefix.as_ref().filter(|p| !p.is_empty()) {
-            Some(prefix) => format!("{}:{}", prefix, attr.name.local),
-            None => attr.name.local.to_string(),
-        };
+        let attr_name = attr_qualified_name(&attr.name);
         let escaped_value = attr
             .value
             .replace('&', "&amp;")
@@ -785,6 +1126,9 @@ fn create_element_via_html(
     }
 
     html.push_str(&format!("</{}>", tag));
+    if let Some(wrapper) = wrapper {
+        html.push_str(&format!("</{}>", wrapper));
+    }
 
     let template = doc
         .create_element("template")?
@@ -792,10 +1136,12 @@ fn create_element_via_html(
         .map_err(|_| JsValue::from_str("failed to create template element"))?;
     template.set_inner_html(&html);
 
-    template
-        .content()
-        .first_child()
-        .ok_or_else(|| JsValue::from_str("template content is empty"))
+    let first = template.content().first_child();
+    let first = match wrapper {
+        Some(_) => first.and_then(|wrapper| wrapper.first_child()),
+        None => first,
+    };
+    first.ok_or_else(|| JsValue::from_str("template content is empty"))
 }
 
 /// Serialize InsertContent to HTML string
@@ -805,14 +1151,12 @@ fn serialize_insert_content(out: &mut String, content: &InsertContent) {
             tag,
             attrs,
             children,
+            ..
         } => {
             out.push('<');
             out.push_str(tag);
             for attr in attrs {
-                let attr_name = match attr.name.prefix.as_ref().filter(|p| !p.is_empty()) {
-                    Some(prefix) => format!("{}:{}", prefix, attr.name.local),
-                    None => attr.name.local.to_string(),
-                };
+                let attr_name = attr_qualified_name(&attr.name);
                 let escaped_value = attr
                     .value
                     .replace('&', "&amp;")
@@ -845,28 +1189,74 @@ fn create_insert_content(doc: &Document, content: &InsertContent) -> Result<Node
     match content {
         InsertContent::Element {
             tag,
+            ns,
             attrs,
             children,
-        } => match create_element_with_attrs(doc, tag, attrs) {
+        } => match create_element_with_attrs(doc, tag, *ns, attrs) {
             Ok(el) => {
+                let host = children_host(&el);
                 for child in children {
                     let child_node = create_insert_content(doc, child)?;
-                    el.append_child(&child_node)?;
+                    host.append_child(&child_node)?;
                 }
                 Ok(el.into())
             }
-            Err(_) => create_element_via_html(doc, tag, attrs, children),
+            Err(_) => create_element_via_html(doc, tag, *ns, attrs, children),
         },
         InsertContent::Text(text) => Ok(doc.create_text_node(text).into()),
         InsertContent::Comment(text) => Ok(doc.create_comment(text).into()),
     }
 }
 
+/// Build a hotmeal document with the same node structure as `root` (as its
+/// body) and `head`. Only what patch validation looks at is copied: node
+/// kinds, tags and text, including the empty text nodes left by earlier patches.
+fn mirror_dom(root: &Node, head: Option<&Node>) -> hotmeal::Document<'static> {
+    let mut mirror = hotmeal::Document::new();
+    if let Some(body) = mirror.body() {
+        mirror_children(&mut mirror, body, root);
+    }
+    if let (Some(mirror_head), Some(head)) = (mirror.head(), head) {
+        mirror_children(&mut mirror, mirror_head, head);
+    }
+    mirror
+}
+
+fn mirror_children(mirror: &mut hotmeal::Document<'static>, parent: NodeId, node: &Node) {
+    let host = mirror.template_contents(parent).unwrap_or(parent);
+    let children = children_host(node).child_nodes();
+    for i in 0..children.length() {
+        let Some(child) = children.item(i) else {
+            continue;
+        };
+        let text = || child.text_content().unwrap_or_default();
+        let id = match child.node_type() {
+            Node::ELEMENT_NODE => mirror.create_element(child.node_name().to_ascii_lowercase()),
+            Node::TEXT_NODE | Node::CDATA_SECTION_NODE => mirror.create_text(text()),
+            // Anything else still takes up a position
+            _ => mirror.create_comment(text()),
+        };
+        mirror.append_child(host, id);
+        if child.node_type() == Node::ELEMENT_NODE {
+            mirror_children(mirror, id, &child);
+        }
+    }
+}
+
+/// The node holding the children of `node`: a `<template>`'s content
+/// fragment, or `node` itself.
+fn children_host(node: &Node) -> Node {
+    match node.dyn_ref::<HtmlTemplateElement>() {
+        Some(template) => template.content().into(),
+        None => node.clone(),
+    }
+}
+
 /// Navigate a relative path within a node (for slot-relative paths).
 fn navigate_within_node(root: &Node, path: &NodePath) -> Result<Node, JsValue> {
     let mut current = root.clone();
     for &idx in &path.0 {
-        let children = current.child_nodes();
+        let children = children_host(&current).child_nodes();
         current = children
             .item(idx)
             .ok_or_else(|| JsValue::from_str(&format!("relative path child {} not found", idx)))?;
@@ -875,7 +1265,7 @@ fn navigate_within_node(root: &Node, path: &NodePath) -> Result<Node, JsValue> {
 }
 
 /// Find a node by slot-based path.
-/// Path format: [slot, child1, child2, ...] where slot 0 = root
+/// Path format: [slot, child1, child2, ...] where slot 0 = root, `HEAD_SLOT` = `<head>`
 fn find_node(root: &Node, path: &NodePath, slots: &Slots) -> Result<Node, JsValue> {
     if path.0.is_empty() {
         return Err(JsValue::from_str("empty path"));
@@ -888,7 +1278,7 @@ fn find_node(root: &Node, path: &NodePath, slots: &Slots) -> Result<Node, JsValu
 
     let mut current = slot_root;
     for &idx in &path.0[1..] {
-        let children = current.child_nodes();
+        let children = children_host(&current).child_nodes();
         let num_children = children.length();
         trace!(idx, num_children, "navigating to child");
         current = children.item(idx).ok_or_else(|| {
@@ -1006,6 +1396,7 @@ pub fn dump_rust_parsed(html: &str) -> Result<String, JsValue> {
         let node = doc.get(node_id);
         let mut result = match &node.kind {
             NodeKind::Document => format!("{}Document\n", prefix),
+            NodeKind::TemplateContents => format!("{}TemplateContents\n", prefix),
             NodeKind::Element(elem) => {
                 format!("{}Element({})\n", prefix, elem.tag.as_ref().to_uppercase())
             }
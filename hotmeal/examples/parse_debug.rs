@@ -27,7 +27,7 @@ fn main() {
             NodeKind::Comment(c) => {
                 println!("{}COMMENT: {:?}", indent, c.as_ref());
             }
-            NodeKind::Document => {
+            NodeKind::Document | NodeKind::TemplateContents => {
                 for child_id in node_id.children(&doc.arena) {
                     print_node(doc, child_id, depth);
                 }
@@ -52,7 +52,7 @@ fn main() {
         let node = doc.get(node_id);
         match &node.kind {
             NodeKind::Element(_) => 1 + children_depth,
-            NodeKind::Document => children_depth,
+            NodeKind::Document | NodeKind::TemplateContents => children_depth,
             _ => 0,
         }
     }
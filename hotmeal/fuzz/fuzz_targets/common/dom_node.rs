@@ -43,8 +43,9 @@ fn node_to_dom_node(doc: &Document, node_id: hotmeal::NodeId) -> DomNode {
                 })
                 .collect();
             attrs.sort_by(|a, b| a.name.cmp(&b.name));
+            let host = doc.template_contents(node_id).unwrap_or(node_id);
             let children: Vec<DomNode> = doc
-                .children(node_id)
+                .children(host)
                 .map(|child_id| node_to_dom_node(doc, child_id))
                 .collect();
             DomNode::Element {
@@ -55,6 +56,8 @@ fn node_to_dom_node(doc: &Document, node_id: hotmeal::NodeId) -> DomNode {
         }
         hotmeal::NodeKind::Text(text) => DomNode::Text(text.as_ref().to_string()),
         hotmeal::NodeKind::Comment(text) => DomNode::Comment(text.as_ref().to_string()),
-        hotmeal::NodeKind::Document => DomNode::Text(String::new()),
+        hotmeal::NodeKind::Document | hotmeal::NodeKind::TemplateContents => {
+            DomNode::Text(String::new())
+        }
     }
 }
@@ -792,4 +792,15 @@ pub fn apply_roundtrip(data: &[u8]) {
         eprintln!("\nFull trace:\n{}", trace);
         panic!("Patched body content should match target body content");
     }
+
+    // Normalizing drops the placeholders patching left behind
+    patched.normalize();
+    let leftover = hotmeal::diff(&patched, &doc_b).expect("diff of normalized tree");
+    if !leftover.is_empty() {
+        eprintln!("Normalized tree still differs!\n");
+        eprintln!("Input A: {:?}", full_a);
+        eprintln!("Input B: {:?}", full_b);
+        eprintln!("\nLeftover patches: {:#?}", leftover);
+        panic!("A normalized patched tree should diff empty against the target");
+    }
 }
@@ -13,12 +13,15 @@ use cinereus::{
     indextree::{self, NodeId},
 };
 use facet::Facet;
-use html5ever::{LocalName, QualName};
+use facet_error as error;
+use html5ever::{LocalName, QualName, local_name, ns};
 use rapidhash::RapidHasher;
 use smallvec::{SmallVec, smallvec};
+use std::borrow::Cow;
 use std::cell::Cell;
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use std::sync::LazyLock;
 
 #[allow(unused_imports)]
 use crate::trace;
@@ -148,6 +151,187 @@ pub enum DiffError {
 
     /// node is not a comment
     NotAComment,
+
+    /// text range {offset}..{offset}+{len} is out of bounds
+    TextRangeOutOfBounds { offset: u32, len: u32 },
+
+    /// subtree roots are not the same kind of element
+    RootMismatch,
+
+    /// patch {index} failed ({source}) and undoing the ones before it failed too; the document is partially patched
+    RollbackFailed {
+        index: usize,
+        #[facet(error::source)]
+        source: Box<DiffError>,
+    },
+}
+
+/// Knobs for [`diff_with_options`] and [`diff_html_with_options`].
+///
+/// [`DiffOptions::default()`] is what [`diff`] and [`diff_html`] use.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffOptions {
+    /// Minimum Dice coefficient for two differing subtrees to be matched
+    /// (and updated in place) rather than replaced.
+    pub similarity_threshold: f64,
+
+    /// Attribute that marks an element as opaque: its children are not
+    /// diffed, and any change to them is sent as a single `OpaqueChanged`.
+    pub opaque_attr: Cow<'static, str>,
+
+    /// Attribute that gives an element a stable identity among its siblings,
+    /// so that reordered elements are moved rather than rewritten. `None`
+    /// (the default) leaves only `id`s as keys, with `key_by_id`.
+    pub key_attr: Option<Cow<'static, str>>,
+
+    /// Use an element's `id` as its identity when it has no `key_attr`.
+    /// On by default.
+    pub key_by_id: bool,
+
+    /// Match the roots (`<body>`, `<head>`) to each other whenever they are
+    /// the same kind of element, even if they weren't similar enough to match
+    /// on their own. When off, roots are matched like any other node, and a
+    /// root left unmatched is replaced: its old children are removed, its
+    /// attributes reset and the new children inserted.
+    pub force_root_match: bool,
+
+    /// Whether whitespace in text nodes counts as a change.
+    pub whitespace: WhitespaceMode,
+
+    /// Elements whose text keeps its whitespace even in
+    /// [`WhitespaceMode::Insignificant`]. Elements with an inline
+    /// `white-space: pre*` (or `break-spaces`) style are always included.
+    pub preserve_whitespace_in: Vec<LocalName>,
+
+    /// Text nodes at least this many bytes long are updated with a
+    /// [`Patch::SpliceText`] of just the changed part, instead of a full
+    /// [`Patch::SetText`]. `usize::MAX` always sends the full text.
+    pub splice_text_min_len: usize,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            similarity_threshold: 0.5,
+            opaque_attr: Cow::Borrowed("data-hotmeal-opaque"),
+            key_attr: None,
+            key_by_id: true,
+            force_root_match: true,
+            whitespace: WhitespaceMode::default(),
+            preserve_whitespace_in: vec![
+                local_name!("pre"),
+                local_name!("textarea"),
+                local_name!("script"),
+                local_name!("style"),
+            ],
+            splice_text_min_len: 64,
+        }
+    }
+}
+
+static DEFAULT_DIFF_OPTIONS: LazyLock<DiffOptions> = LazyLock::new(DiffOptions::default);
+
+/// How [`diff_with_options`] treats whitespace in text nodes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WhitespaceMode {
+    /// Every whitespace change is a text change.
+    #[default]
+    Significant,
+
+    /// Runs of whitespace compare equal to a single space, the way they
+    /// render. Text that only changed in its whitespace keeps its old
+    /// content, so patched documents match the new one up to whitespace.
+    /// Whitespace-only text next to block-level elements, or at the start or
+    /// end of a block, is ignored altogether, so re-indenting markup produces
+    /// no patches. Between inline elements it renders, and still counts.
+    Insignificant,
+}
+
+impl DiffOptions {
+    /// Whether text directly inside `elem` keeps its whitespace.
+    fn preserves_whitespace(&self, elem: &dom::ElementData<'_>) -> bool {
+        self.preserve_whitespace_in.contains(&elem.tag)
+            || elem.attrs.iter().any(|(k, v)| {
+                k.ns == ns!()
+                    && k.local == local_name!("style")
+                    && style_preserves_whitespace(v.as_str())
+            })
+    }
+
+    /// Whether text children of `elem` collapse their whitespace, given
+    /// whether text around `elem` does.
+    fn collapses_whitespace_in(&self, elem: &dom::ElementData<'_>, inherited: bool) -> bool {
+        inherited && !self.preserves_whitespace(elem)
+    }
+}
+
+/// Whether an inline style sets a `white-space` value that keeps whitespace.
+pub(crate) fn style_preserves_whitespace(style: &str) -> bool {
+    style.split(';').any(|decl| {
+        decl.split_once(':').is_some_and(|(name, value)| {
+            let value = value.trim().to_ascii_lowercase();
+            name.trim().eq_ignore_ascii_case("white-space")
+                && (value.starts_with("pre") || value.starts_with("break-spaces"))
+        })
+    })
+}
+
+/// Collapse each run of ASCII whitespace in `text` to a single space.
+pub(crate) fn collapse_whitespace(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_run = false;
+    for c in text.chars() {
+        if c.is_ascii_whitespace() {
+            if !in_run {
+                out.push(' ');
+            }
+            in_run = true;
+        } else {
+            out.push(c);
+            in_run = false;
+        }
+    }
+    out
+}
+
+/// Whether a text node is whitespace-only text that [`WhitespaceMode::Insignificant`]
+/// may leave out of the diff. `collapse_ws` says whether its whitespace collapses;
+/// the text must also border a block (see [`borders_block`]).
+fn is_ignored_whitespace(text: &str, collapse_ws: bool) -> bool {
+    collapse_ws && text.bytes().all(|b| b.is_ascii_whitespace())
+}
+
+/// Whether an element lays out as a block, so whitespace next to it doesn't render.
+fn is_block(tag: &LocalName, ns: Namespace) -> bool {
+    ns == Namespace::Html && dom::is_block_element(tag)
+}
+
+/// Whether a node sits next to a block-level sibling, or first or last in a
+/// block-level parent: whitespace there doesn't render. Neighbouring
+/// whitespace-only text (per `is_blank`) collapses with the node, so it is
+/// looked past.
+fn borders_block(
+    parent: Option<NodeId>,
+    mut previous: impl Iterator<Item = NodeId>,
+    mut next: impl Iterator<Item = NodeId>,
+    is_block: impl Fn(NodeId) -> bool,
+    is_blank: impl Fn(NodeId) -> bool,
+) -> bool {
+    let previous = previous.find(|&id| !is_blank(id));
+    let next = next.find(|&id| !is_blank(id));
+    let edge = previous.is_none() || next.is_none();
+    (edge && parent.is_some_and(&is_block))
+        || previous.is_some_and(&is_block)
+        || next.is_some_and(&is_block)
+}
+
+/// Hash text content, collapsing whitespace first if asked to.
+fn hash_text(text: &Stem<'_>, collapse: bool, hasher: &mut RapidHasher) {
+    if collapse {
+        collapse_whitespace(text.as_str()).hash(hasher);
+    } else {
+        text.hash(hasher);
+    }
 }
 
 /// A path to a node in the DOM tree.
@@ -178,19 +362,28 @@ impl std::fmt::Display for NodePath {
 /// - `[1, 0, 3]` = slot 1 (displaced content), child 0, child 3
 ///
 /// Slot 0 is special - it always contains the original tree (body).
+/// [`HEAD_SLOT`] is also special - it always contains the document's `<head>`.
 /// Slots 1+ are created when content is displaced during Insert/Move operations.
 #[derive(Debug, Clone, PartialEq, Eq, facet::Facet)]
 #[facet(transparent)]
 pub struct NodeRef(pub NodePath);
 
+/// Slot number reserved for the `<head>` element.
+///
+/// Paths starting with this slot address the head subtree, e.g.
+/// `[HEAD_SLOT, 2]` is the third child of `<head>`. Displaced content from
+/// head patches still goes to ordinary numbered slots.
+pub const HEAD_SLOT: u32 = u32::MAX;
+
 /// Content that can be inserted as part of a new subtree.
 #[derive(Debug, Clone, PartialEq, Eq, facet::Facet)]
 #[repr(u8)]
 pub enum InsertContent<'a> {
-    /// An element with its tag, attributes, and nested children
+    /// An element with its tag, namespace, attributes, and nested children
     Element {
         #[facet(opaque, proxy = LocalNameProxy)]
         tag: LocalName,
+        ns: Namespace,
         attrs: Vec<AttrPair<'a>>,
         children: Vec<InsertContent<'a>>,
     },
@@ -222,6 +415,7 @@ pub enum Patch<'a> {
         at: NodeRef,
         #[facet(opaque, proxy = LocalNameProxy)]
         tag: LocalName,
+        ns: Namespace,
         attrs: Vec<AttrPair<'a>>,
         children: Vec<InsertContent<'a>>,
         detach_to_slot: Option<u32>,
@@ -278,6 +472,21 @@ pub enum Patch<'a> {
     /// An opaque node's source content changed.
     /// The DOM is NOT modified — the WASM applier dispatches a CustomEvent instead.
     OpaqueChanged { path: NodePath, content: Stem<'a> },
+
+    /// Replace part of the content of a text (or comment) node at path.
+    /// `offset` and `delete_len` count UTF-16 code units, like the DOM's
+    /// `CharacterData.replaceData`.
+    SpliceText {
+        path: NodePath,
+        offset: u32,
+        delete_len: u32,
+        insert: Stem<'a>,
+    },
+
+    /// Take a node out of its parent without leaving a placeholder, keeping it
+    /// in `to_slot` if given. The diff never emits this; it shows up in inverse
+    /// patch lists, to undo insertions exactly.
+    Detach { node: NodeRef, to_slot: Option<u32> },
 }
 
 impl<'a> std::fmt::Debug for Patch<'a> {
@@ -286,11 +495,13 @@ impl<'a> std::fmt::Debug for Patch<'a> {
             Patch::InsertElement {
                 at,
                 tag,
+                ns,
                 attrs,
                 children,
                 detach_to_slot,
             } => {
-                write!(f, "Insert <{}> @{:?}", tag, at.0.0.as_slice())?;
+                let kind = HtmlNodeKind::Element(tag.clone(), *ns);
+                write!(f, "Insert {} @{:?}", kind, at.0.0.as_slice())?;
                 if !attrs.is_empty() {
                     write!(f, " ({} attrs)", attrs.len())?;
                 }
@@ -333,6 +544,22 @@ impl<'a> std::fmt::Debug for Patch<'a> {
                 let preview: String = text.chars().take(20).collect();
                 write!(f, "SetText {:?} @{:?}", preview, path.0.as_slice())
             }
+            Patch::SpliceText {
+                path,
+                offset,
+                delete_len,
+                insert,
+            } => {
+                let preview: String = insert.chars().take(20).collect();
+                write!(
+                    f,
+                    "SpliceText {}+{} {:?} @{:?}",
+                    offset,
+                    delete_len,
+                    preview,
+                    path.0.as_slice()
+                )
+            }
             Patch::SetAttribute { path, name, value } => {
                 write!(
                     f,
@@ -373,6 +600,13 @@ impl<'a> std::fmt::Debug for Patch<'a> {
                 let preview: String = content.chars().take(40).collect();
                 write!(f, "OpaqueChanged @{:?} {:?}", path.0.as_slice(), preview)
             }
+            Patch::Detach { node, to_slot } => {
+                write!(f, "Detach @{:?}", node.0.0.as_slice())?;
+                if let Some(slot) = to_slot {
+                    write!(f, " →slot{}", slot)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -495,6 +729,32 @@ impl<'a> Properties for HtmlProps<'a> {
     }
 }
 
+/// Identity key of an element: the value of `key_attr`, or else its `id`
+/// with `key_by_id`. Empty values don't count as keys.
+fn html_identity_key<'p>(props: &'p HtmlProps<'_>, options: &DiffOptions) -> Option<&'p str> {
+    let find = |name: &str| {
+        props
+            .attrs
+            .iter()
+            .find(|(k, _)| k.ns == ns!() && k.local.as_ref() == name)
+            .map(|(_, v)| v.as_str())
+            .filter(|v| !v.is_empty())
+    };
+    options
+        .key_attr
+        .as_deref()
+        .and_then(find)
+        .or_else(|| options.key_by_id.then(|| find("id")).flatten())
+}
+
+/// Whether an element carries `options.opaque_attr`.
+fn html_is_opaque(props: &HtmlProps<'_>, options: &DiffOptions) -> bool {
+    props
+        .attrs
+        .iter()
+        .any(|(k, _)| k.local.as_ref() == options.opaque_attr)
+}
+
 /// Tree types marker for HTML DOM.
 pub struct HtmlTreeTypes<'a>(std::marker::PhantomData<&'a ()>);
 
@@ -511,6 +771,9 @@ struct DiffNodeData<'a> {
     props: HtmlProps<'a>,
     /// Text content for text/comment nodes
     text: Option<Stem<'a>>,
+    /// Whether whitespace collapses in this text node (or, for elements,
+    /// in their text children)
+    collapse_ws: bool,
     height: usize,
     /// Cached position among siblings (0-indexed), computed on-demand
     position: Cell<Option<u32>>,
@@ -526,25 +789,53 @@ struct DiffNodeData<'a> {
 /// - `'a` - the lifetime of data inside the Document (Stem<'a> values from original input)
 pub struct DiffableDocument<'b, 'a> {
     doc: &'b Document<'a>,
-    /// The root for diffing (body element)
-    body_id: NodeId,
+    /// The root for diffing (body or head element)
+    root_id: NodeId,
     /// Pre-computed diff data indexed by NodeId
     nodes: HashMap<NodeId, DiffNodeData<'a>>,
+    options: &'b DiffOptions,
 }
 
 impl<'b, 'a> DiffableDocument<'b, 'a> {
     /// Create a new DiffableDocument from a Document.
     ///
     /// Pre-computes hashes and caches kind/props for all body descendants.
-    pub fn new(doc: &'b Document<'a>) -> Result<Self, DiffError> {
+    pub fn new(doc: &'b Document<'a>, options: &'b DiffOptions) -> Result<Self, DiffError> {
         let body_id = doc.body().ok_or(DiffError::NoBody)?;
+        Ok(Self::with_root(doc, body_id, options))
+    }
+
+    /// Create a DiffableDocument rooted at an arbitrary node of `doc`.
+    pub(crate) fn with_root(
+        doc: &'b Document<'a>,
+        root_id: NodeId,
+        options: &'b DiffOptions,
+    ) -> Self {
         // Pre-allocate based on arena size (upper bound for descendants)
-        let mut nodes = HashMap::with_capacity(doc.arena.count());
+        let mut nodes: HashMap<NodeId, DiffNodeData<'a>> =
+            HashMap::with_capacity(doc.arena.count());
+
+        let is_block_node = |id: NodeId| {
+            let node = doc.get(id);
+            matches!(&node.kind, NodeKind::Element(elem) if is_block(&elem.tag, node.ns))
+        };
+        let is_blank_node = |id: NodeId| match &doc.get(id).kind {
+            NodeKind::Text(text) => is_ignored_whitespace(text.as_str(), true),
+            _ => false,
+        };
 
         // First pass: compute kind, props, and text for all nodes
-        for node_id in body_id.descendants(&doc.arena) {
+        // (descendants() is pre-order, so parents are seen before their children)
+        for node_id in root_id.descendants(&doc.arena) {
             let node = doc.get(node_id);
-            let (kind, props, text) = match &node.kind {
+            let inherited_ws = doc
+                .logical_parent(node_id)
+                .filter(|_| node_id != root_id)
+                .and_then(|parent| nodes.get(&parent))
+                .map_or(options.whitespace == WhitespaceMode::Insignificant, |d| {
+                    d.collapse_ws
+                });
+            let (kind, props, text, collapse_ws) = match &node.kind {
                 NodeKind::Element(elem) => {
                     let kind = HtmlNodeKind::Element(elem.tag.clone(), node.ns);
                     let props = HtmlProps {
@@ -554,19 +845,35 @@ impl<'b, 'a> DiffableDocument<'b, 'a> {
                             .map(|(k, v)| (k.clone(), v.clone()))
                             .collect(),
                     };
-                    (kind, props, None)
+                    let collapse_ws = options.collapses_whitespace_in(elem, inherited_ws);
+                    (kind, props, None, collapse_ws)
+                }
+                // Whitespace the options ignore is left out altogether
+                NodeKind::Text(text)
+                    if is_ignored_whitespace(text.as_str(), inherited_ws)
+                        && borders_block(
+                            doc.logical_parent(node_id),
+                            node_id.preceding_siblings(&doc.arena).skip(1),
+                            node_id.following_siblings(&doc.arena).skip(1),
+                            is_block_node,
+                            is_blank_node,
+                        ) =>
+                {
+                    continue;
                 }
                 NodeKind::Text(text) => {
                     let kind = HtmlNodeKind::Text;
                     let props = HtmlProps { attrs: Vec::new() };
-                    (kind, props, Some(text.clone()))
+                    (kind, props, Some(text.clone()), inherited_ws)
                 }
                 NodeKind::Comment(text) => {
                     let kind = HtmlNodeKind::Comment;
                     let props = HtmlProps { attrs: Vec::new() };
-                    (kind, props, Some(text.clone()))
+                    (kind, props, Some(text.clone()), false)
                 }
-                NodeKind::Document => continue, // Skip document nodes
+                // Skip document nodes; template contents are flattened into
+                // the template element
+                NodeKind::Document | NodeKind::TemplateContents => continue,
             };
 
             nodes.insert(
@@ -576,6 +883,7 @@ impl<'b, 'a> DiffableDocument<'b, 'a> {
                     kind,
                     props,
                     text,
+                    collapse_ws,
                     height: 0,                 // Will be computed in second pass
                     position: Cell::new(None), // Computed on-demand
                 },
@@ -584,9 +892,13 @@ impl<'b, 'a> DiffableDocument<'b, 'a> {
 
         // Second pass: compute heights and hashes bottom-up (post-order)
         // We collect updates separately to avoid borrow conflicts
-        let post_order: Vec<_> = PostOrderIterator::new(body_id, &doc.arena).collect();
+        let post_order: Vec<_> = PostOrderIterator::new(root_id, &doc.arena).collect();
         for node_id in post_order {
-            let children: Vec<_> = node_id.children(&doc.arena).collect();
+            let children: Vec<_> = doc
+                .children_host(node_id)
+                .children(&doc.arena)
+                .filter(|c| nodes.contains_key(c))
+                .collect();
 
             // Compute height from children (already processed in post-order)
             let height = if children.is_empty() {
@@ -606,7 +918,7 @@ impl<'b, 'a> DiffableDocument<'b, 'a> {
                 data.kind.hash(&mut hasher);
                 // Include text content - this is the identity of text/comment nodes
                 if let Some(text) = &data.text {
-                    text.hash(&mut hasher);
+                    hash_text(text, data.collapse_ws, &mut hasher);
                 }
                 for child_id in &children {
                     if let Some(child_data) = nodes.get(child_id) {
@@ -625,11 +937,19 @@ impl<'b, 'a> DiffableDocument<'b, 'a> {
             }
         }
 
-        Ok(Self {
+        Self {
             doc,
-            body_id,
+            root_id,
             nodes,
-        })
+            options,
+        }
+    }
+
+    /// Whether `id` is a text node whose whitespace is insignificant.
+    fn collapses_whitespace(&self, id: NodeId) -> bool {
+        self.nodes
+            .get(&id)
+            .is_some_and(|data| data.kind == HtmlNodeKind::Text && data.collapse_ws)
     }
 }
 
@@ -637,7 +957,7 @@ impl<'b, 'a> DiffTree for DiffableDocument<'b, 'a> {
     type Types = HtmlTreeTypes<'a>;
 
     fn root(&self) -> NodeId {
-        self.body_id
+        self.root_id
     }
 
     fn node_count(&self) -> usize {
@@ -667,15 +987,18 @@ impl<'b, 'a> DiffTree for DiffableDocument<'b, 'a> {
     }
 
     fn parent(&self, id: NodeId) -> Option<NodeId> {
-        self.doc.arena.get(id).and_then(|n| n.parent())
+        self.doc.logical_parent(id)
     }
 
     fn children(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
-        id.children(&self.doc.arena)
+        self.doc
+            .children_host(id)
+            .children(&self.doc.arena)
+            .filter(|c| self.nodes.contains_key(c))
     }
 
     fn child_count(&self, id: NodeId) -> usize {
-        id.children(&self.doc.arena).count()
+        self.children(id).count()
     }
 
     fn position(&self, id: NodeId) -> usize {
@@ -686,10 +1009,7 @@ impl<'b, 'a> DiffTree for DiffableDocument<'b, 'a> {
             }
             // Compute and cache
             let pos = if let Some(parent) = self.parent(id) {
-                parent
-                    .children(&self.doc.arena)
-                    .position(|c| c == id)
-                    .unwrap_or(0) as u32
+                self.children(parent).position(|c| c == id).unwrap_or(0) as u32
             } else {
                 0
             };
@@ -705,26 +1025,28 @@ impl<'b, 'a> DiffTree for DiffableDocument<'b, 'a> {
     }
 
     fn iter(&self) -> impl Iterator<Item = NodeId> + '_ {
-        self.body_id.descendants(&self.doc.arena)
+        self.descendants(self.root_id)
     }
 
     fn post_order(&self) -> impl Iterator<Item = NodeId> + '_ {
-        PostOrderIterator::new(self.body_id, &self.doc.arena)
+        PostOrderIterator::new(self.root_id, &self.doc.arena)
+            .filter(|id| self.nodes.contains_key(id))
     }
 
     fn descendants(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        // Template contents fragments are skipped; pre-order is preserved
         id.descendants(&self.doc.arena)
+            .filter(|id| self.nodes.contains_key(id))
     }
 
     fn is_opaque(&self, id: NodeId) -> bool {
-        if let Some(data) = self.nodes.get(&id) {
-            data.props
-                .attrs
-                .iter()
-                .any(|(k, _)| k.local.as_ref() == "data-hotmeal-opaque")
-        } else {
-            false
-        }
+        self.nodes
+            .get(&id)
+            .is_some_and(|data| html_is_opaque(&data.props, self.options))
+    }
+
+    fn identity_key(&self, id: NodeId) -> Option<&str> {
+        html_identity_key(&self.nodes.get(&id)?.props, self.options)
     }
 }
 
@@ -764,10 +1086,30 @@ impl Iterator for PostOrderIterator<'_, '_> {
 /// Wrapper around `Tree<HtmlTreeTypes>` that adds `is_opaque` support.
 ///
 /// `Tree<T>` is generic and can't check HTML attributes for opaqueness.
-/// This wrapper delegates all DiffTree methods and adds the `data-hotmeal-opaque` check.
-struct OpaqueAwareTree<'a>(Tree<HtmlTreeTypes<'a>>);
+/// This wrapper delegates all DiffTree methods and adds the opaque-attribute
+/// and identity-key checks configured in [`DiffOptions`]. Text nodes in the
+/// third field are whitespace the options ignore: they stay in the tree (so
+/// the shadow tree keeps real DOM indices) but are hidden from the diff.
+struct OpaqueAwareTree<'a, 'o>(Tree<HtmlTreeTypes<'a>>, &'o DiffOptions, HashSet<NodeId>);
+
+impl<'a, 'o> OpaqueAwareTree<'a, 'o> {
+    /// Build the tree for the subtree of `doc` rooted at `root`.
+    fn build(
+        doc: &Document<'a>,
+        root: Option<NodeId>,
+        fallback_tag: &str,
+        options: &'o DiffOptions,
+    ) -> Self {
+        let (tree, ignored) = build_tree_rooted_at(doc, root, fallback_tag, options);
+        Self(tree, options, ignored)
+    }
+
+    fn is_visible(&self, id: NodeId) -> bool {
+        !self.2.contains(&id)
+    }
+}
 
-impl<'a> DiffTree for OpaqueAwareTree<'a> {
+impl<'a> DiffTree for OpaqueAwareTree<'a, '_> {
     type Types = HtmlTreeTypes<'a>;
 
     fn root(&self) -> NodeId {
@@ -792,76 +1134,162 @@ impl<'a> DiffTree for OpaqueAwareTree<'a> {
         self.0.parent(id)
     }
     fn children(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
-        self.0.children(id)
+        self.0.children(id).filter(|&c| self.is_visible(c))
     }
     fn child_count(&self, id: NodeId) -> usize {
-        self.0.child_count(id)
+        self.children(id).count()
     }
     fn position(&self, id: NodeId) -> usize {
-        self.0.position(id)
+        self.0
+            .parent(id)
+            .and_then(|parent| self.children(parent).position(|c| c == id))
+            .unwrap_or(0)
     }
     fn height(&self, id: NodeId) -> usize {
-        self.0.height(id)
+        self.children(id)
+            .map(|c| 1 + self.height(c))
+            .max()
+            .unwrap_or(0)
     }
     fn iter(&self) -> impl Iterator<Item = NodeId> + '_ {
-        self.0.iter()
+        self.0.iter().filter(|&id| self.is_visible(id))
     }
     fn post_order(&self) -> impl Iterator<Item = NodeId> + '_ {
-        self.0.post_order()
+        self.0.post_order().filter(|&id| self.is_visible(id))
     }
     fn descendants(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
-        self.0.descendants(id)
+        self.0.descendants(id).filter(|&id| self.is_visible(id))
     }
     fn is_opaque(&self, id: NodeId) -> bool {
-        self.0
-            .properties(id)
-            .attrs
-            .iter()
-            .any(|(k, _)| k.local.as_ref() == "data-hotmeal-opaque")
+        html_is_opaque(self.0.properties(id), self.1)
+    }
+    fn identity_key(&self, id: NodeId) -> Option<&str> {
+        html_identity_key(self.0.properties(id), self.1)
     }
 }
 
 /// Build a cinereus tree from an arena_dom::Document (body content only).
 /// If the document has no body, returns an empty body tree.
-pub fn build_tree_from_arena<'a>(doc: &Document<'a>) -> Tree<HtmlTreeTypes<'a>> {
-    // Find body element, or create an empty body tree if none exists
-    let (body_tag, body_ns, body_id) = if let Some(body_id) = doc.body() {
-        let body_node = doc.get(body_id);
-        if let NodeKind::Element(elem) = &body_node.kind {
-            (elem.tag.clone(), body_node.ns, Some(body_id))
+#[cfg(test)]
+fn build_tree_from_arena<'a>(doc: &Document<'a>, options: &DiffOptions) -> Tree<HtmlTreeTypes<'a>> {
+    build_tree_rooted_at(doc, doc.body(), "body", options).0
+}
+
+/// Build a cinereus tree from the subtree rooted at `root`.
+/// If `root` is `None` (or not an element), returns an empty `<fallback_tag>` tree.
+///
+/// Also returns the text nodes holding whitespace the options ignore. They
+/// are left in the tree but out of their parents' hashes.
+fn build_tree_rooted_at<'a>(
+    doc: &Document<'a>,
+    root: Option<NodeId>,
+    fallback_tag: &str,
+    options: &DiffOptions,
+) -> (Tree<HtmlTreeTypes<'a>>, HashSet<NodeId>) {
+    let collapse_ws = options.whitespace == WhitespaceMode::Insignificant;
+    let (root_tag, root_ns, root_id, collapse_ws) = if let Some(root_id) = root {
+        let root_node = doc.get(root_id);
+        if let NodeKind::Element(elem) = &root_node.kind {
+            (
+                elem.tag.clone(),
+                root_node.ns,
+                Some(root_id),
+                options.collapses_whitespace_in(elem, collapse_ws),
+            )
         } else {
-            (LocalName::from("body"), Namespace::Html, None)
+            (
+                LocalName::from(fallback_tag),
+                Namespace::Html,
+                None,
+                collapse_ws,
+            )
         }
     } else {
-        (LocalName::from("body"), Namespace::Html, None)
+        (
+            LocalName::from(fallback_tag),
+            Namespace::Html,
+            None,
+            collapse_ws,
+        )
     };
 
+    // The root's attributes are diffed like any other element's
+    let root_attrs = match root_id.map(|id| &doc.get(id).kind) {
+        Some(NodeKind::Element(elem)) => elem
+            .attrs
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+        _ => Vec::new(),
+    };
     let root_data = NodeData {
         hash: NodeHash(0),
-        kind: HtmlNodeKind::Element(body_tag, body_ns),
-        properties: HtmlProps { attrs: Vec::new() },
+        kind: HtmlNodeKind::Element(root_tag, root_ns),
+        properties: HtmlProps { attrs: root_attrs },
         text: None,
     };
 
     let mut tree = Tree::new(root_data);
     let tree_root = tree.root;
 
-    // Add children from body (only if we have a body)
-    if let Some(body_id) = body_id {
-        add_arena_children(&mut tree, tree_root, doc, body_id);
+    // Add children from the root (only if we have one)
+    let mut collapsed_text = HashSet::new();
+    if let Some(root_id) = root_id {
+        add_arena_children(
+            &mut tree,
+            tree_root,
+            doc,
+            root_id,
+            options,
+            collapse_ws,
+            &mut collapsed_text,
+        );
     }
 
+    // Whitespace-only text is left out of the diff where whitespace collapses
+    // and it borders a block
+    let ignored: HashSet<NodeId> = collapsed_text
+        .iter()
+        .copied()
+        .filter(|&id| {
+            tree.get(id)
+                .text
+                .as_ref()
+                .is_some_and(|text| is_ignored_whitespace(text.as_str(), true))
+                && borders_block(
+                    tree.parent(id),
+                    id.preceding_siblings(&tree.arena).skip(1),
+                    id.following_siblings(&tree.arena).skip(1),
+                    |id| match &tree.get(id).kind {
+                        HtmlNodeKind::Element(tag, ns) => is_block(tag, *ns),
+                        _ => false,
+                    },
+                    |id| {
+                        let text = tree.get(id).text.as_ref();
+                        text.is_some_and(|text| is_ignored_whitespace(text.as_str(), true))
+                    },
+                )
+        })
+        .collect();
+
     // Recompute hashes bottom-up
-    recompute_hashes(&mut tree);
+    recompute_hashes(&mut tree, &collapsed_text, &ignored);
 
-    tree
+    (tree, ignored)
 }
 
+/// Add the children of `arena_parent` under `parent`, recursively.
+///
+/// `collapse_ws` says whether text children collapse their whitespace; the
+/// tree ids of those that do are collected into `collapsed_text` for hashing.
 fn add_arena_children<'a>(
     tree: &mut Tree<HtmlTreeTypes<'a>>,
     parent: indextree::NodeId,
     doc: &Document<'a>,
     arena_parent: indextree::NodeId,
+    options: &DiffOptions,
+    collapse_ws: bool,
+    collapsed_text: &mut HashSet<NodeId>,
 ) {
     let children: Vec<_> = arena_parent.children(&doc.arena).collect();
 
@@ -884,7 +1312,15 @@ fn add_arena_children<'a>(
                     text: None,
                 };
                 let node_id = tree.add_child(parent, data);
-                add_arena_children(tree, node_id, doc, child_id);
+                add_arena_children(
+                    tree,
+                    node_id,
+                    doc,
+                    child_id,
+                    options,
+                    options.collapses_whitespace_in(elem, collapse_ws),
+                    collapsed_text,
+                );
             }
             NodeKind::Text(text) => {
                 let kind = HtmlNodeKind::Text;
@@ -895,7 +1331,10 @@ fn add_arena_children<'a>(
                     properties: props,
                     text: Some(text.clone()),
                 };
-                tree.add_child(parent, data);
+                let node_id = tree.add_child(parent, data);
+                if collapse_ws {
+                    collapsed_text.insert(node_id);
+                }
             }
             NodeKind::Comment(text) => {
                 let kind = HtmlNodeKind::Comment;
@@ -908,6 +1347,18 @@ fn add_arena_children<'a>(
                 };
                 tree.add_child(parent, data);
             }
+            NodeKind::TemplateContents => {
+                // Template contents are flattened into the template element
+                add_arena_children(
+                    tree,
+                    parent,
+                    doc,
+                    child_id,
+                    options,
+                    collapse_ws,
+                    collapsed_text,
+                );
+            }
             NodeKind::Document => {
                 // Skip document nodes
             }
@@ -920,7 +1371,14 @@ fn add_arena_children<'a>(
 /// The hash captures: node kind + text content + children structure (Merkle-tree style).
 /// Attributes are NOT included - they're compared via the Properties trait after matching,
 /// using similarity scores to decide whether nodes should match.
-fn recompute_hashes(tree: &mut Tree<HtmlTreeTypes<'_>>) {
+///
+/// Text nodes in `collapsed_text` are hashed with their whitespace collapsed;
+/// those in `ignored` are left out of their parents' hashes.
+fn recompute_hashes(
+    tree: &mut Tree<HtmlTreeTypes<'_>>,
+    collapsed_text: &HashSet<NodeId>,
+    ignored: &HashSet<NodeId>,
+) {
     // Process in post-order (children before parents)
     let nodes: Vec<NodeId> = tree.post_order().collect();
 
@@ -932,11 +1390,14 @@ fn recompute_hashes(tree: &mut Tree<HtmlTreeTypes<'_>>) {
 
         // Include text content in hash - this is the identity of text/comment nodes
         if let Some(text) = &data.text {
-            text.hash(&mut hasher);
+            hash_text(text, collapsed_text.contains(&node_id), &mut hasher);
         }
 
         // Hash children's hashes (Merkle-tree style)
-        let children: Vec<NodeId> = tree.children(node_id).collect();
+        let children: Vec<NodeId> = tree
+            .children(node_id)
+            .filter(|child| !ignored.contains(child))
+            .collect();
         for child in children {
             tree.get(child).hash.0.hash(&mut hasher);
         }
@@ -953,7 +1414,7 @@ fn recompute_hashes(tree: &mut Tree<HtmlTreeTypes<'_>>) {
 
 /// Create an insert patch for a node and all its descendants.
 /// Used when we need to insert entire subtrees (e.g., when old doc has no body).
-fn create_insert_patch<'a>(
+pub(crate) fn create_insert_patch<'a>(
     doc: &Document<'a>,
     node_id: NodeId,
     position: usize,
@@ -971,7 +1432,8 @@ fn create_insert_patch<'a>(
                 })
                 .collect();
 
-            let children: Vec<InsertContent<'a>> = node_id
+            let children: Vec<InsertContent<'a>> = doc
+                .children_host(node_id)
                 .children(&doc.arena)
                 .filter_map(|child_id| create_insert_content(doc, child_id))
                 .collect();
@@ -979,6 +1441,7 @@ fn create_insert_patch<'a>(
             Ok(Patch::InsertElement {
                 at: NodeRef(NodePath(smallvec![0, position as u32])),
                 tag: elem.tag.clone(),
+                ns: node.ns,
                 attrs,
                 children,
                 detach_to_slot: None,
@@ -994,7 +1457,7 @@ fn create_insert_patch<'a>(
             text: text.clone(),
             detach_to_slot: None,
         }),
-        NodeKind::Document => Err(DiffError::NoBody), // Shouldn't happen
+        NodeKind::Document | NodeKind::TemplateContents => Err(DiffError::NoBody), // Shouldn't happen
     }
 }
 
@@ -1013,20 +1476,22 @@ fn create_insert_content<'a>(doc: &Document<'a>, node_id: NodeId) -> Option<Inse
                 })
                 .collect();
 
-            let children: Vec<InsertContent<'a>> = node_id
+            let children: Vec<InsertContent<'a>> = doc
+                .children_host(node_id)
                 .children(&doc.arena)
                 .filter_map(|child_id| create_insert_content(doc, child_id))
                 .collect();
 
             Some(InsertContent::Element {
                 tag: elem.tag.clone(),
+                ns: node.ns,
                 attrs,
                 children,
             })
         }
         NodeKind::Text(text) => Some(InsertContent::Text(text.clone())),
         NodeKind::Comment(text) => Some(InsertContent::Comment(text.clone())),
-        NodeKind::Document => None,
+        NodeKind::Document | NodeKind::TemplateContents => None,
     }
 }
 
@@ -1037,16 +1502,107 @@ fn create_insert_content<'a>(doc: &Document<'a>, node_id: NodeId) -> Option<Inse
 pub fn diff_html<'a>(
     old_html: &'a StrTendril,
     new_html: &'a StrTendril,
+) -> Result<Vec<Patch<'a>>, DiffError> {
+    diff_html_with_options(old_html, new_html, &DEFAULT_DIFF_OPTIONS)
+}
+
+/// Like [`diff_html`], with non-default [`DiffOptions`].
+pub fn diff_html_with_options<'a>(
+    old_html: &'a StrTendril,
+    new_html: &'a StrTendril,
+    options: &DiffOptions,
 ) -> Result<Vec<Patch<'a>>, DiffError> {
     let old_doc = dom::parse(old_html);
     let new_doc = dom::parse(new_html);
-    diff(&old_doc, &new_doc)
+    diff_with_options(&old_doc, &new_doc, options)
 }
 
 /// Diff two arena documents and return DOM patches.
 ///
 /// This is the primary diffing API for arena_dom documents.
+/// Body patches are rooted at slot 0; if both documents have a `<head>`,
+/// head patches follow, rooted at [`HEAD_SLOT`].
 pub fn diff<'a>(old: &Document<'a>, new: &Document<'a>) -> Result<Vec<Patch<'a>>, DiffError> {
+    diff_with_options(old, new, &DEFAULT_DIFF_OPTIONS)
+}
+
+/// Like [`diff`], with non-default [`DiffOptions`].
+pub fn diff_with_options<'a>(
+    old: &Document<'a>,
+    new: &Document<'a>,
+    options: &DiffOptions,
+) -> Result<Vec<Patch<'a>>, DiffError> {
+    let mut patches = diff_body(old, new, options)?;
+
+    if let (Some(old_head), Some(new_head)) = (old.head(), new.head()) {
+        // Head displacement slots must not collide with the ones body patches use
+        let first_free_slot = patches
+            .iter()
+            .filter_map(Patch::detach_to_slot)
+            .max()
+            .map_or(1, |slot| slot + 1);
+
+        let tree_a = OpaqueAwareTree::build(old, Some(old_head), "head", options);
+        let diff_b = DiffableDocument::with_root(new, new_head, options);
+        patches.extend(diff_trees(
+            &tree_a,
+            &diff_b,
+            new,
+            options,
+            HEAD_SLOT,
+            first_free_slot,
+        )?);
+    }
+
+    Ok(patches)
+}
+
+/// Diff the subtree of `old` rooted at `old_root` against the one of `new`
+/// rooted at `new_root`.
+///
+/// Paths are rooted at slot 0, which stands for the subtree root rather than
+/// the body: apply the patches with [`Document::apply_patches_at`]. Both
+/// roots must be elements with the same tag and namespace; their attributes
+/// are diffed like any other element's.
+pub fn diff_subtrees<'a>(
+    old: &Document<'a>,
+    old_root: NodeId,
+    new: &Document<'a>,
+    new_root: NodeId,
+) -> Result<Vec<Patch<'a>>, DiffError> {
+    diff_subtrees_with_options(old, old_root, new, new_root, &DEFAULT_DIFF_OPTIONS)
+}
+
+/// Like [`diff_subtrees`], with non-default [`DiffOptions`].
+pub fn diff_subtrees_with_options<'a>(
+    old: &Document<'a>,
+    old_root: NodeId,
+    new: &Document<'a>,
+    new_root: NodeId,
+    options: &DiffOptions,
+) -> Result<Vec<Patch<'a>>, DiffError> {
+    let old_node = old.get(old_root);
+    let new_node = new.get(new_root);
+    match (&old_node.kind, &new_node.kind) {
+        (NodeKind::Element(a), NodeKind::Element(b)) => {
+            if a.tag != b.tag || old_node.ns != new_node.ns {
+                return Err(DiffError::RootMismatch);
+            }
+        }
+        _ => return Err(DiffError::NotAnElement),
+    }
+
+    let tree_a = OpaqueAwareTree::build(old, Some(old_root), "body", options);
+    let diff_b = DiffableDocument::with_root(new, new_root, options);
+    diff_trees(&tree_a, &diff_b, new, options, 0, 1)
+}
+
+/// Diff the `<body>` of two documents, with paths rooted at slot 0.
+fn diff_body<'a>(
+    old: &Document<'a>,
+    new: &Document<'a>,
+    options: &DiffOptions,
+) -> Result<Vec<Patch<'a>>, DiffError> {
     let old_has_body = old.body().is_some();
     let new_has_body = new.body().is_some();
 
@@ -1084,11 +1640,77 @@ pub fn diff<'a>(old: &Document<'a>, new: &Document<'a>) -> Result<Vec<Patch<'a>>
     }
 
     // Build cinereus Tree for old (needed for shadow tree mutation)
-    let raw_tree_a = build_tree_from_arena(old);
-    let tree_a = OpaqueAwareTree(raw_tree_a);
+    let tree_a = OpaqueAwareTree::build(old, old.body(), "body", options);
     // Use DiffableDocument for new (avoids second tree allocation)
-    let diff_b = DiffableDocument::new(new)?;
+    let diff_b = DiffableDocument::new(new, options)?;
+
+    diff_trees(&tree_a, &diff_b, new, options, 0, 1)
+}
+
+/// Patches that swap out everything in an unmatched root: its children are
+/// all removed, its attributes set to the new ones and the new children
+/// inserted. The root itself stays, since it is what the slot points at.
+fn replace_root<'a>(
+    tree_a: &OpaqueAwareTree<'a, '_>,
+    diff_b: &DiffableDocument<'_, 'a>,
+    new: &Document<'a>,
+    root_slot: u32,
+) -> Result<Vec<Patch<'a>>, DiffError> {
+    let mut patches = Vec::new();
+
+    // Every old child goes, ignored whitespace included; back to front keeps indices valid
+    let old_len = tree_a.0.children(tree_a.0.root).count();
+    for pos in (0..old_len).rev() {
+        patches.push(Patch::Remove {
+            node: NodeRef(NodePath(smallvec![root_slot, pos as u32])),
+        });
+    }
+
+    let old_props = &tree_a.0.get(tree_a.0.root).properties;
+    let new_props = diff_b.properties(diff_b.root());
+    if old_props.attrs != new_props.attrs {
+        let changes = old_props
+            .diff(new_props)
+            .into_iter()
+            .map(|c| PropChange {
+                name: c.key,
+                value: match c.value {
+                    cinereus::tree::PropValue::Same => None,
+                    cinereus::tree::PropValue::Different(v) => Some(v),
+                },
+            })
+            .collect();
+        patches.push(Patch::UpdateProps {
+            path: NodePath(smallvec![root_slot]),
+            changes,
+        });
+    }
+
+    for (pos, child) in diff_b.children(diff_b.root()).enumerate() {
+        let mut patch = create_insert_patch(new, child, pos)?;
+        if let Patch::InsertElement { at, .. }
+        | Patch::InsertText { at, .. }
+        | Patch::InsertComment { at, .. } = &mut patch
+        {
+            at.0.0[0] = root_slot;
+        }
+        patches.push(patch);
+    }
+
+    Ok(patches)
+}
 
+/// Diff two prepared trees and convert the edit script to patches.
+///
+/// Paths are rooted at `root_slot`; displaced content is numbered from `first_free_slot`.
+fn diff_trees<'a>(
+    tree_a: &OpaqueAwareTree<'a, '_>,
+    diff_b: &DiffableDocument<'_, 'a>,
+    new: &Document<'a>,
+    options: &DiffOptions,
+    root_slot: u32,
+    first_free_slot: u32,
+) -> Result<Vec<Patch<'a>>, DiffError> {
     #[cfg(test)]
     {
         trace!(
@@ -1104,20 +1726,38 @@ pub fn diff<'a>(old: &Document<'a>, new: &Document<'a>) -> Result<Vec<Patch<'a>>
     }
 
     let config = MatchingConfig {
+        similarity_threshold: options.similarity_threshold,
         min_height: 0,
-        ..MatchingConfig::default()
     };
 
-    let mut matching = cinereus::compute_matching(&tree_a, &diff_b, &config);
+    let mut matching = cinereus::compute_matching(tree_a, diff_b, &config);
 
     // Force root match if same tag
     let root_a_kind = tree_a.0.get(tree_a.0.root).kind.clone();
     let root_b_kind = diff_b.kind(diff_b.root()).clone();
-    if root_a_kind == root_b_kind && !matching.contains_a(tree_a.0.root) {
-        matching.add(tree_a.0.root, diff_b.root());
+    if !matching.contains_a(tree_a.0.root) {
+        if options.force_root_match && root_a_kind == root_b_kind {
+            matching.add(tree_a.0.root, diff_b.root());
+        } else {
+            return replace_root(tree_a, diff_b, new, root_slot);
+        }
     }
 
-    let edit_ops = cinereus::generate_edit_script(&tree_a, &diff_b, &matching);
+    let mut edit_ops = cinereus::generate_edit_script(tree_a, diff_b, &matching);
+
+    // Text that only changed in insignificant whitespace renders the same; leave it be
+    if options.whitespace == WhitespaceMode::Insignificant {
+        edit_ops.retain(|op| match op {
+            EditOp::SetText {
+                node_a,
+                node_b,
+                text,
+            } if diff_b.collapses_whitespace(*node_b) => tree_a.text(*node_a).is_none_or(|old| {
+                collapse_whitespace(old.as_str()) != collapse_whitespace(text.as_str())
+            }),
+            _ => true,
+        });
+    }
 
     #[cfg(test)]
     {
@@ -1158,7 +1798,15 @@ pub fn diff<'a>(old: &Document<'a>, new: &Document<'a>) -> Result<Vec<Patch<'a>>
         ));
     }
 
-    let mut patches = convert_ops_with_shadow(edit_ops, &tree_a.0, &diff_b, &matching)?;
+    let mut patches = convert_ops_with_shadow(
+        edit_ops,
+        tree_a,
+        diff_b,
+        &matching,
+        options,
+        root_slot,
+        first_free_slot,
+    )?;
 
     // Now emit OpaqueChanged patches with correct paths from the shadow tree
     // We need to compute paths for the opaque nodes. Since convert_ops_with_shadow
@@ -1167,7 +1815,12 @@ pub fn diff<'a>(old: &Document<'a>, new: &Document<'a>) -> Result<Vec<Patch<'a>>
     // For now, compute paths using the same approach as the shadow tree.
     if !opaque_patches.is_empty() {
         // Build a fresh shadow just for path computation
-        let shadow = ShadowTree::new(tree_a.0.arena.clone(), tree_a.0.root);
+        let shadow = ShadowTree::with_slots(
+            tree_a.0.arena.clone(),
+            tree_a.0.root,
+            root_slot,
+            first_free_slot,
+        );
         for (a_id, content) in opaque_patches {
             let path = shadow.compute_path(a_id);
             patches.push(Patch::OpaqueChanged {
@@ -1188,18 +1841,35 @@ pub fn diff<'a>(old: &Document<'a>, new: &Document<'a>) -> Result<Vec<Patch<'a>>
 ///
 /// All paths start with the slot number: [0, 1, 2] means slot 0, child 1, child 2.
 /// This eliminates the need for separate tracking of detached nodes.
+///
+/// Internally slots are numbered by their position under the super root. When a tree
+/// is not the body (e.g. the head), `root_slot` and `first_free_slot` translate those
+/// positions into the slot numbers that appear in patches.
 pub(crate) struct ShadowTree<'a> {
     pub(crate) arena: indextree::Arena<NodeData<HtmlTreeTypes<'a>>>,
     /// The super root - its children are slot nodes
     pub(crate) super_root: NodeId,
     /// Number of slots (slot 0 always exists, created in new())
     next_slot: u32,
+    /// Slot number emitted in paths for the original tree
+    root_slot: u32,
+    /// Slot number emitted for the first displaced node
+    first_free_slot: u32,
+    /// Whitespace text the diff ignores; edit-script positions skip it
+    ignored: HashSet<NodeId>,
 }
 
 impl<'a> ShadowTree<'a> {
-    fn new(
+    #[cfg(test)]
+    fn new(arena: indextree::Arena<NodeData<HtmlTreeTypes<'a>>>, original_root: NodeId) -> Self {
+        Self::with_slots(arena, original_root, 0, 1)
+    }
+
+    fn with_slots(
         mut arena: indextree::Arena<NodeData<HtmlTreeTypes<'a>>>,
         original_root: NodeId,
+        root_slot: u32,
+        first_free_slot: u32,
     ) -> Self {
         // Create the super root (a meta node, not a real DOM node)
         let super_root = arena.new_node(NodeData {
@@ -1226,6 +1896,44 @@ impl<'a> ShadowTree<'a> {
             arena,
             super_root,
             next_slot: 1, // Slot 0 already exists
+            root_slot,
+            first_free_slot,
+            ignored: HashSet::new(),
+        }
+    }
+
+    /// Skip `ignored` text nodes when translating edit-script positions.
+    fn ignoring(mut self, ignored: HashSet<NodeId>) -> Self {
+        self.ignored = ignored;
+        self
+    }
+
+    /// Translate an edit-script position under `parent`, which does not count
+    /// ignored whitespace, into an index among all of `parent`'s children.
+    fn child_index(&self, parent: NodeId, position: usize) -> usize {
+        if self.ignored.is_empty() {
+            return position;
+        }
+        let mut visible = 0;
+        let mut count = 0;
+        for child in parent.children(&self.arena) {
+            if !self.ignored.contains(&child) {
+                if visible == position {
+                    return count;
+                }
+                visible += 1;
+            }
+            count += 1;
+        }
+        count + (position - visible)
+    }
+
+    /// Translate an internal slot index into the slot number used in patches.
+    fn external_slot(&self, slot: u32) -> u32 {
+        if slot == 0 {
+            self.root_slot
+        } else {
+            slot - 1 + self.first_free_slot
         }
     }
 
@@ -1266,7 +1974,7 @@ impl<'a> ShadowTree<'a> {
                     .children(&self.arena)
                     .position(|c| c == parent_id)
                     .unwrap_or(0) as u32;
-                path.push(slot);
+                path.push(self.external_slot(slot));
                 break;
             }
 
@@ -1323,7 +2031,7 @@ impl<'a> ShadowTree<'a> {
         slot_node.append(node, &mut self.arena);
 
         debug!(?node, slot_num, "detached node to slot");
-        slot_num
+        self.external_slot(slot_num)
     }
 
     /// Detach a node with a placeholder to prevent sibling shifts.
@@ -1536,12 +2244,21 @@ impl<'a> ShadowTree<'a> {
 /// They DISPLACE whatever is at the target position into a slot for later use.
 fn convert_ops_with_shadow<'a, T: DiffTree<Types = HtmlTreeTypes<'a>>>(
     ops: Vec<EditOp<HtmlTreeTypes<'a>>>,
-    tree_a: &Tree<HtmlTreeTypes<'a>>,
+    tree_a: &OpaqueAwareTree<'a, '_>,
     tree_b: &T,
     matching: &Matching,
+    options: &DiffOptions,
+    root_slot: u32,
+    first_free_slot: u32,
 ) -> Result<Vec<Patch<'a>>, DiffError> {
     // Create shadow tree with encapsulated state
-    let mut shadow = ShadowTree::new(tree_a.arena.clone(), tree_a.root);
+    let mut shadow = ShadowTree::with_slots(
+        tree_a.0.arena.clone(),
+        tree_a.0.root,
+        root_slot,
+        first_free_slot,
+    )
+    .ignoring(tree_a.2.clone());
 
     // Map from tree_b NodeIds to shadow tree NodeIds
     // Initially populated from matching (matched nodes)
@@ -1622,16 +2339,17 @@ fn convert_ops_with_shadow<'a, T: DiffTree<Types = HtmlTreeTypes<'a>>>(
                 let new_node = shadow.arena.new_node(new_data);
 
                 // Insert and handle displacement automatically
-                let detach_to_slot = shadow.insert_at_position(shadow_parent, position, new_node);
+                let index = shadow.child_index(shadow_parent, position);
+                let detach_to_slot = shadow.insert_at_position(shadow_parent, index, new_node);
 
                 b_to_shadow.insert(node_b, new_node);
 
                 // Get reference with position included - this makes Insert consistent with Move!
-                let at = shadow.get_node_ref_with_position(shadow_parent, position);
+                let at = shadow.get_node_ref_with_position(shadow_parent, index);
 
                 // Create the patch based on node kind
                 match kind {
-                    HtmlNodeKind::Element(tag, _ns) => {
+                    HtmlNodeKind::Element(tag, ns) => {
                         let (attrs, children) = extract_content_from_tree_b(
                             node_b,
                             tree_b,
@@ -1641,6 +2359,7 @@ fn convert_ops_with_shadow<'a, T: DiffTree<Types = HtmlTreeTypes<'a>>>(
                         result.push(Patch::InsertElement {
                             at,
                             tag: tag.clone(),
+                            ns,
                             attrs,
                             children,
                             detach_to_slot,
@@ -1689,7 +2408,7 @@ fn convert_ops_with_shadow<'a, T: DiffTree<Types = HtmlTreeTypes<'a>>>(
             }
 
             EditOp::Delete { node_a } => {
-                let _node_kind = &tree_a.get(node_a).kind;
+                let _node_kind = &tree_a.0.get(node_a).kind;
                 debug!(?node_a, ?_node_kind, "Delete operation");
 
                 // Get the node reference (path starts with slot number)
@@ -1811,11 +2530,11 @@ fn convert_ops_with_shadow<'a, T: DiffTree<Types = HtmlTreeTypes<'a>>>(
                 }
 
                 // Move node to new position - handles displacement automatically!
-                let detach_to_slot =
-                    shadow.move_to_position(node_a, shadow_new_parent, new_position);
+                let index = shadow.child_index(shadow_new_parent, new_position);
+                let detach_to_slot = shadow.move_to_position(node_a, shadow_new_parent, index);
 
                 // Get target reference with position
-                let to = shadow.get_node_ref_with_position(shadow_new_parent, new_position);
+                let to = shadow.get_node_ref_with_position(shadow_new_parent, index);
 
                 debug!(?node_a, ?from, ?to, ?detach_to_slot, "Generated Move patch");
 
@@ -1838,12 +2557,21 @@ fn convert_ops_with_shadow<'a, T: DiffTree<Types = HtmlTreeTypes<'a>>>(
                 text,
             } => {
                 // Path to the text/comment node
-                let path = shadow.compute_path(node_a);
-
-                result.push(Patch::SetText {
-                    path: NodePath(path),
-                    text,
-                });
+                let path = NodePath(shadow.compute_path(node_a));
+
+                let old_text = shadow.arena[node_a].get().text.as_ref();
+                let splice = old_text
+                    .filter(|old| old.len() >= options.splice_text_min_len)
+                    .and_then(|old| text_splice(old, &text));
+                match splice {
+                    Some(splice) => result.push(Patch::SpliceText {
+                        path,
+                        offset: splice.offset,
+                        delete_len: splice.delete_len,
+                        insert: text.slice(splice.insert),
+                    }),
+                    None => result.push(Patch::SetText { path, text }),
+                }
                 // No structural change for SetText
             }
         }
@@ -1852,6 +2580,44 @@ fn convert_ops_with_shadow<'a, T: DiffTree<Types = HtmlTreeTypes<'a>>>(
     Ok(result)
 }
 
+/// The part of a text that changed, as found by [`text_splice`].
+struct TextSplice {
+    /// Start of the change in the old text, in UTF-16 code units
+    offset: u32,
+    /// Length of the replaced part of the old text, in UTF-16 code units
+    delete_len: u32,
+    /// Byte range of the replacement in the new text
+    insert: std::ops::Range<usize>,
+}
+
+/// Find the middle part that differs between `old` and `new`, after their
+/// common prefix and suffix. Returns `None` if they share neither.
+fn text_splice(old: &str, new: &str) -> Option<TextSplice> {
+    let prefix: usize = old
+        .chars()
+        .zip(new.chars())
+        .take_while(|(a, b)| a == b)
+        .map(|(c, _)| c.len_utf8())
+        .sum();
+    let suffix: usize = old[prefix..]
+        .chars()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(c, _)| c.len_utf8())
+        .sum();
+    if prefix == 0 && suffix == 0 {
+        return None;
+    }
+
+    let utf16_len = |s: &str| s.encode_utf16().count() as u32;
+    Some(TextSplice {
+        offset: utf16_len(&old[..prefix]),
+        delete_len: utf16_len(&old[prefix..old.len() - suffix]),
+        insert: prefix..new.len() - suffix,
+    })
+}
+
 /// Extract attributes and children from a node in tree_b.
 fn extract_content_from_tree_b<'a, T: DiffTree<Types = HtmlTreeTypes<'a>>>(
     node_b: NodeId,
@@ -1881,7 +2647,7 @@ fn extract_content_from_tree_b<'a, T: DiffTree<Types = HtmlTreeTypes<'a>>>(
 
         let child_kind = tree_b.kind(child_id);
         match child_kind {
-            HtmlNodeKind::Element(tag, _ns) => {
+            HtmlNodeKind::Element(tag, ns) => {
                 let (child_attrs, child_children) = extract_content_from_tree_b(
                     child_id,
                     tree_b,
@@ -1890,6 +2656,7 @@ fn extract_content_from_tree_b<'a, T: DiffTree<Types = HtmlTreeTypes<'a>>>(
                 );
                 children.push(InsertContent::Element {
                     tag: tag.clone(),
+                    ns: *ns,
                     attrs: child_attrs,
                     children: child_children,
                 });
@@ -1928,10 +2695,12 @@ impl<'a> InsertContent<'a> {
         match self {
             InsertContent::Element {
                 tag,
+                ns,
                 attrs,
                 children,
             } => InsertContent::Element {
                 tag,
+                ns,
                 attrs: attrs.into_iter().map(|a| a.into_owned()).collect(),
                 children: children.into_iter().map(|c| c.into_owned()).collect(),
             },
@@ -1952,18 +2721,31 @@ impl<'a> PropChange<'a> {
 }
 
 impl<'a> Patch<'a> {
+    /// The slot this patch displaces an occupant into, if any.
+    pub(crate) fn detach_to_slot(&self) -> Option<u32> {
+        match self {
+            Patch::InsertElement { detach_to_slot, .. }
+            | Patch::InsertText { detach_to_slot, .. }
+            | Patch::InsertComment { detach_to_slot, .. }
+            | Patch::Move { detach_to_slot, .. } => *detach_to_slot,
+            _ => None,
+        }
+    }
+
     /// Convert to an owned version with 'static lifetime.
     pub fn into_owned(self) -> Patch<'static> {
         match self {
             Patch::InsertElement {
                 at,
                 tag,
+                ns,
                 attrs,
                 children,
                 detach_to_slot,
             } => Patch::InsertElement {
                 at,
                 tag,
+                ns,
                 attrs: attrs.into_iter().map(|a| a.into_owned()).collect(),
                 children: children.into_iter().map(|c| c.into_owned()).collect(),
                 detach_to_slot,
@@ -1991,6 +2773,17 @@ impl<'a> Patch<'a> {
                 path,
                 text: text.into_owned(),
             },
+            Patch::SpliceText {
+                path,
+                offset,
+                delete_len,
+                insert,
+            } => Patch::SpliceText {
+                path,
+                offset,
+                delete_len,
+                insert: insert.into_owned(),
+            },
             Patch::SetAttribute { path, name, value } => Patch::SetAttribute {
                 path,
                 name,
@@ -2014,6 +2807,7 @@ impl<'a> Patch<'a> {
                 path,
                 content: content.into_owned(),
             },
+            Patch::Detach { node, to_slot } => Patch::Detach { node, to_slot },
         }
     }
 }
@@ -2033,7 +2827,7 @@ mod tests {
     fn test_build_tree_simple() {
         let html = t("<html><body><div>hello</div></body></html>");
         let doc = dom::parse(&html);
-        let tree = build_tree_from_arena(&doc);
+        let tree = build_tree_from_arena(&doc, &DiffOptions::default());
 
         // Root is body element (build_tree_from_arena uses body as root)
         let root_data = tree.get(tree.root);
@@ -2062,52 +2856,252 @@ mod tests {
     }
 
     #[test]
-    fn test_diff_attr_change() {
-        let old_html = t(r#"<html><body><div class="foo"></div></body></html>"#);
-        let new_html = t(r#"<html><body><div class="bar"></div></body></html>"#);
+    fn test_diff_head_title_change() {
+        let old_html = t("<html><head><title>old</title></head><body><p>x</p></body></html>");
+        let new_html = t("<html><head><title>new</title></head><body><p>x</p></body></html>");
 
         let old = dom::parse(&old_html);
         let new = dom::parse(&new_html);
         let patches = diff(&old, &new).unwrap();
 
-        let has_attr_update = patches.iter().any(|p| {
-            matches!(p, Patch::UpdateProps { changes, .. }
-                if changes.iter().any(|c| matches!(c.name, PropKey::Attr(ref q) if q.local.as_ref() == "class")))
-        });
+        // Body is unchanged, so the text update must land in the head slot
+        let has_head_text_update = patches.iter().any(
+            |p| matches!(p, Patch::SetText { path, .. } if path.0.first() == Some(&HEAD_SLOT)),
+        );
         assert!(
-            has_attr_update,
-            "Expected attr update patch, got: {:?}",
+            has_head_text_update,
+            "Expected SetText patch in head slot, got: {:?}",
             patches
         );
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), new.to_html());
     }
 
     #[test]
-    fn test_diff_remove_all_children() {
-        // Reproduce fuzzer failure: body with child -> body with no children
-        let old_html = t("<html><body><span></span></body></html>");
-        let new_html = t("<html><body></body></html>");
+    fn test_diff_head_slots_do_not_collide_with_body_slots() {
+        let old_html = t("<html><head><title>t</title></head><body><p>a</p><p>b</p></body></html>");
+        let new_html = t(
+            "<html><head><link rel=stylesheet href=x.css><title>t</title></head><body><p>b</p><p>a</p></body></html>",
+        );
 
         let old = dom::parse(&old_html);
         let new = dom::parse(&new_html);
         let patches = diff(&old, &new).unwrap();
-        debug!("Patches: {:#?}", patches);
 
-        // Should be able to apply the patches
-        let mut doc = dom::parse(&old_html);
-        doc.apply_patches(patches).expect("apply should succeed");
+        let mut seen = HashSet::new();
+        for slot in patches.iter().filter_map(Patch::detach_to_slot) {
+            assert!(seen.insert(slot), "slot {slot} reused in {patches:?}");
+        }
 
-        let result = doc.to_html();
-        let expected = dom::parse(&new_html).to_html();
-        assert_eq!(result, expected, "HTML output should match");
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), new.to_html());
     }
 
     #[test]
-    fn test_diff_complex_fuzzer_case() {
-        // Fuzzer found: <body><strong>old_text</strong></body> -> <body>new_text<strong>updated</strong></body>
-        let old_html = t("<html><body><strong>old</strong></body></html>");
-        let new_html = t("<html><body>new_text<strong>updated</strong></body></html>");
-
-        let old = dom::parse(&old_html);
+    fn test_diff_subtrees_patches_only_the_root() {
+        let old_html = t(
+            r#"<html><body><nav>a</nav><main id="content"><p>old</p><ul><li>1</li></ul></main></body></html>"#,
+        );
+        let new_html = t(
+            r#"<html><body><nav>b</nav><main id="content" class="x"><p>new</p><ul><li>1</li><li>2</li></ul></main></body></html>"#,
+        );
+
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+        let old_root = old.select_first("#content").unwrap().unwrap();
+        let new_root = new.select_first("#content").unwrap().unwrap();
+        let patches = diff_subtrees(&old, old_root, &new, new_root).unwrap();
+
+        let mut doc = dom::parse(&old_html);
+        let root = doc.select_first("#content").unwrap().unwrap();
+        doc.validate_patches_at(root, &patches).unwrap();
+        doc.apply_patches_at(root, patches).unwrap();
+        assert_eq!(
+            doc.to_body_html(),
+            r#"<nav>a</nav><main id="content" class="x"><p>new</p><ul><li>1</li><li>2</li></ul></main>"#
+        );
+    }
+
+    #[test]
+    fn test_diff_subtrees_roots_must_match() {
+        let old_html = t("<html><body><div>a</div></body></html>");
+        let new_html = t("<html><body><section>a</section></body></html>");
+
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+        let old_div = old.select_first("div").unwrap().unwrap();
+        let new_section = new.select_first("section").unwrap().unwrap();
+        assert!(matches!(
+            diff_subtrees(&old, old_div, &new, new_section),
+            Err(DiffError::RootMismatch)
+        ));
+
+        let text = new.children(new_section).next().unwrap();
+        assert!(matches!(
+            diff_subtrees(&old, old_div, &new, text),
+            Err(DiffError::NotAnElement)
+        ));
+    }
+
+    #[test]
+    fn test_diff_keyed_reorder_moves_items() {
+        let old_html = t(
+            r#"<html><body><ul><li data-key="a" class="x">A</li><li data-key="b" class="y">B</li><li data-key="c" class="z">C</li></ul></body></html>"#,
+        );
+        let new_html = t(
+            r#"<html><body><ul><li data-key="c" class="z">C<b>!</b></li><li data-key="a" class="x">A</li><li data-key="b" class="y">B</li></ul></body></html>"#,
+        );
+
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+        let options = DiffOptions {
+            key_attr: Some("data-key".into()),
+            ..DiffOptions::default()
+        };
+        let patches = diff_with_options(&old, &new, &options).unwrap();
+
+        // Items are moved, never rewritten in place; the only insertion is the new <b>
+        assert!(
+            !patches.iter().any(|p| matches!(
+                p,
+                Patch::UpdateProps { .. } | Patch::SetText { .. } | Patch::Remove { .. }
+            )),
+            "Expected no in-place rewrites, got: {:?}",
+            patches
+        );
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), new.to_html());
+    }
+
+    #[test]
+    fn test_diff_id_keyed_items_keep_identity() {
+        // `id` works as a key too, by default
+        let old_html = t(
+            r#"<html><body><ul><li id="a" class="x">A</li><li id="b" class="y">B</li><li id="c" class="z">C</li></ul></body></html>"#,
+        );
+        let new_html = t(
+            r#"<html><body><ul><li id="c" class="z">C<b>!</b></li><li id="a" class="x">A</li><li id="b" class="y">B</li></ul></body></html>"#,
+        );
+
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+        let patches = diff(&old, &new).unwrap();
+
+        assert!(
+            !patches.iter().any(|p| matches!(
+                p,
+                Patch::UpdateProps { .. } | Patch::SetText { .. } | Patch::Remove { .. }
+            )),
+            "Expected no in-place rewrites, got: {:?}",
+            patches
+        );
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), new.to_html());
+    }
+
+    #[test]
+    fn test_diff_keys_by_id_by_default() {
+        let old_html =
+            t(r#"<html><body><ul><li id="a">A</li><li id="b">B</li></ul></body></html>"#);
+        let new_html =
+            t(r#"<html><body><ul><li id="b">B!</li><li id="a">A!</li></ul></body></html>"#);
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+
+        let ul = old.body().unwrap().children(&old.arena).next().unwrap();
+        let li = ul.children(&old.arena).next().unwrap();
+
+        // By default, and as a fallback for elements without a `key_attr`
+        for options in [
+            DiffOptions::default(),
+            DiffOptions {
+                key_attr: Some("data-key".into()),
+                ..DiffOptions::default()
+            },
+        ] {
+            let tree = DiffableDocument::new(&old, &options).unwrap();
+            assert_eq!(tree.identity_key(li), Some("a"), "{options:?}");
+        }
+
+        let unkeyed = DiffOptions {
+            key_by_id: false,
+            ..DiffOptions::default()
+        };
+        let tree = DiffableDocument::new(&old, &unkeyed).unwrap();
+        assert_eq!(tree.identity_key(li), None);
+
+        // Keyed, the items are moved; unkeyed, they're recreated
+        let inserts_li = |patches: &[Patch<'_>]| {
+            patches
+                .iter()
+                .any(|p| matches!(p, Patch::InsertElement { tag, .. } if tag == &local_name!("li")))
+        };
+        let keyed_patches = diff(&old, &new).unwrap();
+        assert!(!inserts_li(&keyed_patches), "got: {keyed_patches:?}");
+        let unkeyed_patches = diff_with_options(&old, &new, &unkeyed).unwrap();
+        assert!(inserts_li(&unkeyed_patches), "got: {unkeyed_patches:?}");
+
+        for patches in [keyed_patches, unkeyed_patches] {
+            let mut doc = dom::parse(&old_html);
+            doc.apply_patches(patches).unwrap();
+            assert_eq!(doc.to_html(), new.to_html());
+        }
+    }
+
+    #[test]
+    fn test_diff_attr_change() {
+        let old_html = t(r#"<html><body><div class="foo"></div></body></html>"#);
+        let new_html = t(r#"<html><body><div class="bar"></div></body></html>"#);
+
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+        let patches = diff(&old, &new).unwrap();
+
+        let has_attr_update = patches.iter().any(|p| {
+            matches!(p, Patch::UpdateProps { changes, .. }
+                if changes.iter().any(|c| matches!(c.name, PropKey::Attr(ref q) if q.local.as_ref() == "class")))
+        });
+        assert!(
+            has_attr_update,
+            "Expected attr update patch, got: {:?}",
+            patches
+        );
+    }
+
+    #[test]
+    fn test_diff_remove_all_children() {
+        // Reproduce fuzzer failure: body with child -> body with no children
+        let old_html = t("<html><body><span></span></body></html>");
+        let new_html = t("<html><body></body></html>");
+
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+        let patches = diff(&old, &new).unwrap();
+        debug!("Patches: {:#?}", patches);
+
+        // Should be able to apply the patches
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).expect("apply should succeed");
+
+        let result = doc.to_html();
+        let expected = dom::parse(&new_html).to_html();
+        assert_eq!(result, expected, "HTML output should match");
+    }
+
+    #[test]
+    fn test_diff_complex_fuzzer_case() {
+        // Fuzzer found: <body><strong>old_text</strong></body> -> <body>new_text<strong>updated</strong></body>
+        let old_html = t("<html><body><strong>old</strong></body></html>");
+        let new_html = t("<html><body>new_text<strong>updated</strong></body></html>");
+
+        let old = dom::parse(&old_html);
         let new = dom::parse(&new_html);
         let patches = diff(&old, &new).unwrap();
         debug!("Patches: {:#?}", patches);
@@ -3047,6 +4041,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_opaque_template_rust_applier() {
+        let old_html =
+            t(r#"<html><body><template data-hotmeal-opaque><p>old</p></template></body></html>"#);
+        let new_html =
+            t(r#"<html><body><template data-hotmeal-opaque><p>new</p></template></body></html>"#);
+
+        let patches = diff_html(&old_html, &new_html).unwrap();
+        assert!(
+            patches
+                .iter()
+                .any(|p| matches!(p, Patch::OpaqueChanged { .. })),
+            "got: {:?}",
+            patches
+        );
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), dom::parse(&new_html).to_html());
+
+        // The new content went into the template's contents, replacing the old
+        let template = doc.children(doc.body().unwrap()).next().unwrap();
+        let contents = doc.template_contents(template).unwrap();
+        assert_eq!(
+            template.children(&doc.arena).collect::<Vec<_>>(),
+            [contents]
+        );
+        assert_eq!(doc.serialize_inner_html(template), "<p>new</p>");
+    }
+
     #[test]
     fn test_opaque_deeply_nested_content() {
         // Opaque node with deeply nested content changing
@@ -3226,4 +4250,508 @@ mod tests {
             "Roundtrip with sibling changes should match"
         );
     }
+
+    #[test]
+    fn test_diff_options_custom_opaque_attr() {
+        let old_html = t(r#"<html><body><div data-island><p>old</p></div></body></html>"#);
+        let new_html = t(r#"<html><body><div data-island><p>new</p></div></body></html>"#);
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+
+        // By default, data-island is just another attribute
+        let patches = diff(&old, &new).unwrap();
+        assert!(
+            !patches
+                .iter()
+                .any(|p| matches!(p, Patch::OpaqueChanged { .. })),
+            "got: {:?}",
+            patches
+        );
+
+        let options = DiffOptions {
+            opaque_attr: "data-island".into(),
+            ..DiffOptions::default()
+        };
+        let patches = diff_with_options(&old, &new, &options).unwrap();
+        assert_eq!(patches.len(), 1, "got: {:?}", patches);
+        assert!(matches!(patches[0], Patch::OpaqueChanged { .. }));
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), new.to_html());
+    }
+
+    #[test]
+    fn test_diff_options_custom_key_attr() {
+        let old_html = t(
+            r#"<html><body><ul><li data-row="a" class="x">A</li><li data-row="b" class="y">B</li><li data-row="c" class="z">C</li></ul></body></html>"#,
+        );
+        let new_html = t(
+            r#"<html><body><ul><li data-row="c" class="z">C<b>!</b></li><li data-row="a" class="x">A</li><li data-row="b" class="y">B</li></ul></body></html>"#,
+        );
+        let options = DiffOptions {
+            key_attr: Some("data-row".into()),
+            ..DiffOptions::default()
+        };
+        let patches = diff_html_with_options(&old_html, &new_html, &options).unwrap();
+
+        assert!(
+            !patches.iter().any(|p| matches!(
+                p,
+                Patch::UpdateProps { .. } | Patch::SetText { .. } | Patch::Remove { .. }
+            )),
+            "Expected no in-place rewrites, got: {:?}",
+            patches
+        );
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), dom::parse(&new_html).to_html());
+    }
+
+    #[test]
+    fn test_diff_options_similarity_threshold() {
+        let old_html =
+            t(r#"<html><body><div class="a"><p>1</p><p>2</p><p>3</p></div></body></html>"#);
+        let new_html =
+            t(r#"<html><body><div class="a"><p>1</p><p>2</p><p>9</p></div></body></html>"#);
+
+        // Two out of three paragraphs agree: similar enough by default
+        let patches = diff_html(&old_html, &new_html).unwrap();
+        assert_eq!(patches.len(), 1, "got: {:?}", patches);
+        assert!(matches!(patches[0], Patch::SetText { .. }));
+
+        // ...but not when everything has to agree, so the div is rebuilt
+        let options = DiffOptions {
+            similarity_threshold: 1.0,
+            ..DiffOptions::default()
+        };
+        let patches = diff_html_with_options(&old_html, &new_html, &options).unwrap();
+        assert!(
+            patches
+                .iter()
+                .any(|p| matches!(p, Patch::InsertElement { tag, .. } if tag.as_ref() == "div")),
+            "got: {:?}",
+            patches
+        );
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), dom::parse(&new_html).to_html());
+    }
+
+    #[test]
+    fn test_diff_options_no_force_root_match() {
+        let old_html = t(r#"<html><body><p>x</p></body></html>"#);
+        let new_html = t(r#"<html><body><div>y</div></body></html>"#);
+
+        // The bodies share nothing, but by default the text node is still reused
+        let patches = diff_html(&old_html, &new_html).unwrap();
+        assert!(
+            patches.iter().any(|p| matches!(p, Patch::SetText { .. })),
+            "got: {:?}",
+            patches
+        );
+
+        let options = DiffOptions {
+            force_root_match: false,
+            ..DiffOptions::default()
+        };
+        let patches = diff_html_with_options(&old_html, &new_html, &options).unwrap();
+        assert!(
+            !patches
+                .iter()
+                .any(|p| matches!(p, Patch::SetText { .. } | Patch::Move { .. })),
+            "got: {:?}",
+            patches
+        );
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), dom::parse(&new_html).to_html());
+    }
+
+    #[test]
+    fn test_diff_unchanged_body_attributes() {
+        let html = t(r#"<html><body class="x"><p>a</p></body></html>"#);
+        let patches = diff_html(&html, &html).unwrap();
+        assert!(patches.is_empty(), "got: {:?}", patches);
+    }
+
+    #[test]
+    fn test_diff_options_no_force_root_match_replaces_changed_root() {
+        let old_html = t(r#"<html><body><div id="a"><p>x</p></div></body></html>"#);
+        let new_html = t(r#"<html><body><section id="a"><p>x</p></section></body></html>"#);
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+        let old_root = old.children(old.body().unwrap()).next().unwrap();
+        let new_root = new.children(new.body().unwrap()).next().unwrap();
+
+        let options = DiffOptions {
+            force_root_match: false,
+            ..DiffOptions::default()
+        };
+        let tree_a = OpaqueAwareTree::build(&old, Some(old_root), "body", &options);
+        let diff_b = DiffableDocument::with_root(&new, new_root, &options);
+        let patches = diff_trees(&tree_a, &diff_b, &new, &options, 0, 1).unwrap();
+
+        // The roots differ in kind, so the <p> is not carried over
+        assert!(
+            matches!(
+                patches.as_slice(),
+                [Patch::Remove { node }, Patch::InsertElement { at, .. }]
+                    if node.0.0.as_slice() == [0, 0] && at.0.0.as_slice() == [0, 0]
+            ),
+            "got: {:?}",
+            patches
+        );
+    }
+
+    fn insignificant_whitespace() -> DiffOptions {
+        DiffOptions {
+            whitespace: WhitespaceMode::Insignificant,
+            ..DiffOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_diff_whitespace_insignificant_reindent() {
+        let old_html = t("<html><body><div>\n  <p>Hello   world</p>\n</div></body></html>");
+        let new_html = t("<html><body><div>\n      <p>Hello\n world</p>\n    </div></body></html>");
+
+        let patches = diff_html(&old_html, &new_html).unwrap();
+        assert!(!patches.is_empty());
+
+        let patches =
+            diff_html_with_options(&old_html, &new_html, &insignificant_whitespace()).unwrap();
+        assert!(patches.is_empty(), "got: {:?}", patches);
+    }
+
+    #[test]
+    fn test_diff_whitespace_insignificant_ignores_indentation() {
+        let old_html = t("<html><body><div><p>a</p><p>b</p></div></body></html>");
+        let new_html = t("<html><body><div>\n  <p>a</p>\n  <p>b</p>\n</div></body></html>");
+        let options = insignificant_whitespace();
+
+        for (from, to) in [(&old_html, &new_html), (&new_html, &old_html)] {
+            let patches = diff_html_with_options(from, to, &options).unwrap();
+            assert!(patches.is_empty(), "got: {:?}", patches);
+        }
+    }
+
+    #[test]
+    fn test_diff_whitespace_insignificant_keeps_space_between_inline_elements() {
+        let old_html = t("<html><body><p><span>a</span> <span>b</span></p></body></html>");
+        let new_html = t("<html><body><p><span>a</span><span>b</span></p></body></html>");
+        let options = insignificant_whitespace();
+
+        for (from, to) in [(&old_html, &new_html), (&new_html, &old_html)] {
+            let patches = diff_html_with_options(from, to, &options).unwrap();
+            assert!(
+                !patches.is_empty(),
+                "the space renders, so it must be diffed"
+            );
+
+            let mut doc = dom::parse(from);
+            doc.apply_patches(patches).unwrap();
+            assert_eq!(doc.to_html(), dom::parse(to).to_html());
+        }
+    }
+
+    #[test]
+    fn test_diff_whitespace_insignificant_edits_between_indentation() {
+        let options = insignificant_whitespace();
+        for (old_html, new_html) in [
+            (
+                "<ul>\n  <li>a</li>\n  <li>c</li>\n</ul>",
+                "<ul><li>a</li><li>b</li><li>c</li></ul>",
+            ),
+            (
+                "<ul><li>a</li><li>c</li></ul>",
+                "<ul>\n  <li>a</li>\n  <li>b</li>\n  <li>c</li>\n</ul>",
+            ),
+            (
+                "<ul>\n  <li>a</li>\n  <li>b</li>\n  <li>c</li>\n</ul>",
+                "<ul>\n  <li>c</li>\n  <li>a</li>\n</ul>",
+            ),
+            (
+                "<div>\n  <p>one</p>\n  <section>\n    <p>two</p>\n  </section>\n</div>",
+                "<div><section><p>two</p><p>one</p></section><p>three</p></div>",
+            ),
+        ] {
+            let old_html = t(&format!("<html><body>{old_html}</body></html>"));
+            let new_html = t(&format!("<html><body>{new_html}</body></html>"));
+            let new = dom::parse(&new_html);
+            let patches = diff_with_options(&dom::parse(&old_html), &new, &options).unwrap();
+
+            let mut doc = dom::parse(&old_html);
+            doc.apply_patches(patches).unwrap();
+            let leftover = diff_with_options(&doc, &new, &options).unwrap();
+            assert!(
+                leftover.is_empty(),
+                "{old_html:?} -> {new_html:?} gave {}, leftover: {leftover:?}",
+                doc.to_html()
+            );
+        }
+    }
+
+    #[test]
+    fn test_diff_whitespace_insignificant_keeps_preserved_elements() {
+        let options = insignificant_whitespace();
+        for (old_html, new_html) in [
+            ("<pre>a  b</pre>", "<pre>a b</pre>"),
+            ("<textarea>a  b</textarea>", "<textarea>a b</textarea>"),
+            (
+                "<pre><code>a  b</code></pre>",
+                "<pre><code>a b</code></pre>",
+            ),
+            (
+                r#"<div style="color: red; white-space: pre-wrap">a  b</div>"#,
+                r#"<div style="color: red; white-space: pre-wrap">a b</div>"#,
+            ),
+        ] {
+            let old_html = t(&format!("<html><body>{old_html}</body></html>"));
+            let new_html = t(&format!("<html><body>{new_html}</body></html>"));
+            let patches = diff_html_with_options(&old_html, &new_html, &options).unwrap();
+            assert!(
+                patches.iter().any(|p| matches!(p, Patch::SetText { .. })),
+                "{old_html:?} -> {new_html:?} got: {patches:?}"
+            );
+
+            let mut doc = dom::parse(&old_html);
+            doc.apply_patches(patches).unwrap();
+            assert_eq!(doc.to_html(), dom::parse(&new_html).to_html());
+        }
+
+        // The allow-list is configurable
+        let options = DiffOptions {
+            preserve_whitespace_in: vec![],
+            ..insignificant_whitespace()
+        };
+        let old_html = t("<html><body><pre>a  b</pre></body></html>");
+        let new_html = t("<html><body><pre>a b</pre></body></html>");
+        let patches = diff_html_with_options(&old_html, &new_html, &options).unwrap();
+        assert!(patches.is_empty(), "got: {:?}", patches);
+    }
+
+    #[test]
+    fn test_diff_whitespace_insignificant_roundtrip() {
+        let old_html = t(
+            "<html><head>\n<title>A  page</title>\n</head><body><ul>\n  <li>a</li>\n</ul><p>x</p></body></html>",
+        );
+        let new_html = t(
+            "<html><head>\n  <title>A page</title>\n</head><body><ul>\n    <li>a</li>\n    <li>b</li>\n  </ul><p>y  z</p></body></html>",
+        );
+        let options = insignificant_whitespace();
+
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+        let patches = diff_with_options(&old, &new, &options).unwrap();
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+
+        // Equal up to whitespace, and real text changes made it through
+        let leftover = diff_with_options(&doc, &new, &options).unwrap();
+        assert!(leftover.is_empty(), "got: {:?}", leftover);
+        assert!(doc.to_html().contains("<p>y  z</p>"), "{}", doc.to_html());
+        assert!(doc.to_html().contains("<li>b</li>"), "{}", doc.to_html());
+    }
+
+    #[test]
+    fn test_text_splice() {
+        let splice = text_splice("hello world", "hello there world").unwrap();
+        assert_eq!((splice.offset, splice.delete_len), (6, 0));
+        assert_eq!(splice.insert, 6..12);
+
+        // Offsets count UTF-16 code units, ranges stay on char boundaries
+        let splice = text_splice("👋 é!", "👋 è!").unwrap();
+        assert_eq!((splice.offset, splice.delete_len), (3, 1));
+        assert_eq!(&"👋 è!"[splice.insert], "è");
+
+        assert!(text_splice("abc", "xyz").is_none());
+    }
+
+    #[test]
+    fn test_diff_splice_text_for_long_text() {
+        let sentence = "The quick brown fox jumps over the lazy dog. ";
+        let old_text = format!("{}cat{}", sentence.repeat(3), sentence.repeat(3));
+        let new_text = format!("{}mouse{}", sentence.repeat(3), sentence.repeat(3));
+        let old_html = t(&format!("<html><body><p>{old_text}</p></body></html>"));
+        let new_html = t(&format!("<html><body><p>{new_text}</p></body></html>"));
+
+        let patches = diff_html(&old_html, &new_html).unwrap();
+        assert!(
+            !patches.iter().any(|p| matches!(p, Patch::SetText { .. })),
+            "got: {:?}",
+            patches
+        );
+        let Some(Patch::SpliceText {
+            offset,
+            delete_len,
+            insert,
+            ..
+        }) = patches
+            .iter()
+            .find(|p| matches!(p, Patch::SpliceText { .. }))
+        else {
+            panic!("expected SpliceText, got: {:?}", patches);
+        };
+        assert_eq!(*offset as usize, sentence.len() * 3);
+        assert_eq!(*delete_len, 3);
+        assert_eq!(insert.as_str(), "mouse");
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), dom::parse(&new_html).to_html());
+
+        // Turned off, the whole text is sent
+        let options = DiffOptions {
+            splice_text_min_len: usize::MAX,
+            ..DiffOptions::default()
+        };
+        let patches = diff_html_with_options(&old_html, &new_html, &options).unwrap();
+        assert!(
+            patches
+                .iter()
+                .any(|p| matches!(p, Patch::SetText { text, .. } if text.as_str() == new_text)),
+            "got: {:?}",
+            patches
+        );
+        assert!(
+            !patches
+                .iter()
+                .any(|p| matches!(p, Patch::SpliceText { .. })),
+            "got: {:?}",
+            patches
+        );
+    }
+
+    #[test]
+    fn test_diff_short_text_uses_set_text() {
+        let old_html = t("<html><body><p>Hello world</p></body></html>");
+        let new_html = t("<html><body><p>Hello there</p></body></html>");
+        let patches = diff_html(&old_html, &new_html).unwrap();
+        assert!(
+            patches.iter().any(|p| matches!(p, Patch::SetText { .. })),
+            "got: {:?}",
+            patches
+        );
+        assert!(
+            !patches
+                .iter()
+                .any(|p| matches!(p, Patch::SpliceText { .. })),
+            "got: {:?}",
+            patches
+        );
+    }
+
+    /// Tag, namespace and attribute namespaces of every element, in document order.
+    fn element_namespaces(doc: &Document<'_>) -> Vec<(String, Namespace, Vec<String>)> {
+        doc.root
+            .descendants(&doc.arena)
+            .filter_map(|id| match &doc.get(id).kind {
+                NodeKind::Element(elem) => Some((
+                    elem.tag.to_string(),
+                    doc.get(id).ns,
+                    elem.attrs.iter().map(|(k, _)| k.ns.to_string()).collect(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_insert_keeps_svg_namespace() {
+        let old_html = t("<html><body><p>icon:</p></body></html>");
+        let new_html = t(
+            r##"<html><body><p>icon:</p><svg viewBox="0 0 10 10"><circle r="4"></circle><use xlink:href="#i"></use></svg><math><mi>x</mi></math></body></html>"##,
+        );
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+        let patches = diff(&old, &new).unwrap();
+
+        let svg = patches.iter().find_map(|p| match p {
+            Patch::InsertElement {
+                tag, ns, children, ..
+            } if tag.as_ref() == "svg" => Some((ns, children)),
+            _ => None,
+        });
+        let Some((ns, children)) = svg else {
+            panic!("expected an svg insert, got: {:?}", patches);
+        };
+        assert_eq!(*ns, Namespace::Svg);
+        assert!(
+            children.iter().all(|c| matches!(
+                c,
+                InsertContent::Element {
+                    ns: Namespace::Svg,
+                    ..
+                }
+            )),
+            "got: {:?}",
+            children
+        );
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), new.to_html());
+        assert_eq!(element_namespaces(&doc), element_namespaces(&new));
+        assert!(
+            element_namespaces(&doc)
+                .iter()
+                .any(|(tag, ns, attrs)| tag == "use"
+                    && *ns == Namespace::Svg
+                    && attrs == &["http://www.w3.org/1999/xlink"]),
+            "{:?}",
+            element_namespaces(&doc)
+        );
+    }
+
+    #[test]
+    fn test_diff_inside_template_contents() {
+        let old_html =
+            t("<html><body><template><p>one</p><p>two</p></template><p>after</p></body></html>");
+        let new_html = t(
+            "<html><body><template><p>one</p><p>zwei</p><b>three</b></template><p>after</p></body></html>",
+        );
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+        let patches = diff(&old, &new).unwrap();
+
+        // Paths look through the contents fragment: the template's children
+        // are addressed directly under [0, 0]
+        assert!(
+            patches.iter().any(|p| matches!(
+                p,
+                Patch::InsertElement { at, tag, .. }
+                    if tag.as_ref() == "b" && at.0.0.as_slice() == [0, 0, 2]
+            )),
+            "got: {:?}",
+            patches
+        );
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), new.to_html());
+        let template = doc.children(doc.body().unwrap()).next().unwrap();
+        assert!(doc.template_contents(template).is_some());
+        assert_eq!(template.children(&doc.arena).count(), 1);
+    }
+
+    #[test]
+    fn test_diff_insert_template_creates_contents() {
+        let old_html = t("<html><body><p>x</p></body></html>");
+        let new_html = t("<html><body><p>x</p><template><li>a</li>b</template></body></html>");
+        let old = dom::parse(&old_html);
+        let new = dom::parse(&new_html);
+        let patches = diff(&old, &new).unwrap();
+
+        let mut doc = dom::parse(&old_html);
+        doc.apply_patches(patches).unwrap();
+        assert_eq!(doc.to_html(), new.to_html());
+        let template = doc.children(doc.body().unwrap()).nth(1).unwrap();
+        let contents = doc.template_contents(template).expect("template contents");
+        assert_eq!(doc.children(contents).count(), 2);
+    }
 }
@@ -7,28 +7,25 @@
 //! - **Zero conversions**: Same representation used by parser, differ, and patch applier
 
 use cinereus::indextree::{Arena, NodeId};
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
 use html5ever::tree_builder::{ElemName, ElementFlags, NodeOrText, QuirksMode, TreeSink};
 use html5ever::{Attribute, LocalName, QualName, parse_document};
 use html5ever::{local_name, ns};
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::{fmt, io};
 use tendril::StrTendril;
 
-use crate::diff::{DiffError, InsertContent, NodeRef, Patch, PropKey};
-use crate::{Stem, debug};
-
-#[cfg(feature = "tracing")]
-macro_rules! trace {
-    ($($arg:tt)*) => {
-        tracing::trace!($($arg)*)
-    };
-}
-
-#[cfg(not(feature = "tracing"))]
-macro_rules! trace {
-    ($($arg:tt)*) => {};
-}
+use crate::diff::{
+    DiffError, HEAD_SLOT, InsertContent, NodePath, NodeRef, Patch, PropKey, collapse_whitespace,
+    style_preserves_whitespace,
+};
+use crate::inverse::{Inserted, InverseRecorder, MoveOrigin};
+use crate::select::{Selector, SelectorError};
+use crate::span::{self as spans, ParseError, Span, SpanTracker};
+use crate::validate::{DryRun, PatchValidationError};
+use crate::{Stem, debug, trace};
 
 /// Arena-based DOM document.
 #[derive(Debug, Clone)]
@@ -40,10 +37,14 @@ pub struct Document<'a> {
     pub root: NodeId,
 
     /// Errors encountered while parsing
-    pub errors: Vec<Cow<'static, str>>,
+    pub errors: Vec<ParseError>,
 
     /// DOCTYPE if present (usually "html")
     pub doctype: Option<Stem<'a>>,
+
+    /// Encoding the input was decoded from: UTF-8, unless it was parsed
+    /// with [`parse_bytes`]
+    pub encoding: &'static Encoding,
 }
 
 impl<'a> Document<'a> {
@@ -58,6 +59,7 @@ impl<'a> Document<'a> {
                 attrs: Vec::new(),
             }),
             ns: Namespace::Html,
+            span: None,
         });
 
         // Create head element
@@ -67,6 +69,7 @@ impl<'a> Document<'a> {
                 attrs: Vec::new(),
             }),
             ns: Namespace::Html,
+            span: None,
         });
         html.append(head, &mut arena);
 
@@ -77,6 +80,7 @@ impl<'a> Document<'a> {
                 attrs: Vec::new(),
             }),
             ns: Namespace::Html,
+            span: None,
         });
         html.append(body, &mut arena);
 
@@ -85,6 +89,7 @@ impl<'a> Document<'a> {
             root: html,
             errors: Default::default(),
             doctype: None,
+            encoding: UTF_8,
         }
     }
 
@@ -108,6 +113,7 @@ impl<'a> Document<'a> {
                 format!("comment({:?})", preview)
             }
             NodeKind::Document => "#document".to_string(),
+            NodeKind::TemplateContents => "#template-contents".to_string(),
         }
     }
 
@@ -116,9 +122,33 @@ impl<'a> Document<'a> {
         self.arena[id].get_mut()
     }
 
-    /// Iterate children of a node
+    /// Iterate children of a node. For a `<template>`, those are the
+    /// children of its contents, as the template's own child is the hidden
+    /// contents fragment (see [`template_contents`](Self::template_contents)).
     pub fn children(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
-        id.children(&self.arena)
+        self.children_host(id).children(&self.arena)
+    }
+
+    /// Get the contents fragment of a `<template>` element, if `id` is one.
+    pub fn template_contents(&self, id: NodeId) -> Option<NodeId> {
+        let first = id.children(&self.arena).next()?;
+        matches!(self.get(first).kind, NodeKind::TemplateContents).then_some(first)
+    }
+
+    /// The node whose children are the logical children of `id`: the contents
+    /// fragment for `<template>`, `id` itself otherwise.
+    pub(crate) fn children_host(&self, id: NodeId) -> NodeId {
+        self.template_contents(id).unwrap_or(id)
+    }
+
+    /// Parent of `id`, looking through template contents fragments.
+    pub(crate) fn logical_parent(&self, id: NodeId) -> Option<NodeId> {
+        let parent = self.arena[id].parent()?;
+        if matches!(self.get(parent).kind, NodeKind::TemplateContents) {
+            self.arena[parent].parent()
+        } else {
+            Some(parent)
+        }
     }
 
     /// Pretty-print a subtree for debugging.
@@ -153,7 +183,7 @@ impl<'a> Document<'a> {
                 }
                 out.push_str(">\n");
 
-                for child in self.children(node_id) {
+                for child in node_id.children(&self.arena) {
                     self.dump_node(child, indent + 1, out);
                 }
 
@@ -167,7 +197,13 @@ impl<'a> Document<'a> {
             }
             NodeKind::Document => {
                 out.push_str(&format!("{prefix}#document\n"));
-                for child in self.children(node_id) {
+                for child in node_id.children(&self.arena) {
+                    self.dump_node(child, indent + 1, out);
+                }
+            }
+            NodeKind::TemplateContents => {
+                out.push_str(&format!("{prefix}#template-contents\n"));
+                for child in node_id.children(&self.arena) {
                     self.dump_node(child, indent + 1, out);
                 }
             }
@@ -211,6 +247,7 @@ impl<'a> Document<'a> {
                 attrs: Vec::new(),
             }),
             ns: Namespace::Html,
+            span: None,
         });
         self.root.append(body_id, &mut self.arena);
         body_id
@@ -219,14 +256,40 @@ impl<'a> Document<'a> {
     // ==================== DOM Manipulation API ====================
 
     /// Create an element node (not yet attached to the tree)
+    ///
+    /// A `<template>` gets its contents fragment created alongside it.
     pub fn create_element(&mut self, tag: impl Into<LocalName>) -> NodeId {
-        self.arena.new_node(NodeData {
+        self.create_element_ns(Namespace::Html, tag)
+    }
+
+    /// Create an element in namespace `ns`, like `<svg>` or `<circle>` in
+    /// [`Namespace::Svg`] (not yet attached to the tree)
+    pub fn create_element_ns(&mut self, ns: Namespace, tag: impl Into<LocalName>) -> NodeId {
+        let tag = tag.into();
+        let is_template = ns == Namespace::Html && tag.as_ref() == "template";
+        let node = self.arena.new_node(NodeData {
             kind: NodeKind::Element(ElementData {
-                tag: tag.into(),
+                tag,
                 attrs: Vec::new(),
             }),
+            ns,
+            span: None,
+        });
+        if is_template {
+            self.add_template_contents(node);
+        }
+        node
+    }
+
+    /// Attach a fresh contents fragment to a newly created `<template>`.
+    fn add_template_contents(&mut self, template: NodeId) -> NodeId {
+        let contents = self.arena.new_node(NodeData {
+            kind: NodeKind::TemplateContents,
             ns: Namespace::Html,
-        })
+            span: None,
+        });
+        template.append(contents, &mut self.arena);
+        contents
     }
 
     /// Create a text node (not yet attached to the tree)
@@ -234,6 +297,7 @@ impl<'a> Document<'a> {
         self.arena.new_node(NodeData {
             kind: NodeKind::Text(text.into()),
             ns: Namespace::Html,
+            span: None,
         })
     }
 
@@ -242,12 +306,19 @@ impl<'a> Document<'a> {
         self.arena.new_node(NodeData {
             kind: NodeKind::Comment(text.into()),
             ns: Namespace::Html,
+            span: None,
         })
     }
 
-    /// Append a child node to a parent
+    /// Append a child node to a parent (to its contents, for a `<template>`)
     pub fn append_child(&mut self, parent: NodeId, child: NodeId) {
-        parent.append(child, &mut self.arena);
+        self.children_host(parent).append(child, &mut self.arena);
+    }
+
+    /// Insert a child node before the existing children of a parent (of its
+    /// contents, for a `<template>`)
+    pub fn prepend(&mut self, parent: NodeId, child: NodeId) {
+        self.children_host(parent).prepend(child, &mut self.arena);
     }
 
     /// Insert a node before a sibling
@@ -265,6 +336,86 @@ impl<'a> Document<'a> {
         node.detach(&mut self.arena);
     }
 
+    /// Put `new_node` where `node` is, detaching `node`. Does nothing if
+    /// `node` has no parent.
+    pub fn replace_with(&mut self, node: NodeId, new_node: NodeId) {
+        if node == new_node || self.parent(node).is_none() {
+            return;
+        }
+        node.insert_before(new_node, &mut self.arena);
+        node.detach(&mut self.arena);
+    }
+
+    /// Put `node` inside `wrapper`, and `wrapper` where `node` was.
+    ///
+    /// `node` becomes the last child of `wrapper` (of its contents, for a
+    /// `<template>`).
+    pub fn wrap(&mut self, node: NodeId, wrapper: NodeId) {
+        if self.parent(node).is_some() {
+            node.insert_before(wrapper, &mut self.arena);
+        }
+        self.children_host(wrapper).append(node, &mut self.arena);
+    }
+
+    /// Replace `node` with its children, detaching it. Does nothing if
+    /// `node` has no parent.
+    pub fn unwrap(&mut self, node: NodeId) {
+        if self.parent(node).is_none() {
+            return;
+        }
+        for child in self.take_children(node) {
+            node.insert_before(child, &mut self.arena);
+        }
+        node.detach(&mut self.arena);
+    }
+
+    /// Detach the children of `node` (the contents of a `<template>`), and
+    /// return them in order. They stay in the arena until freed with
+    /// `remove_subtree`.
+    pub fn take_children(&mut self, node: NodeId) -> Vec<NodeId> {
+        let children: Vec<_> = self.children(node).collect();
+        for &child in &children {
+            child.detach(&mut self.arena);
+        }
+        children
+    }
+
+    /// Copy `node` and all of its descendants, template contents included.
+    /// The copy isn't attached to the tree; its nodes keep the originals'
+    /// [`span`](NodeData::span)s.
+    pub fn deep_clone(&mut self, node: NodeId) -> NodeId {
+        let copy = self.arena.new_node(self.get(node).clone());
+        let children: Vec<_> = node.children(&self.arena).collect();
+        for child in children {
+            let child_copy = self.deep_clone(child);
+            copy.append(child_copy, &mut self.arena);
+        }
+        copy
+    }
+
+    /// Replace the children of `element` with `html`, parsed like setting
+    /// `innerHTML` on it: see [`parse_fragment`]. Does nothing if `element`
+    /// isn't an element.
+    pub fn set_inner_html(&mut self, element: NodeId, html: &str) {
+        let node = self.get(element);
+        let NodeKind::Element(elem) = &node.kind else {
+            return;
+        };
+        let context = QualName::new(None, node.ns.url().into(), elem.tag.clone());
+        let tendril = StrTendril::from(html);
+        let fragment = parse_fragment(&tendril, context);
+
+        // The old children go for good; free them rather than leak them
+        for child in self.take_children(element) {
+            child.remove_subtree(&mut self.arena);
+        }
+        let host = self.children_host(element);
+        for child in fragment.children(fragment.root) {
+            let copy = self.clone_subtree_from(&fragment, child);
+            host.append(copy, &mut self.arena);
+        }
+    }
+
     /// Set an attribute on an element
     pub fn set_attr(&mut self, element: NodeId, name: QualName, value: impl Into<Stem<'a>>) {
         if let NodeKind::Element(elem) = &mut self.arena[element].get_mut().kind {
@@ -292,19 +443,66 @@ impl<'a> Document<'a> {
         }
     }
 
-    /// Get parent of a node
+    /// Merge adjacent text nodes and drop empty ones, like DOM
+    /// `Node.normalize()` on the whole document, template contents included.
+    ///
+    /// Applying patches leaves empty text placeholders behind. Once
+    /// normalized, a patched document has the same tree that parsing its
+    /// HTML gives, so diffing it against the patches' target is empty.
+    /// Merged and dropped text nodes are freed, so their ids must not be used
+    /// again.
+    pub fn normalize(&mut self) {
+        let parents: Vec<NodeId> = self.root.descendants(&self.arena).collect();
+        for parent in parents {
+            let mut next = parent.children(&self.arena).next();
+            while let Some(node) = next {
+                next = self.next_sibling(node);
+                let NodeKind::Text(text) = &self.get(node).kind else {
+                    continue;
+                };
+                if text.is_empty() {
+                    node.remove(&mut self.arena);
+                    continue;
+                }
+                while let Some(sibling) = next {
+                    let NodeKind::Text(more) = &self.get(sibling).kind else {
+                        break;
+                    };
+                    let more = more.clone();
+                    let more_span = self.get(sibling).span;
+                    next = self.next_sibling(sibling);
+                    sibling.remove(&mut self.arena);
+
+                    let data = self.get_mut(node);
+                    if let NodeKind::Text(text) = &mut data.kind {
+                        text.push_str(&more);
+                    }
+                    // Only a span that still covers the whole text is worth keeping
+                    data.span = match (data.span, more_span) {
+                        (Some(span), Some(more_span)) if span.end == more_span.start => {
+                            Some(span.cover(more_span))
+                        }
+                        _ if more.is_empty() => data.span,
+                        _ => None,
+                    };
+                }
+            }
+        }
+    }
+
+    /// Get parent of a node (the `<template>`, for a node in its contents)
     pub fn parent(&self, node: NodeId) -> Option<NodeId> {
-        self.arena[node].parent()
+        self.logical_parent(node)
     }
 
-    /// Get first child of a node
+    /// Get first child of a node (of its contents, for a `<template>`)
     pub fn first_child(&self, node: NodeId) -> Option<NodeId> {
-        node.children(&self.arena).next()
+        self.children(node).next()
     }
 
-    /// Get last child of a node
+    /// Get last child of a node (of its contents, for a `<template>`)
     pub fn last_child(&self, node: NodeId) -> Option<NodeId> {
-        node.children(&self.arena).next_back()
+        self.children_host(node).children(&self.arena).next_back()
     }
 
     /// Get next sibling of a node
@@ -322,6 +520,100 @@ impl<'a> Document<'a> {
         node.children(&self.arena).count()
     }
 
+    /// The node a patch path points at, in the document the patches were
+    /// computed against: `[0, ..]` starts from `<body>`, `[HEAD_SLOT, ..]`
+    /// from `<head>`.
+    ///
+    /// Handy to find the [`span`](NodeData::span) behind a patch, or behind a
+    /// [`PatchValidationError`].
+    pub fn node_at_path(&self, path: &NodePath) -> Option<NodeId> {
+        let (&slot, rest) = path.0.split_first()?;
+        let mut current = match slot {
+            0 => self.body()?,
+            HEAD_SLOT => self.head()?,
+            _ => return None,
+        };
+        for &idx in rest {
+            current = self.children(current).nth(idx as usize)?;
+        }
+        Some(current)
+    }
+
+    /// All elements matching a CSS selector list, in document order.
+    ///
+    /// See [`Selector`] for what's supported. Like `querySelectorAll`, this
+    /// doesn't look inside `<template>` contents.
+    pub fn select(&self, selector: &str) -> Result<Vec<NodeId>, SelectorError> {
+        Ok(Selector::parse(selector)?.select(self).collect())
+    }
+
+    /// The first element matching a CSS selector list, in document order.
+    pub fn select_first(&self, selector: &str) -> Result<Option<NodeId>, SelectorError> {
+        Ok(Selector::parse(selector)?.select(self).next())
+    }
+
+    /// Copy everything borrowed from the input, so the document can outlive
+    /// it. The ids of nodes in the document stay the same.
+    pub fn into_owned(self) -> Document<'static> {
+        let placeholder = || NodeData {
+            kind: NodeKind::Document,
+            ns: Namespace::Html,
+            span: None,
+        };
+        let mut arena = Arena::with_capacity(self.arena.count());
+        // Freed slots get a placeholder, so that ids line up
+        let ids: Vec<NodeId> = self
+            .arena
+            .iter()
+            .map(|node| {
+                if node.is_removed() {
+                    return arena.new_node(placeholder());
+                }
+                let mut id = arena.new_node(placeholder());
+                // A slot freed and reused (say by `normalize`) has a newer id;
+                // free and reuse the new slot as often to catch up
+                if let Some(old_id) = self.arena.get_node_id(node) {
+                    while id != old_id {
+                        id.remove(&mut arena);
+                        let reused = arena.new_node(placeholder());
+                        if usize::from(reused) != usize::from(id) {
+                            break;
+                        }
+                        id = reused;
+                    }
+                }
+                *arena[id].get_mut() = node.get().clone().into_owned();
+                id
+            })
+            .collect();
+        let owned_id = |id: NodeId| ids[usize::from(id) - 1];
+        for (node, &id) in self.arena.iter().zip(&ids) {
+            if node.is_removed() {
+                continue;
+            }
+            let Some(old_id) = self.arena.get_node_id(node) else {
+                continue;
+            };
+            for child in old_id.children(&self.arena) {
+                id.append(owned_id(child), &mut arena);
+            }
+        }
+        // Only now, so that freed slots don't get reused above
+        for (node, &id) in self.arena.iter().zip(&ids) {
+            if node.is_removed() {
+                id.remove(&mut arena);
+            }
+        }
+
+        Document {
+            arena,
+            root: owned_id(self.root),
+            errors: self.errors,
+            doctype: self.doctype.map(Stem::into_owned),
+            encoding: self.encoding,
+        }
+    }
+
     /// Clone a subtree from another document into this document's arena.
     /// Returns the NodeId of the cloned root in this document's arena.
     fn clone_subtree_from(&mut self, source: &Document<'_>, source_id: NodeId) -> NodeId {
@@ -348,8 +640,10 @@ impl<'a> Document<'a> {
                     NodeKind::Comment(Stem::Owned(compact_str::CompactString::new(t.as_ref())))
                 }
                 NodeKind::Document => NodeKind::Document,
+                NodeKind::TemplateContents => NodeKind::TemplateContents,
             },
             ns: source_node.ns,
+            span: None,
         });
         for child_id in source_id.children(&source.arena) {
             let cloned_child = self.clone_subtree_from(source, child_id);
@@ -365,49 +659,254 @@ impl Default for Document<'_> {
     }
 }
 
+/// Knobs for [`Document::to_html_with`] and the other `_with` serializers.
+///
+/// [`SerializeOptions::default()`] is the compact form [`Document::to_html`]
+/// writes. Whatever the options, the output parses back to the same document,
+/// up to whitespace where it doesn't render when [`indent`](Self::indent) or
+/// [`collapse_whitespace`](Self::collapse_whitespace) is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SerializeOptions {
+    /// Put the children of elements that only contain block-level elements
+    /// on their own lines, indented by this many spaces per level. Inline
+    /// content and whitespace-sensitive elements (`<pre>`, `<textarea>`, ...)
+    /// are written as they are.
+    pub indent: Option<usize>,
+
+    /// Leave out the start and end tags the parser would imply, like `</li>`
+    /// before another `<li>`, or `<head>` when it has no attributes.
+    pub omit_optional_tags: bool,
+
+    /// Leave out the quotes around attribute values that don't need them,
+    /// and empty values altogether.
+    pub unquoted_attributes: bool,
+
+    /// Collapse runs of whitespace in text to a single space, and drop
+    /// whitespace between block-level elements.
+    pub collapse_whitespace: bool,
+}
+
+impl SerializeOptions {
+    /// Indented output, two spaces per level.
+    pub fn pretty() -> Self {
+        Self {
+            indent: Some(2),
+            ..Self::default()
+        }
+    }
+
+    /// The smallest output: optional tags, quotes and whitespace all go.
+    pub fn minify() -> Self {
+        Self {
+            indent: None,
+            omit_optional_tags: true,
+            unquoted_attributes: true,
+            collapse_whitespace: true,
+        }
+    }
+
+    /// Whether whitespace between block-level elements is left out.
+    fn drops_block_whitespace(&self) -> bool {
+        self.indent.is_some() || self.collapse_whitespace
+    }
+}
+
+/// Where a node is being serialized.
+#[derive(Clone, Copy)]
+struct SerializeContext<'o> {
+    options: &'o SerializeOptions,
+    /// Nesting level, for indentation
+    depth: usize,
+    /// Inside an element whose whitespace is significant
+    preserve_ws: bool,
+    /// Text is written without escaping, in `<script>` and the like
+    raw_text: bool,
+    /// Right after the doctype, the only place `<html>` may be omitted:
+    /// input without a doctype gets wrapped in a body when reparsed
+    after_doctype: bool,
+}
+
+impl<'o> SerializeContext<'o> {
+    fn new(options: &'o SerializeOptions) -> Self {
+        Self {
+            options,
+            depth: 0,
+            preserve_ws: false,
+            raw_text: false,
+            after_doctype: false,
+        }
+    }
+}
+
+/// Writes formatted output to an [`io::Write`], keeping the error that
+/// [`fmt::Error`] can't carry.
+struct IoAdapter<W> {
+    inner: W,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> fmt::Write for IoAdapter<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
 impl<'a> Document<'a> {
     /// Serialize to full HTML string including doctype
     pub fn to_html(&self) -> String {
+        self.to_html_with(&SerializeOptions::default())
+    }
+
+    /// Like [`to_html`](Self::to_html), with [`SerializeOptions`].
+    pub fn to_html_with(&self, options: &SerializeOptions) -> String {
         let mut output = String::new();
+        self.write_html_with(&mut output, options)
+            .expect("writing to a String never fails");
+        output
+    }
+
+    /// Write the full HTML, doctype included, to `w` as it is serialized.
+    pub fn write_html(&self, w: impl fmt::Write) -> fmt::Result {
+        self.write_html_with(w, &SerializeOptions::default())
+    }
+
+    /// Like [`write_html`](Self::write_html), with [`SerializeOptions`].
+    pub fn write_html_with(
+        &self,
+        mut w: impl fmt::Write,
+        options: &SerializeOptions,
+    ) -> fmt::Result {
+        let mut cx = SerializeContext::new(options);
         if let Some(ref doctype) = self.doctype {
-            output.push_str("<!DOCTYPE ");
-            output.push_str(doctype.as_ref());
-            output.push('>');
+            w.write_str("<!DOCTYPE ")?;
+            w.write_str(doctype.as_ref())?;
+            w.write_char('>')?;
+            if options.indent.is_some() {
+                w.write_char('\n')?;
+            }
+            cx.after_doctype = true;
+        }
+        self.serialize_node(&mut w, self.root, cx)?;
+        Ok(())
+    }
+
+    /// Like [`write_html`](Self::write_html), to an [`io::Write`], such as
+    /// a response body. Writes are as small as a single tag, so `w` should
+    /// be buffered.
+    pub fn write_html_io(&self, w: impl io::Write) -> io::Result<()> {
+        self.write_html_io_with(w, &SerializeOptions::default())
+    }
+
+    /// Like [`write_html_io`](Self::write_html_io), with [`SerializeOptions`].
+    pub fn write_html_io_with(
+        &self,
+        w: impl io::Write,
+        options: &SerializeOptions,
+    ) -> io::Result<()> {
+        let mut adapter = IoAdapter {
+            inner: w,
+            error: None,
+        };
+        match self.write_html_with(&mut adapter, options) {
+            Ok(()) => Ok(()),
+            Err(fmt::Error) => Err(adapter
+                .error
+                .unwrap_or_else(|| io::Error::other("formatter error"))),
         }
-        self.serialize_node(&mut output, self.root);
-        output
     }
 
     /// Serialize to HTML string without the doctype declaration.
     /// Useful for comparing DOM structure when doctype differences should be ignored.
     pub fn to_html_without_doctype(&self) -> String {
         let mut output = String::new();
-        self.serialize_node(&mut output, self.root);
+        let options = SerializeOptions::default();
+        self.serialize_node(&mut output, self.root, SerializeContext::new(&options))
+            .expect("writing to a String never fails");
         output
     }
 
     /// Serialize just the body's inner content.
     /// Returns empty string if there's no body.
     pub fn to_body_html(&self) -> String {
+        self.to_body_html_with(&SerializeOptions::default())
+    }
+
+    /// Like [`to_body_html`](Self::to_body_html), with [`SerializeOptions`].
+    pub fn to_body_html_with(&self, options: &SerializeOptions) -> String {
         let Some(body_id) = self.body() else {
             return String::new();
         };
-        let mut output = String::new();
-        for child_id in body_id.children(&self.arena) {
-            self.serialize_node(&mut output, child_id);
-        }
-        output
+        self.serialize_inner_html_with(body_id, options)
     }
 
     /// Serialize the inner HTML of a node (its children, not the node itself).
     pub fn serialize_inner_html(&self, node_id: NodeId) -> String {
+        self.serialize_inner_html_with(node_id, &SerializeOptions::default())
+    }
+
+    /// Like [`serialize_inner_html`](Self::serialize_inner_html), with [`SerializeOptions`].
+    ///
+    /// With [`indent`](SerializeOptions::indent), each child starts on a line
+    /// of its own when they're all block-level.
+    pub fn serialize_inner_html_with(&self, node_id: NodeId, options: &SerializeOptions) -> String {
         let mut output = String::new();
-        for child_id in node_id.children(&self.arena) {
-            self.serialize_node(&mut output, child_id);
-        }
+        self.write_inner_html(&mut output, node_id, options)
+            .expect("writing to a String never fails");
         output
     }
 
+    /// Write the inner HTML of a node to `w`, like
+    /// [`serialize_inner_html_with`](Self::serialize_inner_html_with).
+    pub fn write_inner_html(
+        &self,
+        mut w: impl fmt::Write,
+        node_id: NodeId,
+        options: &SerializeOptions,
+    ) -> fmt::Result {
+        let mut cx = SerializeContext::new(options);
+        cx.preserve_ws = self.preserves_whitespace_in(node_id);
+        cx.raw_text = self.is_raw_text(node_id);
+        self.serialize_children(&mut w, node_id, cx)?;
+        Ok(())
+    }
+
+    /// Write the outer HTML of the siblings from `first` through `last`
+    /// to `w`, as they'd appear in their parent's inner HTML. `first` alone
+    /// can be written by passing it as `last` too.
+    ///
+    /// If `last` isn't `first` or a sibling after it, writing goes on to the
+    /// last sibling.
+    pub fn write_range(
+        &self,
+        mut w: impl fmt::Write,
+        first: NodeId,
+        last: NodeId,
+        options: &SerializeOptions,
+    ) -> fmt::Result {
+        let mut cx = SerializeContext::new(options);
+        if let Some(parent) = self.logical_parent(first) {
+            cx.preserve_ws = self.preserves_whitespace_in(parent);
+            cx.raw_text = self.is_raw_text(parent);
+        }
+        let range = || {
+            let mut done = false;
+            first
+                .following_siblings(&self.arena)
+                .take_while(move |&id| {
+                    let take = !done;
+                    done = id == last;
+                    take
+                })
+        };
+        let parent = self.logical_parent(first);
+        let block_layout = self.has_block_layout(parent, range(), cx);
+        self.serialize_sequence(&mut w, range(), cx, block_layout)?;
+        Ok(())
+    }
+
     /// Navigate to a node using the new unified path format.
     /// Path `[slot, a, b, c]` means: get slot root, then navigate a → b → c.
     fn navigate_slot_path(
@@ -424,7 +923,7 @@ impl<'a> Document<'a> {
 
         let mut current = slot_root;
         for &idx in &path[1..] {
-            let mut children = current.children(&self.arena);
+            let mut children = self.children_host(current).children(&self.arena);
             current = children
                 .nth(idx as usize)
                 .ok_or(DiffError::PathOutOfBounds {
@@ -450,7 +949,7 @@ impl<'a> Document<'a> {
         let parent_path = &path[..path.len() - 1];
         let parent_id = self.navigate_slot_path(parent_path, slots)?;
 
-        Ok((parent_id, position))
+        Ok((self.children_host(parent_id), position))
     }
 
     /// Apply patches to this document (modifying it in place).
@@ -460,10 +959,25 @@ impl<'a> Document<'a> {
             return Ok(());
         }
 
-        // Slots hold NodeIds - slot 0 is always the body (main tree)
-        let mut slots: HashMap<u32, NodeId> = HashMap::new();
-        let body_id = self.body().unwrap_or_else(|| self.ensure_body());
-        slots.insert(0, body_id);
+        let mut slots = self.init_patch_slots();
+
+        for patch in patches {
+            self.apply_patch(patch, &mut slots)?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply patches whose slot 0 is `root` rather than the body, such as
+    /// the ones [`diff_subtrees`](crate::diff_subtrees) makes.
+    ///
+    /// [`HEAD_SLOT`] still refers to the `<head>`.
+    pub fn apply_patches_at(
+        &mut self,
+        root: NodeId,
+        patches: Vec<Patch<'a>>,
+    ) -> Result<(), DiffError> {
+        let mut slots = self.init_patch_slots_at(root);
 
         for patch in patches {
             self.apply_patch(patch, &mut slots)?;
@@ -472,11 +986,139 @@ impl<'a> Document<'a> {
         Ok(())
     }
 
+    /// Like [`apply_patches`](Self::apply_patches), but all or nothing: if a
+    /// patch fails, the ones already applied are undone and the document is
+    /// left as it was, node ids included.
+    ///
+    /// Undoing replays a journal of inverse patches recorded along the way, so
+    /// it costs as much as the patches themselves, whatever the document size.
+    /// Should that fail as well, the error is [`DiffError::RollbackFailed`],
+    /// wrapping the patch's own error, and the document is only partially
+    /// restored.
+    pub fn apply_patches_atomic(&mut self, patches: Vec<Patch<'a>>) -> Result<(), DiffError> {
+        if patches.is_empty() {
+            return Ok(());
+        }
+
+        let had_body = self.body().is_some();
+        let mut slots = self.init_patch_slots();
+        let mut journal = InverseRecorder::new(&slots);
+
+        for (index, patch) in patches.into_iter().enumerate() {
+            journal.begin();
+            if let Err(err) = self.apply_patch_recording(patch, &mut slots, Some(&mut journal)) {
+                debug!("Rolling back after failed patch {}: {}", index, err);
+                let (undo, slots) = journal.into_rollback();
+                return Err(self.roll_back(undo, slots, had_body, index, err));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replay the `undo` patches of a failed atomic apply. Every patch is
+    /// tried even if one fails, to restore as much as possible.
+    ///
+    /// Returns the error to report for patch `index` failing with `err`:
+    /// `err` itself, or [`DiffError::RollbackFailed`] if undoing failed too.
+    fn roll_back(
+        &mut self,
+        undo: Vec<Patch<'a>>,
+        mut slots: HashMap<u32, NodeId>,
+        had_body: bool,
+        index: usize,
+        err: DiffError,
+    ) -> DiffError {
+        let body = slots[&0];
+        let mut failed = false;
+        for patch in undo {
+            if let Err(_err) = self.apply_patch(patch, &mut slots) {
+                debug!("Rollback patch failed: {}", _err);
+                failed = true;
+            }
+        }
+        if !had_body {
+            body.detach(&mut self.arena);
+        }
+        if failed {
+            return DiffError::RollbackFailed {
+                index,
+                source: Box::new(err),
+            };
+        }
+        err
+    }
+
+    /// Check that `patches` would apply cleanly, without touching the document.
+    ///
+    /// Resolves every slot and path the way [`apply_patches`](Self::apply_patches)
+    /// would, and reports the first patch that would fail.
+    pub fn validate_patches(&self, patches: &[Patch<'_>]) -> Result<(), PatchValidationError> {
+        if patches.is_empty() {
+            return Ok(());
+        }
+        DryRun::new(self, None).run(patches)
+    }
+
+    /// Like [`validate_patches`](Self::validate_patches), for patches made
+    /// to be applied with [`apply_patches_at`](Self::apply_patches_at).
+    pub fn validate_patches_at(
+        &self,
+        root: NodeId,
+        patches: &[Patch<'_>],
+    ) -> Result<(), PatchValidationError> {
+        if patches.is_empty() {
+            return Ok(());
+        }
+        DryRun::new(self, Some(root)).run(patches)
+    }
+
+    /// Like [`apply_patches`](Self::apply_patches), and also return the
+    /// inverse patch list: applying it to the patched document restores the
+    /// previous state, placeholders and all.
+    ///
+    /// Re-applying the original patches to the restored document redoes them.
+    pub fn apply_patches_with_inverse(
+        &mut self,
+        patches: Vec<Patch<'a>>,
+    ) -> Result<Vec<Patch<'a>>, DiffError> {
+        if patches.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut slots = self.init_patch_slots();
+        let mut inverse = InverseRecorder::new(&slots);
+
+        for patch in patches {
+            inverse.begin();
+            self.apply_patch_recording(patch, &mut slots, Some(&mut inverse))?;
+        }
+
+        Ok(inverse.finish(self))
+    }
+
     /// Initialize slot map for patch application.
+    ///
+    /// Slot 0 is always the body (main tree). [`HEAD_SLOT`] is the `<head>`, if any.
     pub fn init_patch_slots(&mut self) -> HashMap<u32, NodeId> {
         let mut slots: HashMap<u32, NodeId> = HashMap::new();
         let body_id = self.body().unwrap_or_else(|| self.ensure_body());
         slots.insert(0, body_id);
+        if let Some(head_id) = self.head() {
+            slots.insert(HEAD_SLOT, head_id);
+        }
+        slots
+    }
+
+    /// Initialize a slot map for patches rooted at `root`.
+    ///
+    /// Slot 0 is `root`. [`HEAD_SLOT`] is the `<head>`, if any.
+    pub fn init_patch_slots_at(&self, root: NodeId) -> HashMap<u32, NodeId> {
+        let mut slots: HashMap<u32, NodeId> = HashMap::new();
+        slots.insert(0, root);
+        if let Some(head_id) = self.head() {
+            slots.insert(HEAD_SLOT, head_id);
+        }
         slots
     }
 
@@ -489,17 +1131,28 @@ impl<'a> Document<'a> {
         self.apply_patch(patch, slots)
     }
 
-    #[allow(clippy::too_many_lines)]
     fn apply_patch(
         &mut self,
         patch: Patch<'a>,
         slots: &mut HashMap<u32, NodeId>,
+    ) -> Result<(), DiffError> {
+        self.apply_patch_recording(patch, slots, None)
+    }
+
+    /// Apply a patch, recording its inverse into `inverse` if given.
+    #[allow(clippy::too_many_lines)]
+    fn apply_patch_recording(
+        &mut self,
+        patch: Patch<'a>,
+        slots: &mut HashMap<u32, NodeId>,
+        mut inverse: Option<&mut InverseRecorder<'a>>,
     ) -> Result<(), DiffError> {
         debug!("Applying patch: {:?}", patch);
         match patch {
             Patch::InsertElement {
                 at,
                 tag,
+                ns,
                 attrs,
                 children,
                 detach_to_slot,
@@ -514,16 +1167,21 @@ impl<'a> Document<'a> {
                 };
                 let new_node = self.arena.new_node(NodeData {
                     kind: NodeKind::Element(elem_data),
-                    ns: Namespace::Html,
+                    ns,
+                    span: None,
                 });
 
                 // Add children to the new element
+                let host = self.new_children_host(new_node);
                 for child in children {
                     let child_node = self.create_insert_content(child)?;
-                    new_node.append(child_node, &mut self.arena);
+                    host.append(child_node, &mut self.arena);
                 }
 
-                self.insert_at(&at, new_node, detach_to_slot, slots)?;
+                let inserted = self.insert_at(&at, new_node, detach_to_slot, slots)?;
+                if let Some(inv) = inverse {
+                    inv.undo_insert(self, &inserted, None);
+                }
             }
             Patch::InsertText {
                 at,
@@ -533,8 +1191,12 @@ impl<'a> Document<'a> {
                 let new_node = self.arena.new_node(NodeData {
                     kind: NodeKind::Text(text),
                     ns: Namespace::Html,
+                    span: None,
                 });
-                self.insert_at(&at, new_node, detach_to_slot, slots)?;
+                let inserted = self.insert_at(&at, new_node, detach_to_slot, slots)?;
+                if let Some(inv) = inverse {
+                    inv.undo_insert(self, &inserted, None);
+                }
             }
             Patch::InsertComment {
                 at,
@@ -544,8 +1206,12 @@ impl<'a> Document<'a> {
                 let new_node = self.arena.new_node(NodeData {
                     kind: NodeKind::Comment(text),
                     ns: Namespace::Html,
+                    span: None,
                 });
-                self.insert_at(&at, new_node, detach_to_slot, slots)?;
+                let inserted = self.insert_at(&at, new_node, detach_to_slot, slots)?;
+                if let Some(inv) = inverse {
+                    inv.undo_insert(self, &inserted, None);
+                }
             }
             Patch::Remove { node } => {
                 let path = &node.0.0;
@@ -554,12 +1220,22 @@ impl<'a> Document<'a> {
                 let empty_text = self.arena.new_node(NodeData {
                     kind: NodeKind::Text(Stem::new()),
                     ns: Namespace::Html,
+                    span: None,
                 });
                 node_id.insert_before(empty_text, &mut self.arena);
                 node_id.detach(&mut self.arena);
+                if let Some(inv) = inverse
+                    && self.arena[empty_text].parent().is_some()
+                {
+                    let at = inv.path(self, empty_text);
+                    inv.undo_detach(node_id, at, true);
+                }
             }
             Patch::SetText { path, text } => {
                 let node_id = self.navigate_slot_path(&path.0, slots)?;
+                if let Some(inv) = inverse {
+                    inv.save_value(self, node_id);
+                }
                 let node_data = self.arena[node_id].get_mut();
                 match &mut node_data.kind {
                     NodeKind::Text(t) => *t = text,
@@ -567,8 +1243,28 @@ impl<'a> Document<'a> {
                     _ => return Err(DiffError::NotATextNode),
                 }
             }
+            Patch::SpliceText {
+                path,
+                offset,
+                delete_len,
+                insert,
+            } => {
+                let node_id = self.navigate_slot_path(&path.0, slots)?;
+                if let Some(inv) = inverse {
+                    inv.save_value(self, node_id);
+                }
+                let node_data = self.arena[node_id].get_mut();
+                let t = match &mut node_data.kind {
+                    NodeKind::Text(t) | NodeKind::Comment(t) => t,
+                    _ => return Err(DiffError::NotATextNode),
+                };
+                *t = Stem::Owned(splice_utf16(t, offset, delete_len, &insert)?);
+            }
             Patch::SetAttribute { path, name, value } => {
                 let node_id = self.navigate_slot_path(&path.0, slots)?;
+                if let Some(inv) = inverse {
+                    inv.save_value(self, node_id);
+                }
                 let node_data = self.arena[node_id].get_mut();
                 if let NodeKind::Element(elem) = &mut node_data.kind {
                     // Find existing attribute and update, or append new one
@@ -585,6 +1281,9 @@ impl<'a> Document<'a> {
             }
             Patch::RemoveAttribute { path, name } => {
                 let node_id = self.navigate_slot_path(&path.0, slots)?;
+                if let Some(inv) = inverse {
+                    inv.save_value(self, node_id);
+                }
                 let node_data = self.arena[node_id].get_mut();
                 if let NodeKind::Element(elem) = &mut node_data.kind {
                     elem.attrs.retain(|(k, _)| k != &name);
@@ -604,21 +1303,61 @@ impl<'a> Document<'a> {
                 // Exception: path of length 1 (just [slot]) means the slot root itself
                 let needs_replacement = from_path.len() > 1;
 
-                if needs_replacement {
+                let origin = if needs_replacement {
                     let empty_text = self.arena.new_node(NodeData {
                         kind: NodeKind::Text(Stem::new()),
                         ns: Namespace::Html,
+                        span: None,
                     });
                     node_to_move.insert_before(empty_text, &mut self.arena);
                     node_to_move.detach(&mut self.arena);
+                    inverse
+                        .as_mut()
+                        .map(|inv| MoveOrigin::Placeholder(inv.path(self, empty_text)))
                 } else {
+                    let origin = inverse.as_mut().map(|inv| {
+                        if self.arena[node_to_move].parent().is_some() {
+                            MoveOrigin::Attached(inv.path(self, node_to_move))
+                        } else {
+                            MoveOrigin::Detached
+                        }
+                    });
                     node_to_move.detach(&mut self.arena);
-                }
+                    origin
+                };
 
-                self.insert_at(&to, node_to_move, detach_to_slot, slots)?;
+                let inserted = match self.insert_at(&to, node_to_move, detach_to_slot, slots) {
+                    Ok(inserted) => inserted,
+                    Err(err) => {
+                        // The node is already out of the tree
+                        if let (Some(inv), Some(origin)) = (inverse, origin) {
+                            inv.undo_failed_move(origin, node_to_move);
+                        }
+                        return Err(err);
+                    }
+                };
+                if let (Some(inv), Some(origin)) = (inverse, origin) {
+                    inv.undo_move(self, origin, &inserted);
+                }
+            }
+            Patch::Detach { node, to_slot } => {
+                let node_id = self.navigate_slot_path(&node.0.0, slots)?;
+                if let Some(inv) = inverse
+                    && self.arena[node_id].parent().is_some()
+                {
+                    let at = inv.path(self, node_id);
+                    inv.undo_detach(node_id, at, false);
+                }
+                node_id.detach(&mut self.arena);
+                if let Some(slot) = to_slot {
+                    slots.insert(slot, node_id);
+                }
             }
             Patch::UpdateProps { path, changes } => {
                 let node_id = self.navigate_slot_path(&path.0, slots)?;
+                if let Some(inv) = inverse {
+                    inv.save_value(self, node_id);
+                }
                 let node_data = self.arena[node_id].get_mut();
 
                 // Handle text node updates
@@ -673,8 +1412,12 @@ impl<'a> Document<'a> {
             }
             Patch::OpaqueChanged { path, content } => {
                 let node_id = self.navigate_slot_path(&path.0, slots)?;
-                // Remove all existing children
-                let children: Vec<_> = node_id.children(&self.arena).collect();
+                if let Some(inv) = inverse {
+                    inv.save_opaque(self, node_id);
+                }
+                // Remove all existing children (a template's live in its contents)
+                let host = self.children_host(node_id);
+                let children: Vec<_> = host.children(&self.arena).collect();
                 for child in children {
                     child.detach(&mut self.arena);
                 }
@@ -689,7 +1432,7 @@ impl<'a> Document<'a> {
                     if let Some(div_id) = body_id.children(&wrapper_doc.arena).next() {
                         for child_id in div_id.children(&wrapper_doc.arena) {
                             let cloned = self.clone_subtree_from(&wrapper_doc, child_id);
-                            node_id.append(cloned, &mut self.arena);
+                            host.append(cloned, &mut self.arena);
                         }
                     }
                 }
@@ -705,7 +1448,7 @@ impl<'a> Document<'a> {
         node_to_insert: NodeId,
         detach_to_slot: Option<u32>,
         slots: &mut HashMap<u32, NodeId>,
-    ) -> Result<(), DiffError> {
+    ) -> Result<Inserted, DiffError> {
         let path = &at.0.0;
         let (parent_id, position) = self.get_slot_parent(path, slots)?;
 
@@ -716,6 +1459,7 @@ impl<'a> Document<'a> {
             position
         );
 
+        let mut displaced = None;
         if let Some(slot) = detach_to_slot {
             let children: Vec<_> = parent_id.children(&self.arena).collect();
             debug!(
@@ -728,15 +1472,20 @@ impl<'a> Document<'a> {
                 slot
             );
             if position < children.len() {
-                let displaced = children[position];
-                displaced.detach(&mut self.arena);
-                slots.insert(slot, displaced);
+                let occupant = children[position];
+                occupant.detach(&mut self.arena);
+                slots.insert(slot, occupant);
+                displaced = Some(occupant);
             }
         }
 
-        self.insert_at_position(parent_id, position, node_to_insert)?;
+        let padding = self.insert_at_position(parent_id, position, node_to_insert)?;
 
-        Ok(())
+        Ok(Inserted {
+            node: node_to_insert,
+            displaced,
+            padding,
+        })
     }
 
     fn insert_at_position(
@@ -744,8 +1493,9 @@ impl<'a> Document<'a> {
         parent_id: NodeId,
         position: usize,
         node_to_insert: NodeId,
-    ) -> Result<(), DiffError> {
+    ) -> Result<usize, DiffError> {
         let children: Vec<_> = parent_id.children(&self.arena).collect();
+        let padding = position.saturating_sub(children.len());
 
         debug!(
             "insert_at_position: {} under {} at pos {} (has {} children)",
@@ -762,6 +1512,7 @@ impl<'a> Document<'a> {
             let empty_text = self.arena.new_node(NodeData {
                 kind: NodeKind::Text(Stem::new()),
                 ns: Namespace::Html,
+                span: None,
             });
             parent_id.append(empty_text, &mut self.arena);
             debug!("Filled gap at position {} with empty text node", i);
@@ -776,13 +1527,27 @@ impl<'a> Document<'a> {
             next_sibling.insert_before(node_to_insert, &mut self.arena);
         }
 
-        Ok(())
+        Ok(padding)
+    }
+
+    /// For a freshly created `<template>`, add its contents fragment and return
+    /// it; for any other element, return the element itself.
+    fn new_children_host(&mut self, node: NodeId) -> NodeId {
+        let data = self.get(node);
+        let is_template = data.ns == Namespace::Html
+            && matches!(&data.kind, NodeKind::Element(e) if e.tag.as_ref() == "template");
+        if is_template {
+            self.add_template_contents(node)
+        } else {
+            node
+        }
     }
 
     fn create_insert_content(&mut self, content: InsertContent<'a>) -> Result<NodeId, DiffError> {
         match content {
             InsertContent::Element {
                 tag,
+                ns,
                 attrs,
                 children,
             } => {
@@ -792,12 +1557,14 @@ impl<'a> Document<'a> {
                 };
                 let node = self.arena.new_node(NodeData {
                     kind: NodeKind::Element(elem_data),
-                    ns: Namespace::Html,
+                    ns,
+                    span: None,
                 });
 
+                let host = self.new_children_host(node);
                 for child in children {
                     let child_node = self.create_insert_content(child)?;
-                    node.append(child_node, &mut self.arena);
+                    host.append(child_node, &mut self.arena);
                 }
 
                 Ok(node)
@@ -806,6 +1573,7 @@ impl<'a> Document<'a> {
                 let node = self.arena.new_node(NodeData {
                     kind: NodeKind::Text(text),
                     ns: Namespace::Html,
+                    span: None,
                 });
                 Ok(node)
             }
@@ -813,126 +1581,506 @@ impl<'a> Document<'a> {
                 let node = self.arena.new_node(NodeData {
                     kind: NodeKind::Comment(text),
                     ns: Namespace::Html,
+                    span: None,
                 });
                 Ok(node)
             }
         }
     }
 
-    fn serialize_node(&self, out: &mut String, node_id: NodeId) {
+    /// Serialize a node; returns whether it was closed (a start tag
+    /// without its end tag leaves it open for what comes next).
+    fn serialize_node(
+        &self,
+        out: &mut dyn fmt::Write,
+        node_id: NodeId,
+        cx: SerializeContext<'_>,
+    ) -> Result<bool, fmt::Error> {
         let node = self.get(node_id);
         match &node.kind {
             NodeKind::Document => {
                 // Document nodes are invisible
             }
+            NodeKind::TemplateContents => {
+                return Ok(!self.serialize_children(out, node_id, cx)?);
+            }
             NodeKind::Element(elem) => {
-                self.serialize_element(out, node_id, elem);
+                return self.serialize_element(out, node_id, elem, cx);
+            }
+            NodeKind::Text(text) if cx.raw_text => {
+                // Raw text - no escaping
+                out.write_str(text.as_ref())?;
             }
             NodeKind::Text(text) => {
-                // Escape text content
-                for c in text.as_ref().chars() {
-                    match c {
-                        '&' => out.push_str("&amp;"),
-                        '<' => out.push_str("&lt;"),
-                        _ => out.push(c),
-                    }
-                }
+                let collapsed;
+                let text = if cx.options.collapse_whitespace && !cx.preserve_ws {
+                    collapsed = collapse_whitespace(text.as_ref());
+                    collapsed.as_str()
+                } else {
+                    text.as_ref()
+                };
+                write_escaped(out, text, false)?;
             }
             NodeKind::Comment(text) => {
-                out.push_str("<!--");
-                out.push_str(text.as_ref());
-                out.push_str("-->");
+                out.write_str("<!--")?;
+                out.write_str(text.as_ref())?;
+                out.write_str("-->")?;
             }
         }
+        Ok(true)
     }
 
-    fn serialize_element(&self, out: &mut String, node_id: NodeId, elem: &ElementData) {
-        let tag = elem.tag.as_ref();
+    /// Serialize the logical children of `node_id`. Returns whether the
+    /// end tag of `node_id` goes on a line of its own.
+    fn serialize_children(
+        &self,
+        out: &mut dyn fmt::Write,
+        node_id: NodeId,
+        cx: SerializeContext<'_>,
+    ) -> Result<bool, fmt::Error> {
+        let children = || self.children(node_id);
+        let block_layout = self.has_block_layout(Some(node_id), children(), cx);
+        self.serialize_sequence(out, children(), cx, block_layout)
+    }
 
-        // Opening tag
-        out.push('<');
-        out.push_str(tag);
-
-        // Attributes
-        for (name, value) in &elem.attrs {
-            out.push(' ');
-            // Serialize QualName with prefix if present
-            if let Some(ref prefix) = name.prefix {
-                out.push_str(prefix.as_ref());
-                out.push(':');
-            }
-            out.push_str(name.local.as_ref());
-            out.push_str("=\"");
-            // Escape attribute value
-            for c in value.as_ref().chars() {
-                match c {
-                    '&' => out.push_str("&amp;"),
-                    '"' => out.push_str("&quot;"),
-                    '<' => out.push_str("&lt;"),
-                    '>' => out.push_str("&gt;"),
-                    _ => out.push(c),
-                }
+    /// Serialize sibling nodes, each on a line of its own with
+    /// `block_layout`. Returns whether what comes next goes on a new line.
+    fn serialize_sequence(
+        &self,
+        out: &mut dyn fmt::Write,
+        nodes: impl Iterator<Item = NodeId>,
+        cx: SerializeContext<'_>,
+        block_layout: bool,
+    ) -> Result<bool, fmt::Error> {
+        // Top-level nodes start on the first line. A line break after an
+        // open element (or after the body, which takes all content past
+        // its end tag) would end up inside it
+        let mut line_break = block_layout && cx.depth > 0;
+        for node_id in nodes {
+            if self.is_dropped(node_id, cx) {
+                continue;
             }
-            out.push('"');
+            if line_break {
+                push_indent(out, cx.options, cx.depth)?;
+            }
+            let closed = self.serialize_node(out, node_id, cx)?;
+            line_break = block_layout && closed && !self.is_html_element(Some(node_id), &["body"]);
         }
+        Ok(line_break)
+    }
 
-        // Check if void element
-        if is_void_element(tag) {
-            out.push('>');
-            return;
+    fn serialize_element(
+        &self,
+        out: &mut dyn fmt::Write,
+        node_id: NodeId,
+        elem: &ElementData,
+        cx: SerializeContext<'_>,
+    ) -> Result<bool, fmt::Error> {
+        let tag = elem.tag.as_ref();
+        let omit_tags = cx.options.omit_optional_tags && self.get(node_id).ns == Namespace::Html;
+
+        if !(omit_tags && elem.attrs.is_empty() && self.omits_start_tag(node_id, tag, cx)) {
+            // Opening tag
+            out.write_char('<')?;
+            out.write_str(tag)?;
+
+            // Attributes
+            for (name, value) in &elem.attrs {
+                out.write_char(' ')?;
+                write_attr_name(out, name)?;
+                let value = value.as_ref();
+                if cx.options.unquoted_attributes && value.is_empty() {
+                    continue;
+                }
+                let quoted = !cx.options.unquoted_attributes || needs_quotes(value);
+                out.write_char('=')?;
+                if quoted {
+                    out.write_char('"')?;
+                }
+                write_escaped(out, value, true)?;
+                if quoted {
+                    out.write_char('"')?;
+                }
+            }
+
+            out.write_char('>')?;
         }
 
-        out.push('>');
+        // Void elements have no end tag. Foreign elements always have one,
+        // even if they were written self-closing
+        let html = self.get(node_id).ns == Namespace::Html;
+        if html && is_void_element(tag) {
+            return Ok(true);
+        }
 
         // Children - raw text elements (script, style) should not have their content escaped
-        let is_raw_text = is_raw_text_element(tag);
-        for child_id in node_id.children(&self.arena) {
-            if is_raw_text {
-                self.serialize_node_raw(out, child_id);
-            } else {
-                self.serialize_node(out, child_id);
-            }
+        let inner = SerializeContext {
+            depth: cx.depth + 1,
+            preserve_ws: cx.preserve_ws || preserves_whitespace(elem),
+            raw_text: html && is_raw_text_element(tag),
+            after_doctype: false,
+            ..cx
+        };
+        let end_on_own_line = self.serialize_children(out, node_id, inner)?;
+
+        if omit_tags && self.omits_end_tag(node_id, tag, cx) {
+            return Ok(false);
+        }
+        if end_on_own_line {
+            push_indent(out, cx.options, cx.depth)?;
         }
 
         // Closing tag
-        out.push_str("</");
-        out.push_str(tag);
-        out.push('>');
+        out.write_str("</")?;
+        out.write_str(tag)?;
+        out.write_char('>')?;
+        Ok(true)
+    }
+
+    /// Whether text children of `node_id` are written unescaped.
+    fn is_raw_text(&self, node_id: NodeId) -> bool {
+        let node = self.get(node_id);
+        matches!(&node.kind, NodeKind::Element(elem)
+            if node.ns == Namespace::Html && is_raw_text_element(&elem.tag))
+    }
+
+    /// Whether text inside `node_id` keeps its whitespace, because of
+    /// `node_id` itself or one of its ancestors.
+    fn preserves_whitespace_in(&self, node_id: NodeId) -> bool {
+        let mut current = Some(node_id);
+        while let Some(id) = current {
+            if let NodeKind::Element(elem) = &self.get(id).kind
+                && preserves_whitespace(elem)
+            {
+                return true;
+            }
+            current = self.logical_parent(id);
+        }
+        false
     }
 
-    /// Serialize a node without escaping text content (for raw text elements like script/style)
-    fn serialize_node_raw(&self, out: &mut String, node_id: NodeId) {
+    /// Whether `node_id` is a block-level element (or a comment, which
+    /// doesn't render either way) for whitespace around it.
+    fn is_block_level(&self, node_id: NodeId) -> bool {
         let node = self.get(node_id);
         match &node.kind {
-            NodeKind::Document => {}
-            NodeKind::Element(elem) => {
-                self.serialize_element(out, node_id, elem);
+            NodeKind::Comment(_) => true,
+            NodeKind::Element(elem) => node.ns == Namespace::Html && is_block_element(&elem.tag),
+            _ => false,
+        }
+    }
+
+    /// Whether the whitespace-only text `node_id` is left out: it's between
+    /// block-level elements, where it doesn't render. Empty text never
+    /// makes it to the output.
+    fn is_dropped(&self, node_id: NodeId, cx: SerializeContext<'_>) -> bool {
+        let NodeKind::Text(text) = &self.get(node_id).kind else {
+            return false;
+        };
+        if text.is_empty() {
+            return true;
+        }
+        if !cx.options.drops_block_whitespace()
+            || cx.preserve_ws
+            || cx.raw_text
+            || !text.bytes().all(|b| b.is_ascii_whitespace())
+        {
+            return false;
+        }
+        let in_block = self
+            .logical_parent(node_id)
+            .is_none_or(|parent| self.is_block_level(parent));
+        let around =
+            |sibling: Option<NodeId>| sibling.map_or(in_block, |id| self.is_block_level(id));
+        let node = &self.arena[node_id];
+        around(node.previous_sibling()) && around(node.next_sibling())
+    }
+
+    /// Whether `nodes`, children of `parent`, go on lines of their own:
+    /// they must all be block-level, and so must `parent`.
+    fn has_block_layout(
+        &self,
+        parent: Option<NodeId>,
+        nodes: impl Iterator<Item = NodeId>,
+        cx: SerializeContext<'_>,
+    ) -> bool {
+        if cx.options.indent.is_none()
+            || cx.preserve_ws
+            || cx.raw_text
+            || !parent.is_none_or(|id| self.is_block_level(id))
+        {
+            return false;
+        }
+        let mut nodes = nodes.filter(|&id| !self.is_dropped(id, cx)).peekable();
+        nodes.peek().is_some() && nodes.all(|id| self.is_block_level(id))
+    }
+
+    /// The sibling written right before (or after, with `next`) `node_id`.
+    fn serialized_sibling(
+        &self,
+        node_id: NodeId,
+        cx: SerializeContext<'_>,
+        next: bool,
+    ) -> Option<NodeId> {
+        let step = |id: NodeId| {
+            let node = &self.arena[id];
+            if next {
+                node.next_sibling()
+            } else {
+                node.previous_sibling()
             }
-            NodeKind::Text(text) => {
-                // Raw text - no escaping
-                out.push_str(text.as_ref());
+        };
+        let mut sibling = step(node_id)?;
+        while self.is_dropped(sibling, cx) {
+            sibling = step(sibling)?;
+        }
+        Some(sibling)
+    }
+
+    /// The first child of `node_id` that gets written.
+    fn first_serialized_child(&self, node_id: NodeId, cx: SerializeContext<'_>) -> Option<NodeId> {
+        self.children(node_id).find(|&id| !self.is_dropped(id, cx))
+    }
+
+    /// Whether `node_id` is an HTML element named one of `tags`.
+    fn is_html_element(&self, node_id: Option<NodeId>, tags: &[&str]) -> bool {
+        node_id.is_some_and(|id| {
+            let node = self.get(id);
+            matches!(&node.kind, NodeKind::Element(elem)
+                if node.ns == Namespace::Html && tags.contains(&elem.tag.as_ref()))
+        })
+    }
+
+    /// Whether `node_id` is text starting with whitespace, or a comment:
+    /// what may not follow some omitted tags, as the parser would put it
+    /// somewhere else.
+    fn is_space_or_comment(&self, node_id: Option<NodeId>) -> bool {
+        node_id.is_some_and(|id| match &self.get(id).kind {
+            NodeKind::Text(text) => text.starts_with(|c: char| c.is_ascii_whitespace()),
+            NodeKind::Comment(_) => true,
+            _ => false,
+        })
+    }
+
+    /// Whether the start tag of the element `node_id`, which has no
+    /// attributes, may be left out.
+    /// See: https://html.spec.whatwg.org/multipage/syntax.html#optional-tags
+    fn omits_start_tag(&self, node_id: NodeId, tag: &str, cx: SerializeContext<'_>) -> bool {
+        // None of these keep whitespace, so their children are in the same context
+        let first = self.first_serialized_child(node_id, cx);
+        let previous = self.serialized_sibling(node_id, cx, false);
+        match tag {
+            // Without a doctype, `hotmeal::parse` would take what follows for body content
+            "html" => cx.after_doctype && !first.is_some_and(|id| self.is_comment(id)),
+            "head" => first.is_none_or(|id| matches!(self.get(id).kind, NodeKind::Element(_))),
+            "body" => {
+                first.is_none()
+                    || !(self.is_space_or_comment(first)
+                        || self.is_html_element(
+                            first,
+                            &[
+                                "base", "basefont", "bgsound", "link", "meta", "noframes",
+                                "script", "style", "template", "title",
+                            ],
+                        ))
             }
-            NodeKind::Comment(text) => {
-                out.push_str("<!--");
-                out.push_str(text.as_ref());
-                out.push_str("-->");
+            "colgroup" => {
+                self.is_html_element(first, &["col"])
+                    && !self.is_html_element(previous, &["colgroup"])
+            }
+            "tbody" => {
+                self.is_html_element(first, &["tr"])
+                    && !self.is_html_element(previous, &["tbody", "thead", "tfoot"])
             }
+            _ => false,
+        }
+    }
+
+    /// Whether the end tag of the element `node_id` may be left out.
+    /// See: https://html.spec.whatwg.org/multipage/syntax.html#optional-tags
+    fn omits_end_tag(&self, node_id: NodeId, tag: &str, cx: SerializeContext<'_>) -> bool {
+        let next = self.serialized_sibling(node_id, cx, true);
+        match tag {
+            "html" | "body" => !next.is_some_and(|id| self.is_comment(id)),
+            "head" | "colgroup" | "caption" => !self.is_space_or_comment(next),
+            "li" => next.is_none() || self.is_html_element(next, &["li"]),
+            "dt" => self.is_html_element(next, &["dt", "dd"]),
+            "dd" => next.is_none() || self.is_html_element(next, &["dt", "dd"]),
+            "p" => match next {
+                Some(_) => self.is_html_element(
+                    next,
+                    &[
+                        "address",
+                        "article",
+                        "aside",
+                        "blockquote",
+                        "details",
+                        "dialog",
+                        "div",
+                        "dl",
+                        "fieldset",
+                        "figcaption",
+                        "figure",
+                        "footer",
+                        "form",
+                        "h1",
+                        "h2",
+                        "h3",
+                        "h4",
+                        "h5",
+                        "h6",
+                        "header",
+                        "hgroup",
+                        "hr",
+                        "main",
+                        "menu",
+                        "nav",
+                        "ol",
+                        "p",
+                        "pre",
+                        "section",
+                        "table",
+                        "ul",
+                    ],
+                ),
+                None => self.logical_parent(node_id).is_some_and(|parent| {
+                    let node = self.get(parent);
+                    match &node.kind {
+                        NodeKind::Element(elem) => {
+                            node.ns == Namespace::Html
+                                && !elem.tag.contains('-')
+                                && !matches!(
+                                    elem.tag.as_ref(),
+                                    "a" | "audio" | "del" | "ins" | "map" | "noscript" | "video"
+                                )
+                        }
+                        _ => false,
+                    }
+                }),
+            },
+            "rt" | "rp" => next.is_none() || self.is_html_element(next, &["rt", "rp"]),
+            "optgroup" => next.is_none() || self.is_html_element(next, &["optgroup"]),
+            "option" => next.is_none() || self.is_html_element(next, &["option", "optgroup"]),
+            "thead" => self.is_html_element(next, &["tbody", "tfoot"]),
+            "tbody" => next.is_none() || self.is_html_element(next, &["tbody", "tfoot"]),
+            "tfoot" => next.is_none(),
+            "tr" => next.is_none() || self.is_html_element(next, &["tr"]),
+            "td" | "th" => next.is_none() || self.is_html_element(next, &["td", "th"]),
+            _ => false,
         }
     }
+
+    fn is_comment(&self, node_id: NodeId) -> bool {
+        matches!(self.get(node_id).kind, NodeKind::Comment(_))
+    }
+}
+
+/// Start a new line, indented for `depth`.
+fn push_indent(out: &mut dyn fmt::Write, options: &SerializeOptions, depth: usize) -> fmt::Result {
+    let width = options.indent.unwrap_or(0) * depth;
+    write!(out, "\n{:width$}", "")
+}
+
+/// Whether an attribute value has to be quoted.
+/// See: https://html.spec.whatwg.org/multipage/syntax.html#unquoted
+fn needs_quotes(value: &str) -> bool {
+    value.is_empty()
+        || value.bytes().any(|b| {
+            b.is_ascii_whitespace() || matches!(b, b'"' | b'\'' | b'=' | b'<' | b'>' | b'`')
+        })
+}
+
+/// Whether text directly inside `elem` keeps its whitespace when serialized.
+fn preserves_whitespace(elem: &ElementData) -> bool {
+    matches!(
+        elem.tag.as_ref(),
+        "pre" | "textarea" | "listing" | "xmp" | "plaintext" | "script" | "style" | "noscript"
+    ) || elem.attrs.iter().any(|(k, v)| {
+        k.ns == ns!() && k.local == local_name!("style") && style_preserves_whitespace(v.as_ref())
+    })
+}
+
+/// Elements laid out as blocks, or not rendered at all, so whitespace
+/// around them doesn't show.
+pub(crate) fn is_block_element(tag: &str) -> bool {
+    matches!(
+        tag,
+        "address"
+            | "article"
+            | "aside"
+            | "base"
+            | "blockquote"
+            | "body"
+            | "caption"
+            | "col"
+            | "colgroup"
+            | "dd"
+            | "details"
+            | "dialog"
+            | "div"
+            | "dl"
+            | "dt"
+            | "fieldset"
+            | "figcaption"
+            | "figure"
+            | "footer"
+            | "form"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "head"
+            | "header"
+            | "hgroup"
+            | "hr"
+            | "html"
+            | "li"
+            | "link"
+            | "main"
+            | "menu"
+            | "meta"
+            | "nav"
+            | "ol"
+            | "optgroup"
+            | "option"
+            | "p"
+            | "pre"
+            | "script"
+            | "section"
+            | "style"
+            | "summary"
+            | "table"
+            | "tbody"
+            | "td"
+            | "template"
+            | "tfoot"
+            | "th"
+            | "thead"
+            | "title"
+            | "tr"
+            | "ul"
+    )
 }
 
-/// HTML5 void elements that never have closing tags
+/// HTML elements serialized without an end tag (or children)
+/// See: https://html.spec.whatwg.org/multipage/parsing.html#serializes-as-void
 fn is_void_element(tag: &str) -> bool {
     matches!(
         tag,
         "area"
             | "base"
+            | "basefont"
+            | "bgsound"
             | "br"
             | "col"
             | "embed"
+            | "frame"
             | "hr"
             | "img"
             | "input"
+            | "keygen"
             | "link"
             | "meta"
             | "param"
@@ -942,14 +2090,54 @@ fn is_void_element(tag: &str) -> bool {
     )
 }
 
-/// HTML5 raw text elements whose content should not be escaped
-/// See: https://html.spec.whatwg.org/multipage/syntax.html#raw-text-elements
+/// HTML elements whose text content is serialized without escaping
+/// See: https://html.spec.whatwg.org/multipage/parsing.html#serialising-html-fragments
 ///
 /// Note: `noscript` is included because we always parse with scripting enabled
 /// (html5ever's default). When scripting is enabled, noscript is parsed as rawtext,
 /// so it must also be serialized as rawtext for innerHTML to be idempotent.
 fn is_raw_text_element(tag: &str) -> bool {
-    matches!(tag, "script" | "style" | "noscript")
+    matches!(
+        tag,
+        "style" | "script" | "xmp" | "iframe" | "noembed" | "noframes" | "plaintext" | "noscript"
+    )
+}
+
+/// Write an attribute's serialized name: the namespaces the parser puts
+/// attributes of foreign elements in have fixed prefixes.
+fn write_attr_name(out: &mut dyn fmt::Write, name: &QualName) -> fmt::Result {
+    let prefix = match name.ns {
+        ns!(xml) => Some("xml"),
+        ns!(xmlns) if name.local == local_name!("xmlns") => None,
+        ns!(xmlns) => Some("xmlns"),
+        ns!(xlink) => Some("xlink"),
+        _ => name.prefix.as_deref().filter(|prefix| !prefix.is_empty()),
+    };
+    if let Some(prefix) = prefix {
+        out.write_str(prefix)?;
+        out.write_char(':')?;
+    }
+    out.write_str(name.local.as_ref())
+}
+
+/// Escape text, or an attribute value with `attribute`.
+/// See: https://html.spec.whatwg.org/multipage/parsing.html#escapingString
+fn write_escaped(out: &mut dyn fmt::Write, text: &str, attribute: bool) -> fmt::Result {
+    let mut rest = text;
+    while let Some(i) = rest.find(['&', '\u{a0}', '"', '<', '>']) {
+        let c = rest[i..].chars().next().unwrap_or_default();
+        out.write_str(&rest[..i])?;
+        out.write_str(match c {
+            '&' => "&amp;",
+            '\u{a0}' => "&nbsp;",
+            '"' if attribute => "&quot;",
+            '"' => "\"",
+            '<' => "&lt;",
+            _ => "&gt;",
+        })?;
+        rest = &rest[i + c.len_utf8()..];
+    }
+    out.write_str(rest)
 }
 
 /// What goes in each arena slot
@@ -957,6 +2145,10 @@ fn is_raw_text_element(tag: &str) -> bool {
 pub struct NodeData<'a> {
     pub kind: NodeKind<'a>,
     pub ns: Namespace,
+    /// Where the node came from in the parsed input, with [`ParseOptions::spans`]:
+    /// the start tag of an element, the whole of a comment, the source text
+    /// (character references included) of a text node.
+    pub span: Option<Span>,
 }
 
 /// Node types
@@ -970,6 +2162,9 @@ pub enum NodeKind<'a> {
     Text(Stem<'a>),
     /// HTML comment
     Comment(Stem<'a>),
+    /// Contents fragment of a `<template>` element (invisible, always the
+    /// template's only child; the template's children live under it)
+    TemplateContents,
 }
 
 /// Element data (tag + attributes)
@@ -982,9 +2177,48 @@ pub struct ElementData<'a> {
     pub attrs: Vec<(QualName, Stem<'a>)>,
 }
 
+// into_owned implementations, for documents that outlive their input
+
+impl NodeData<'_> {
+    pub fn into_owned(self) -> NodeData<'static> {
+        NodeData {
+            kind: self.kind.into_owned(),
+            ns: self.ns,
+            span: self.span,
+        }
+    }
+}
+
+impl NodeKind<'_> {
+    pub fn into_owned(self) -> NodeKind<'static> {
+        match self {
+            NodeKind::Document => NodeKind::Document,
+            NodeKind::Element(elem) => NodeKind::Element(elem.into_owned()),
+            NodeKind::Text(text) => NodeKind::Text(text.into_owned()),
+            NodeKind::Comment(text) => NodeKind::Comment(text.into_owned()),
+            NodeKind::TemplateContents => NodeKind::TemplateContents,
+        }
+    }
+}
+
+impl ElementData<'_> {
+    pub fn into_owned(self) -> ElementData<'static> {
+        ElementData {
+            tag: self.tag,
+            attrs: self
+                .attrs
+                .into_iter()
+                .map(|(name, value)| (name, value.into_owned()))
+                .collect(),
+        }
+    }
+}
+
 /// XML namespace
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, facet::Facet)]
+#[repr(u8)]
 pub enum Namespace {
+    #[default]
     Html,
     Svg,
     MathMl,
@@ -1009,6 +2243,40 @@ impl Namespace {
     }
 }
 
+/// Replace `delete_len` UTF-16 code units of `text`, starting at `offset`, with `insert`.
+pub(crate) fn splice_utf16(
+    text: &str,
+    offset: u32,
+    delete_len: u32,
+    insert: &str,
+) -> Result<compact_str::CompactString, DiffError> {
+    let out_of_bounds = || DiffError::TextRangeOutOfBounds {
+        offset,
+        len: delete_len,
+    };
+    let start = utf16_to_byte_offset(text, offset).ok_or_else(out_of_bounds)?;
+    let end = utf16_to_byte_offset(&text[start..], delete_len).ok_or_else(out_of_bounds)? + start;
+    Ok(compact_str::format_compact!(
+        "{}{}{}",
+        &text[..start],
+        insert,
+        &text[end..]
+    ))
+}
+
+/// Byte offset of the `units`-th UTF-16 code unit of `s`, or `None` if that's
+/// past the end or in the middle of a character.
+fn utf16_to_byte_offset(s: &str, units: u32) -> Option<usize> {
+    let mut seen = 0;
+    for (byte, c) in s.char_indices() {
+        if seen >= units {
+            return (seen == units).then_some(byte);
+        }
+        seen += c.len_utf16() as u32;
+    }
+    (seen == units).then_some(s.len())
+}
+
 fn has_doctype_prefix(input: &str) -> bool {
     let bytes = input.as_bytes();
     let mut i = 0;
@@ -1036,10 +2304,91 @@ fn has_doctype_prefix(input: &str) -> bool {
 /// // doc borrows from input - zero-copy for unchanged content
 /// ```
 pub fn parse(tendril: &StrTendril) -> Document<'_> {
+    parse_with_options(tendril, &ParseOptions::default())
+}
+
+/// Knobs for [`parse_bytes_with_options`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseBytesOptions {
+    /// The encoding the input came with, like the `charset` of a
+    /// `Content-Type` header. Only a byte order mark overrides it.
+    pub transport_encoding: Option<&'static Encoding>,
+
+    /// The encoding used when neither a byte order mark nor a `<meta>`
+    /// tag in the first 1024 bytes says which it is. Browsers pick it from
+    /// the user's locale; windows-1252 by default.
+    pub fallback: &'static Encoding,
+
+    /// How the decoded input is parsed. Spans point into the decoded text,
+    /// not into the bytes.
+    pub parse: ParseOptions,
+}
+
+impl Default for ParseBytesOptions {
+    fn default() -> Self {
+        Self {
+            transport_encoding: None,
+            fallback: WINDOWS_1252,
+            parse: ParseOptions::default(),
+        }
+    }
+}
+
+/// Parse HTML bytes, working out their encoding like browsers do: from a
+/// byte order mark, a `<meta charset>`, or else windows-1252. The encoding
+/// used is in [`Document::encoding`].
+///
+/// The input is decoded to a new string, so the document doesn't borrow
+/// from `bytes`.
+///
+/// ```
+/// let doc = hotmeal::parse_bytes(b"<meta charset=shift_jis><p>\x93\xfa\x96\x7b</p>");
+/// assert_eq!(doc.encoding, hotmeal::encoding_rs::SHIFT_JIS);
+/// assert!(doc.to_body_html().contains("<p>日本</p>"));
+/// ```
+pub fn parse_bytes(bytes: &[u8]) -> Document<'static> {
+    parse_bytes_with_options(bytes, &ParseBytesOptions::default())
+}
+
+/// Like [`parse_bytes`], with [`ParseBytesOptions`].
+pub fn parse_bytes_with_options(bytes: &[u8], options: &ParseBytesOptions) -> Document<'static> {
+    let (encoding, bom_len) = crate::encoding::sniff(bytes, options);
+    let (text, _) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+    let tendril = StrTendril::from(text.as_ref());
+    let mut doc = parse_with_options(&tendril, &options.parse).into_owned();
+    doc.encoding = encoding;
+    doc
+}
+
+/// Knobs for [`parse_with_options`].
+///
+/// [`ParseOptions::default()`] is what [`parse`] uses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Record where every node ([`NodeData::span`]) and parse error
+    /// ([`ParseError::span`]) came from in the input.
+    ///
+    /// Parsing gets slower, and text containing `>` is copied rather than
+    /// borrowed from the input.
+    pub spans: bool,
+}
+
+/// Like [`parse`], with [`ParseOptions`].
+///
+/// ```
+/// use hotmeal::{parse_with_options, ParseOptions, StrTendril};
+///
+/// let input = StrTendril::from("<p>Hello\n<b>there</b></p>");
+/// let doc = parse_with_options(&input, &ParseOptions { spans: true });
+/// let b = doc.select_first("b").unwrap().unwrap();
+/// let span = doc.get(b).span.unwrap();
+/// assert_eq!(&input[span.start as usize..span.end as usize], "<b>");
+/// assert_eq!(span.line_col(&input), (2, 1));
+/// ```
+pub fn parse_with_options<'a>(tendril: &'a StrTendril, options: &ParseOptions) -> Document<'a> {
     use tendril::TendrilSink;
 
     let input_ref: &str = tendril.as_ref();
-    let sink = ArenaSink::new(input_ref);
 
     // Prepend DOCTYPE if not present to ensure no-quirks mode parsing.
     // This matches browser behavior for innerHTML which always uses no-quirks.
@@ -1050,22 +2399,37 @@ pub fn parse(tendril: &StrTendril) -> Document<'_> {
     // content like " *" to become inter-element whitespace before <html> and
     // get discarded. Instead, wrap in <html><body>...</body></html> so the
     // content is clearly in the body context.
-    let input = if has_doctype {
-        tendril.clone()
+    let (input, prefix) = if has_doctype {
+        (tendril.clone(), "")
     } else if has_html_structure(input_ref) {
         // Input has <html> or <body> tags, just prepend DOCTYPE
-        let mut with_doctype = StrTendril::from("<!DOCTYPE html>");
+        let prefix = "<!DOCTYPE html>";
+        let mut with_doctype = StrTendril::from(prefix);
         with_doctype.push_tendril(tendril);
-        with_doctype
+        (with_doctype, prefix)
     } else {
         // Input is raw body content - wrap it to preserve leading whitespace
-        let mut wrapped = StrTendril::from("<!DOCTYPE html><html><body>");
+        let prefix = "<!DOCTYPE html><html><body>";
+        let mut wrapped = StrTendril::from(prefix);
         wrapped.push_tendril(tendril);
         wrapped.push_slice("</body></html>");
-        wrapped
+        (wrapped, prefix)
     };
 
-    let mut doc = parse_document(sink, Default::default()).one(input);
+    let mut doc = if options.spans {
+        let tracker = SpanTracker::new(input.clone(), prefix.len() as u32, tendril.len32());
+        let mut parser =
+            parse_document(ArenaSink::new(input_ref, Some(tracker)), Default::default());
+        for (start, end) in spans::chunks(&input) {
+            if let Some(tracker) = &parser.tokenizer.sink.sink.spans {
+                tracker.set_chunk(start, end);
+            }
+            parser.process(input.subtendril(start, end - start));
+        }
+        parser.finish()
+    } else {
+        parse_document(ArenaSink::new(input_ref, None), Default::default()).one(input)
+    };
     if !has_doctype {
         // Don't store the artificially added DOCTYPE - preserve original input behavior
         doc.doctype = None;
@@ -1080,25 +2444,50 @@ pub fn parse(tendril: &StrTendril) -> Document<'_> {
 ///
 /// Use this when comparing against browser innerHTML parsing for parity testing.
 pub fn parse_body_fragment(tendril: &StrTendril) -> Document<'_> {
-    use html5ever::parse_fragment;
+    parse_fragment(tendril, QualName::new(None, ns!(html), local_name!("body")))
+}
+
+/// Parse HTML as the contents of a `context` element, like setting
+/// `innerHTML` on it: `<td>`s in a `<tr>`, `<option>`s in a `<select>`,
+/// SVG elements in an `<svg>`, and so on.
+///
+/// The parsed nodes are the children of [`Document::root`], an element
+/// with the context element's name standing in for it, so that
+/// [`Document::serialize_inner_html`] on it serializes them the way they'd
+/// be serialized in place (as raw text in a `<script>`, say). Like in
+/// browsers, scripting is enabled.
+///
+/// ```
+/// use hotmeal::{QualName, StrTendril, local_name, ns, parse_fragment};
+///
+/// let input = StrTendril::from("<td>a</td><td>b</td>");
+/// let doc = parse_fragment(&input, QualName::new(None, ns!(html), local_name!("tr")));
+/// assert_eq!(doc.serialize_inner_html(doc.root), "<td>a</td><td>b</td>");
+/// ```
+pub fn parse_fragment(tendril: &StrTendril, context: QualName) -> Document<'_> {
     use tendril::TendrilSink;
 
     let input_ref: &str = tendril.as_ref();
-    let sink = ArenaSink::new(input_ref);
-
-    // Parse as fragment with <body> as context, scripting enabled
-    let context_name = QualName::new(None, ns!(html), local_name!("body"));
+    let sink = ArenaSink::new(input_ref, None);
     let context_attrs = vec![];
     let scripting_enabled = true;
 
-    parse_fragment(
+    let mut doc = html5ever::parse_fragment(
         sink,
         Default::default(),
-        context_name,
+        context.clone(),
         context_attrs,
         scripting_enabled,
     )
-    .one(tendril.clone())
+    .one(tendril.clone());
+
+    // The parser puts the nodes in an <html>; name it after the context instead
+    let root = doc.arena[doc.root].get_mut();
+    if let NodeKind::Element(elem) = &mut root.kind {
+        elem.tag = context.local;
+        root.ns = Namespace::from_url(&context.ns);
+    }
+    doc
 }
 
 /// Check if input has HTML structure tags (<html> or <body>).
@@ -1133,7 +2522,13 @@ struct ArenaSink<'a> {
     document: NodeId,
 
     /// Parse errors
-    errors: RefCell<Vec<Cow<'static, str>>>,
+    errors: RefCell<Vec<ParseError>>,
+
+    /// Line html5ever is on
+    line: Cell<u64>,
+
+    /// Where the parser is in the input, with [`ParseOptions::spans`]
+    spans: Option<SpanTracker>,
 
     /// DOCTYPE encountered during parse
     doctype: RefCell<Option<Stem<'a>>>,
@@ -1158,6 +2553,14 @@ fn tendril_to_stem_with_input<'a>(input: &'a str, t: StrTendril) -> Stem<'a> {
     }
 }
 
+/// Grow `span` to cover `more` too.
+fn extend_span(span: &mut Option<Span>, more: Option<Span>) {
+    *span = match (*span, more) {
+        (Some(a), Some(b)) => Some(a.cover(b)),
+        (a, b) => a.or(b),
+    };
+}
+
 fn node_id_short(node_id: NodeId) -> String {
     let debug = format!("{:?}", node_id);
     let Some(start) = debug.find("index1: ") else {
@@ -1249,6 +2652,12 @@ fn dump_arena_subtree<'a>(
                     dump_node(arena, child, indent + 1, out, highlights);
                 }
             }
+            NodeKind::TemplateContents => {
+                out.push_str(&format!("{prefix}#template-contents{badge}\n"));
+                for child in node_id.children(arena) {
+                    dump_node(arena, child, indent + 1, out, highlights);
+                }
+            }
         }
     }
 
@@ -1287,12 +2696,13 @@ impl<'a> std::fmt::Display for LazyTreeDump<'a> {
 }
 
 impl<'a> ArenaSink<'a> {
-    fn new(input: &'a str) -> Self {
+    fn new(input: &'a str, spans: Option<SpanTracker>) -> Self {
         let mut arena = Arena::new();
 
         let document = arena.new_node(NodeData {
             kind: NodeKind::Document,
             ns: Namespace::Html,
+            span: None,
         });
 
         ArenaSink {
@@ -1301,6 +2711,8 @@ impl<'a> ArenaSink<'a> {
             document,
             doctype: RefCell::new(None),
             errors: Default::default(),
+            line: Cell::new(1),
+            spans,
         }
     }
 
@@ -1308,6 +2720,11 @@ impl<'a> ArenaSink<'a> {
     fn tendril_to_stem(&self, t: StrTendril) -> Stem<'a> {
         tendril_to_stem_with_input(self.input, t)
     }
+
+    /// Where `text` came from, if spans are being tracked
+    fn text_span(&self, text: &StrTendril) -> Option<Span> {
+        self.spans.as_ref()?.text(text)
+    }
 }
 
 impl<'a> TreeSink for ArenaSink<'a> {
@@ -1333,11 +2750,20 @@ impl<'a> TreeSink for ArenaSink<'a> {
             root,
             doctype: self.doctype.into_inner(),
             errors: self.errors.into_inner(),
+            encoding: UTF_8,
         }
     }
 
     fn parse_error(&self, msg: Cow<'static, str>) {
-        self.errors.borrow_mut().push(msg);
+        self.errors.borrow_mut().push(ParseError {
+            message: msg,
+            line: self.line.get(),
+            span: self.spans.as_ref().and_then(SpanTracker::chunk),
+        });
+    }
+
+    fn set_current_line(&self, line_number: u64) {
+        self.line.set(line_number);
     }
 
     fn get_document(&self) -> Self::Handle {
@@ -1384,10 +2810,11 @@ impl<'a> TreeSink for ArenaSink<'a> {
         &self,
         name: QualName,
         attrs: Vec<Attribute>,
-        _flags: ElementFlags,
+        flags: ElementFlags,
     ) -> Self::Handle {
         let tag = name.local;
         let ns = Namespace::from_url(name.ns.as_ref());
+        let span = self.spans.as_ref().and_then(|s| s.start_tag(&tag));
 
         let attrs: Vec<_> = attrs
             .into_iter()
@@ -1395,16 +2822,29 @@ impl<'a> TreeSink for ArenaSink<'a> {
             .collect();
 
         // Create node in arena
-        self.arena.borrow_mut().new_node(NodeData {
+        let mut arena = self.arena.borrow_mut();
+        let node = arena.new_node(NodeData {
             kind: NodeKind::Element(ElementData { tag, attrs }),
             ns,
-        })
+            span,
+        });
+        if flags.template {
+            let contents = arena.new_node(NodeData {
+                kind: NodeKind::TemplateContents,
+                ns: Namespace::Html,
+                span: None,
+            });
+            node.append(contents, &mut arena);
+        }
+        node
     }
 
     fn create_comment(&self, text: StrTendril) -> Self::Handle {
+        let span = self.spans.as_ref().and_then(|s| s.comment(text.len()));
         self.arena.borrow_mut().new_node(NodeData {
             kind: NodeKind::Comment(self.tendril_to_stem(text)),
             ns: Namespace::Html,
+            span,
         })
     }
 
@@ -1413,6 +2853,7 @@ impl<'a> TreeSink for ArenaSink<'a> {
         self.arena.borrow_mut().new_node(NodeData {
             kind: NodeKind::Comment(Stem::new()),
             ns: Namespace::Html,
+            span: None,
         })
     }
 
@@ -1499,15 +2940,18 @@ impl<'a> TreeSink for ArenaSink<'a> {
                         "append: merged text"
                     );
                     existing.push_tendril(&text);
+                    extend_span(&mut arena[last_child].get_mut().span, self.text_span(&text));
                     return;
                 }
 
                 // Can't use self.tendril_to_stem here because we have arena borrowed
                 // Need to do the check manually
+                let span = self.text_span(&text);
                 let stem = tendril_to_stem_with_input(self.input, text);
                 let text_node = arena.new_node(NodeData {
                     kind: NodeKind::Text(stem),
                     ns: Namespace::Html,
+                    span,
                 });
                 trace!(
                     parent_id = %node_id_short(*parent),
@@ -1638,6 +3082,10 @@ impl<'a> TreeSink for ArenaSink<'a> {
                             "append_before_sibling: merged text"
                         );
                         existing.push_tendril(&text);
+                        extend_span(
+                            &mut arena[prev_sibling].get_mut().span,
+                            self.text_span(&text),
+                        );
                         if let Some(parent) = parent {
                             let highlights = [
                                 (parent, "\x1b[32m", "PARENT"),
@@ -1657,10 +3105,12 @@ impl<'a> TreeSink for ArenaSink<'a> {
                     }
                 }
 
+                let span = self.text_span(&text);
                 let stem = tendril_to_stem_with_input(self.input, text);
                 let text_node = arena.new_node(NodeData {
                     kind: NodeKind::Text(stem),
                     ns: Namespace::Html,
+                    span,
                 });
                 trace!(
                     sibling_id = %node_id_short(*sibling),
@@ -1719,9 +3169,12 @@ impl<'a> TreeSink for ArenaSink<'a> {
     }
 
     fn get_template_contents(&self, target: &Self::Handle) -> Self::Handle {
-        // For <template>, return the element itself
-        // (proper template support would need a template contents fragment)
-        *target
+        // The contents fragment is created alongside the <template> element
+        let arena = self.arena.borrow();
+        match target.children(&arena).next() {
+            Some(first) if matches!(arena[first].get().kind, NodeKind::TemplateContents) => first,
+            _ => *target,
+        }
     }
 
     fn add_attrs_if_missing(&self, target: &Self::Handle, attrs: Vec<Attribute>) {
@@ -1982,7 +3435,7 @@ mod tests {
         let doc = parse(&html);
         assert_eq!(
             doc.to_html(),
-            "<html><head></head><body><div>&lt;script> &amp; \"quotes\"</div></body></html>"
+            "<html><head></head><body><div>&lt;script&gt; &amp; \"quotes\"</div></body></html>"
         );
     }
 
@@ -2021,6 +3474,43 @@ mod tests {
         assert_eq!(mut_old_doc.to_html(), new_doc.to_html());
     }
 
+    #[test]
+    fn test_roll_back_reports_failure() {
+        let html = t("<html><body><div>Patched</div></body></html>");
+        let mut doc = parse(&html);
+        let slots = doc.init_patch_slots();
+
+        let undo = vec![
+            Patch::Remove {
+                node: NodeRef(NodePath(smallvec::smallvec![0, 9])),
+            },
+            Patch::SetText {
+                path: NodePath(smallvec::smallvec![0, 0, 0]),
+                text: Stem::from("Original"),
+            },
+        ];
+        let err = doc.roll_back(undo, slots, true, 3, DiffError::EmptyPath);
+        assert!(
+            matches!(
+                &err,
+                DiffError::RollbackFailed { index: 3, source }
+                    if matches!(**source, DiffError::EmptyPath)
+            ),
+            "got: {err:?}"
+        );
+        assert_eq!(
+            err.to_string(),
+            "patch 3 failed (cannot get parent of empty path) and undoing the ones before it \
+             failed too; the document is partially patched"
+        );
+
+        // The patches after the failing one were still replayed
+        assert_eq!(
+            doc.to_html(),
+            "<html><head></head><body><div>Original</div></body></html>"
+        );
+    }
+
     #[test]
     fn test_apply_patches_insert_element() {
         let old_html = t("<html><body><div>First</div></body></html>");
@@ -2037,6 +3527,46 @@ mod tests {
         assert_eq!(mut_old_doc.to_html(), new_doc.to_html());
     }
 
+    #[test]
+    fn test_template_contents_fragment() {
+        let html =
+            t("<html><body><template id=\"row\"><tr><td>cell</td></tr></template></body></html>");
+        let doc = parse(&html);
+        let body = doc.body().unwrap();
+        let template = doc.children(body).next().unwrap();
+
+        // The template's only child is its contents fragment
+        let contents = doc.template_contents(template).expect("template contents");
+        assert_eq!(template.children(&doc.arena).count(), 1);
+        assert!(doc.children(template).eq(doc.children(contents)));
+        let tr = doc.children(contents).next().unwrap();
+        assert!(matches!(&doc.get(tr).kind, NodeKind::Element(e) if e.tag.as_ref() == "tr"));
+        assert_eq!(doc.parent(tr), Some(template));
+
+        assert_eq!(
+            doc.to_body_html(),
+            "<template id=\"row\"><tr><td>cell</td></tr></template>"
+        );
+    }
+
+    #[test]
+    fn test_create_template_element_has_contents() {
+        let mut doc = Document::new();
+        let template = doc.create_element("template");
+        let contents = doc.template_contents(template).expect("template contents");
+        let text = doc.create_text("hi");
+        doc.append_child(contents, text);
+        assert_eq!(doc.serialize_inner_html(template), "hi");
+        // Going through the template itself lands in its contents too
+        let more = doc.create_text("!");
+        doc.append_child(template, more);
+        assert_eq!(doc.serialize_inner_html(template), "hi!");
+        assert_eq!(doc.children(template).collect::<Vec<_>>(), [text, more]);
+        assert_eq!(doc.first_child(template), Some(text));
+        let div = doc.create_element("div");
+        assert!(doc.template_contents(div).is_none());
+    }
+
     #[test]
     fn test_script_content_not_escaped() {
         // Script content should NOT be HTML-escaped (it's a raw text element)
@@ -2105,20 +3635,21 @@ mod tests {
         let output = doc.to_html();
 
         assert!(
-            output.contains("1 &lt; 2 &amp; 3 > 1"),
+            output.contains("1 &lt; 2 &amp; 3 &gt; 1"),
             "Normal text should be escaped. Got: {}",
             output
         );
     }
 
     #[test]
-    fn test_gt_not_escaped_in_text_nodes() {
+    fn test_gt_escaped_in_text_nodes() {
+        // Like browsers' innerHTML
         let html = t("<pre>[*] --> Foo</pre>");
         let doc = parse(&html);
         let output = doc.to_html();
         assert!(
-            output.contains("[*] --> Foo"),
-            "Greater-than should not be escaped in text nodes. Got: {}",
+            output.contains("[*] --&gt; Foo"),
+            "Greater-than should be escaped in text nodes. Got: {}",
             output
         );
     }
@@ -2629,4 +4160,468 @@ mod tests {
             eprintln!("{}", doc.dump_subtree(doc.root));
         }
     }
+
+    #[test]
+    fn test_parse_with_spans() {
+        let input = t(
+            "<!DOCTYPE html>\n<html><body>\n<p class=\"a>b\">x &amp; y</p><!-- hi -->\n<table><tr><td>1</td></tr></table></body></html>",
+        );
+        let doc = parse_with_options(&input, &ParseOptions { spans: true });
+        let source = |node: NodeId| {
+            let span = doc.get(node).span.unwrap();
+            &input[span.start as usize..span.end as usize]
+        };
+
+        let p = doc.select_first("p").unwrap().unwrap();
+        assert_eq!(source(p), "<p class=\"a>b\">");
+        assert_eq!(doc.get(p).span.unwrap().line_col(&input), (3, 1));
+        let text = doc.first_child(p).unwrap();
+        assert_eq!(source(text), "x &amp; y");
+        let comment = doc.next_sibling(p).unwrap();
+        assert_eq!(source(comment), "<!-- hi -->");
+        let td = doc.select_first("td").unwrap().unwrap();
+        assert_eq!(source(td), "<td>");
+        // Created by the parser, not by a tag
+        let tbody = doc.select_first("tbody").unwrap().unwrap();
+        assert_eq!(doc.get(tbody).span, None);
+
+        // Without the option, nothing is tracked
+        let plain = parse(&input);
+        assert!(plain.arena.iter().all(|node| node.get().span.is_none()));
+        assert_eq!(plain.to_html(), doc.to_html());
+    }
+
+    #[test]
+    fn test_parse_with_spans_without_doctype() {
+        // The input gets wrapped before parsing; spans still point into it
+        let input = t("<div>\n  <span>hi</span></div>");
+        let doc = parse_with_options(&input, &ParseOptions { spans: true });
+        let span = doc.select_first("span").unwrap().unwrap();
+        assert_eq!(doc.get(span).span, Some(Span { start: 8, end: 14 }));
+        assert_eq!(doc.get(span).span.unwrap().line_col(&input), (2, 3));
+        let body = doc.body().unwrap();
+        assert_eq!(doc.get(body).span, None);
+    }
+
+    #[test]
+    fn test_parse_errors_are_located() {
+        let input = t("<!DOCTYPE html>\n<p>\n</div>");
+        let doc = parse_with_options(&input, &ParseOptions { spans: true });
+        let error = doc.errors.first().unwrap();
+        assert_eq!(error.line, 3);
+        let span = error.span.unwrap();
+        assert!(input[span.start as usize..span.end as usize].contains("</div>"));
+        assert!(error.to_string().starts_with("line 3: "));
+
+        assert_eq!(parse(&input).errors[0].line, 3);
+    }
+
+    #[test]
+    fn test_node_at_path() {
+        let input =
+            t("<html><head><title>t</title></head><body><div><p>a</p><p>b</p></div></body></html>");
+        let doc = parse(&input);
+        let p = doc
+            .node_at_path(&NodePath(smallvec::smallvec![0, 0, 1]))
+            .unwrap();
+        assert_eq!(doc.select("p").unwrap()[1], p);
+        let title = doc
+            .node_at_path(&NodePath(smallvec::smallvec![HEAD_SLOT, 0]))
+            .unwrap();
+        assert_eq!(doc.select_first("title").unwrap(), Some(title));
+        assert_eq!(doc.node_at_path(&NodePath(smallvec::smallvec![0, 3])), None);
+        assert_eq!(doc.node_at_path(&NodePath(smallvec::smallvec![1])), None);
+    }
+
+    #[test]
+    fn test_serialize_pretty() {
+        let input = t(
+            "<!DOCTYPE html><html><head><title>t</title></head><body><ul><li>a <b>b</b></li><li>c</li></ul><pre> x\n y</pre></body></html>",
+        );
+        let doc = parse(&input);
+        let html = doc.to_html_with(&SerializeOptions::pretty());
+        assert_eq!(
+            html,
+            "<!DOCTYPE html>\n<html>\n  <head>\n    <title>t</title>\n  </head>\n  <body>\n    <ul>\n      <li>a <b>b</b></li>\n      <li>c</li>\n    </ul>\n    <pre> x\n y</pre>\n  </body></html>"
+        );
+
+        let body = doc.to_body_html_with(&SerializeOptions::pretty());
+        assert!(body.starts_with("<ul>\n  <li>"));
+        // Inline content stays as it is
+        let span = t("<p>a <span> </span> b</p>");
+        let doc = parse(&span);
+        assert_eq!(
+            doc.to_body_html_with(&SerializeOptions::pretty()),
+            "<p>a <span> </span> b</p>"
+        );
+    }
+
+    #[test]
+    fn test_serialize_minify() {
+        let input = t(
+            "<!DOCTYPE html><html><head><title>t</title></head><body>\n<p class=\"a\">one   two</p>\n<p title=\"x y\" hidden>three</p>\n<table><tr><td>1</td><td>2</td></tr></table></body></html>",
+        );
+        let doc = parse(&input);
+        let html = doc.to_html_with(&SerializeOptions::minify());
+        assert_eq!(
+            html,
+            "<!DOCTYPE html><title>t</title><p class=a>one two<p title=\"x y\" hidden>three<table><tr><td>1<td>2</table>"
+        );
+        let output = t(&html);
+        let reparsed = parse(&output);
+        assert_eq!(reparsed.select("td").unwrap().len(), 2);
+        assert_eq!(reparsed.to_html_with(&SerializeOptions::minify()), html);
+    }
+
+    #[test]
+    fn test_serialize_omits_only_safe_tags() {
+        let options = SerializeOptions {
+            omit_optional_tags: true,
+            ..SerializeOptions::default()
+        };
+        // Without a doctype, `<html>` has to stay for the head to be a head
+        let input = t("<html><head><title>t</title></head><body><p>a</p><!--c--></body></html>");
+        let doc = parse(&input);
+        assert_eq!(
+            doc.to_html_with(&options),
+            "<html><title>t</title><p>a</p><!--c-->"
+        );
+        // `</p>` stays inside an `<a>`, and before text
+        let input = t("<a><p>x</p></a><p>y</p>z");
+        let doc = parse(&input);
+        assert_eq!(doc.to_body_html_with(&options), "<a><p>x</p></a><p>y</p>z");
+    }
+
+    #[test]
+    fn test_write_html() {
+        let input = t(
+            "<!DOCTYPE html><html><body><p>a &amp; b</p><ul><li>1</li><li>2</li></ul></body></html>",
+        );
+        let doc = parse(&input);
+        let mut written = String::new();
+        doc.write_html(&mut written).unwrap();
+        assert_eq!(written, doc.to_html());
+
+        let mut bytes = Vec::new();
+        doc.write_html_io_with(&mut bytes, &SerializeOptions::pretty())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            doc.to_html_with(&SerializeOptions::pretty())
+        );
+
+        // I/O errors come through as they are
+        struct Full;
+        impl io::Write for Full {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::StorageFull.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let error = doc.write_html_io(Full).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::StorageFull);
+    }
+
+    #[test]
+    fn test_write_range() {
+        let input = t("<p>a</p><ul><li>1</li><li>2</li><li>3</li></ul><pre>x</pre>");
+        let doc = parse(&input);
+        let items = doc.select("li").unwrap();
+        let options = SerializeOptions::default();
+        let range = |first, last| {
+            let mut out = String::new();
+            doc.write_range(&mut out, first, last, &options).unwrap();
+            out
+        };
+        assert_eq!(range(items[0], items[1]), "<li>1</li><li>2</li>");
+        assert_eq!(range(items[1], items[1]), "<li>2</li>");
+        // `last` before `first`: on to the end
+        assert_eq!(range(items[1], items[0]), "<li>2</li><li>3</li>");
+
+        let p = doc.select_first("p").unwrap().unwrap();
+        let pre = doc.select_first("pre").unwrap().unwrap();
+        let mut out = String::new();
+        doc.write_range(&mut out, p, pre, &SerializeOptions::pretty())
+            .unwrap();
+        assert_eq!(
+            out,
+            "<p>a</p>\n<ul>\n  <li>1</li>\n  <li>2</li>\n  <li>3</li>\n</ul>\n<pre>x</pre>"
+        );
+    }
+
+    #[test]
+    fn test_serialize_like_inner_html() {
+        // Expected outputs are what browsers give for `body.innerHTML`
+        let cases = [
+            ("<p>a\u{a0}b</p>", "<p>a&nbsp;b</p>"),
+            (
+                "<p title='a\u{a0}\"b\"'>x</p>",
+                "<p title=\"a&nbsp;&quot;b&quot;\">x</p>",
+            ),
+            ("<xmp><b>&amp;</b></xmp>", "<xmp><b>&amp;</b></xmp>"),
+            ("<iframe><p>x</p></iframe>", "<iframe><p>x</p></iframe>"),
+            ("<noembed>a<b</noembed>", "<noembed>a<b</noembed>"),
+            // Foreign elements aren't void, and their style isn't raw text
+            (
+                "<svg><circle/><source/><style>a>b</style></svg>",
+                "<svg><circle></circle><source></source><style>a&gt;b</style></svg>",
+            ),
+            (
+                "<svg xmlns:xlink=\"http://www.w3.org/1999/xlink\" xlink:href=\"#a\" xml:lang=\"en\"></svg>",
+                "<svg xmlns:xlink=\"http://www.w3.org/1999/xlink\" xlink:href=\"#a\" xml:lang=\"en\"></svg>",
+            ),
+            (
+                "<svg xmlns=\"http://www.w3.org/2000/svg\"></svg>",
+                "<svg xmlns=\"http://www.w3.org/2000/svg\"></svg>",
+            ),
+            ("<p>a<basefont>b</p>", "<p>a<basefont>b</p>"),
+        ];
+        for (input, expected) in cases {
+            let input = t(input);
+            assert_eq!(parse(&input).to_body_html(), expected);
+        }
+
+        // A raw text element's own inner HTML isn't escaped either
+        let input = t("<script>if (a < b) {}</script>");
+        let doc = parse(&input);
+        let script = doc.select_first("script").unwrap().unwrap();
+        assert_eq!(doc.serialize_inner_html(script), "if (a < b) {}");
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        // Latin-1 page without a declaration: the fallback applies
+        let doc = parse_bytes(b"<p>caf\xe9</p>");
+        assert_eq!(doc.encoding, WINDOWS_1252);
+        assert_eq!(doc.to_body_html(), "<p>café</p>");
+
+        let options = ParseBytesOptions {
+            fallback: encoding_rs::SHIFT_JIS,
+            ..ParseBytesOptions::default()
+        };
+        let doc = parse_bytes_with_options(b"<p>\x93\xfa\x96\x7b</p>", &options);
+        assert_eq!(doc.to_body_html(), "<p>日本</p>");
+
+        // UTF-16 with a byte order mark
+        let utf16: Vec<u8> = "\u{feff}<p>é</p>"
+            .encode_utf16()
+            .flat_map(|unit| unit.to_le_bytes())
+            .collect();
+        let doc = parse_bytes(&utf16);
+        assert_eq!(doc.encoding, encoding_rs::UTF_16LE);
+        assert_eq!(doc.to_body_html(), "<p>é</p>");
+
+        // Decoded documents diff like any other
+        let old = parse_bytes(b"<meta charset=iso-8859-1><p>caf\xe9</p>");
+        let new_input = t("<meta charset=utf-8><p>café</p>");
+        let new = parse(&new_input);
+        let patches = crate::diff::diff(&old, &new).unwrap();
+        assert!(
+            patches
+                .iter()
+                .all(|patch| !matches!(patch, Patch::SetText { .. })),
+            "{patches:?}"
+        );
+    }
+
+    #[test]
+    fn test_into_owned_keeps_ids() {
+        let input = t("<div id=a><p>x</p><!--c--></div>");
+        let doc = parse(&input);
+        let p = doc.select_first("p").unwrap().unwrap();
+        let html = doc.to_html();
+        let owned: Document<'static> = doc.into_owned();
+        drop(input);
+        assert_eq!(owned.select_first("p").unwrap(), Some(p));
+        assert_eq!(owned.to_html(), html);
+        assert!(owned.arena.iter().all(|node| match &node.get().kind {
+            NodeKind::Text(s) | NodeKind::Comment(s) => matches!(s, Stem::Owned(_)),
+            _ => true,
+        }));
+    }
+
+    #[test]
+    fn test_into_owned_skips_freed_nodes() {
+        let input = t("<div><p>x</p><span>y</span></div><em>z</em>");
+        let mut doc = parse(&input);
+        let p = doc.select_first("p").unwrap().unwrap();
+        let em = doc.select_first("em").unwrap().unwrap();
+        p.remove_subtree(&mut doc.arena);
+        let html = doc.to_html();
+
+        let owned = doc.into_owned();
+        drop(input);
+        assert_eq!(owned.to_html(), html);
+        assert_eq!(owned.select_first("em").unwrap(), Some(em));
+        assert!(owned.arena[p].is_removed());
+    }
+
+    #[test]
+    fn test_into_owned_keeps_ids_of_reused_slots() {
+        let input = t("<div>a</div>");
+        let mut doc = parse(&input);
+        let div = doc.select_first("div").unwrap().unwrap();
+        let b = doc.create_text("b");
+        doc.append_child(div, b);
+        // Merging "b" into "a" frees its slot, which the next node reuses
+        doc.normalize();
+        let em = doc.create_element("em");
+        assert_eq!(usize::from(em), usize::from(b));
+        assert_ne!(em, b);
+        doc.append_child(div, em);
+        let html = doc.to_html();
+
+        let owned = doc.into_owned();
+        drop(input);
+        assert_eq!(owned.to_html(), html);
+        assert_eq!(owned.select_first("em").unwrap(), Some(em));
+        assert_eq!(owned.children(div).last(), Some(em));
+    }
+
+    #[test]
+    fn test_parse_fragment_context() {
+        let html = |tag: LocalName| QualName::new(None, ns!(html), tag);
+        let inner = |input: &str, context: QualName| {
+            let input = t(input);
+            let doc = parse_fragment(&input, context);
+            doc.serialize_inner_html(doc.root)
+        };
+
+        let cells = "<td>a</td><td>b</td>";
+        assert_eq!(inner(cells, html(local_name!("tr"))), cells);
+        // In a body, the table tags are dropped
+        assert_eq!(inner(cells, html(local_name!("body"))), "ab");
+
+        assert_eq!(
+            inner("<col><col>x", html(local_name!("colgroup"))),
+            "<col><col>"
+        );
+        assert_eq!(inner("<col><col>x", html(local_name!("body"))), "x");
+        assert_eq!(
+            inner("<tr><td>x", html(local_name!("template"))),
+            "<tr><td>x</td></tr>"
+        );
+        assert_eq!(
+            inner("<b>&lt;</b>", html(local_name!("textarea"))),
+            "&lt;b&gt;&lt;&lt;/b&gt;"
+        );
+        // Raw text stays raw, as it would in the context element
+        for context in [
+            local_name!("script"),
+            local_name!("style"),
+            local_name!("xmp"),
+        ] {
+            assert_eq!(inner("a < b && c", html(context)), "a < b && c");
+        }
+
+        let input = t("<circle r=1/><foreignObject><p>x</p></foreignObject>");
+        let doc = parse_fragment(&input, QualName::new(None, ns!(svg), local_name!("svg")));
+        assert_eq!(doc.get(doc.root).ns, Namespace::Svg);
+        let circle = doc.first_child(doc.root).unwrap();
+        assert_eq!(doc.get(circle).ns, Namespace::Svg);
+        let p = doc.select_first("p").unwrap().unwrap();
+        assert_eq!(doc.get(p).ns, Namespace::Html);
+    }
+
+    #[test]
+    fn test_mutation_api() {
+        let html = t("<ul><li>a</li><li>b</li></ul><p>text <img src=x.png></p>");
+        let mut doc = parse(&html);
+        let ul = doc.select_first("ul").unwrap().unwrap();
+        let img = doc.select_first("img").unwrap().unwrap();
+
+        let copy = doc.deep_clone(ul);
+        let first = doc.first_child(copy).unwrap();
+        doc.set_text(doc.first_child(first).unwrap(), "z");
+        let li = doc.create_element("li");
+        doc.prepend(copy, li);
+        assert_eq!(
+            doc.serialize_inner_html(copy),
+            "<li></li><li>z</li><li>b</li>"
+        );
+
+        // Into the contents of a template, which stay its first child
+        let template = doc.create_element("template");
+        let b = doc.create_text("b");
+        let a = doc.create_text("a");
+        doc.prepend(template, b);
+        doc.prepend(template, a);
+        assert!(doc.template_contents(template).is_some());
+        assert_eq!(doc.serialize_inner_html(template), "ab");
+
+        let items = doc.take_children(ul);
+        assert_eq!(items.len(), 2);
+        let nav = doc.create_element("nav");
+        doc.replace_with(ul, nav);
+        let a = doc.create_element("a");
+        doc.wrap(img, a);
+        doc.unwrap(doc.select_first("p").unwrap().unwrap());
+        assert_eq!(
+            doc.to_body_html(),
+            r#"<nav></nav>text <a><img src="x.png"></a>"#
+        );
+    }
+
+    #[test]
+    fn test_set_inner_html() {
+        let html = t("<table><tr><td>old</td></tr></table><template></template>");
+        let mut doc = parse(&html);
+
+        let tr = doc.select_first("tr").unwrap().unwrap();
+        let old_td = doc.first_child(tr).unwrap();
+        let old_text = doc.first_child(old_td).unwrap();
+        doc.set_inner_html(tr, "<td>a</td><td>b</td>");
+        assert_eq!(doc.serialize_inner_html(tr), "<td>a</td><td>b</td>");
+        // Freed, and their slots reused for the new cells
+        assert!(old_td.is_removed(&doc.arena));
+        assert!(old_text.is_removed(&doc.arena));
+
+        let template = doc.select_first("template").unwrap().unwrap();
+        doc.set_inner_html(template, "<tr><td>x");
+        assert_eq!(doc.serialize_inner_html(template), "<tr><td>x</td></tr>");
+
+        let svg = doc.create_element_ns(Namespace::Svg, "svg");
+        doc.set_inner_html(svg, "<circle r=1></circle>");
+        let circle = doc.first_child(svg).unwrap();
+        assert_eq!(doc.get(circle).ns, Namespace::Svg);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let html = t("<p>a<b>b</b></p><template><i>x</i></template>");
+        let mut doc = parse(&html);
+        let p = doc.select_first("p").unwrap().unwrap();
+        let b = doc.select_first("b").unwrap().unwrap();
+        let empty = doc.create_text("");
+        doc.insert_before(b, empty);
+        let c = doc.create_text("c");
+        doc.insert_before(b, c);
+        doc.unwrap(b);
+        let template = doc.select_first("template").unwrap().unwrap();
+        let contents = doc.template_contents(template).unwrap();
+        let y = doc.create_text("y");
+        doc.prepend(contents, y);
+        let empty = doc.create_text("");
+        doc.prepend(contents, empty);
+
+        doc.normalize();
+        let texts = |doc: &Document<'_>, id| {
+            doc.children(id)
+                .map(|child| match &doc.get(child).kind {
+                    NodeKind::Text(text) => text.to_string(),
+                    _ => "<>".to_string(),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(texts(&doc, p), ["acb"]);
+        assert_eq!(texts(&doc, contents), ["y", "<>"]);
+
+        // Merged and dropped nodes are freed, which into_owned copes with
+        assert!(doc.arena[c].is_removed());
+        assert!(doc.arena[empty].is_removed());
+        let html = doc.to_html();
+        assert_eq!(doc.into_owned().to_html(), html);
+    }
 }
@@ -0,0 +1,302 @@
+//! Working out the character encoding of byte input, following the
+//! encoding sniffing algorithm.
+//! See: https://html.spec.whatwg.org/multipage/parsing.html#encoding-sniffing-algorithm
+
+use encoding_rs::{Encoding, UTF_8, UTF_16BE, UTF_16LE, WINDOWS_1252, X_USER_DEFINED};
+
+use crate::dom::ParseBytesOptions;
+
+/// How much of the input is searched for a `<meta>` declaring the encoding.
+const PRESCAN_LEN: usize = 1024;
+
+/// The encoding of `bytes`, and the length of the byte order mark it
+/// starts with, if any.
+pub(crate) fn sniff(bytes: &[u8], options: &ParseBytesOptions) -> (&'static Encoding, usize) {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(bytes) {
+        return (encoding, bom_len);
+    }
+    let encoding = options
+        .transport_encoding
+        .or_else(|| prescan(&bytes[..bytes.len().min(PRESCAN_LEN)]))
+        .unwrap_or(options.fallback);
+    (encoding, 0)
+}
+
+fn is_space(b: u8) -> bool {
+    matches!(b, b'\t' | b'\n' | b'\x0C' | b'\r' | b' ')
+}
+
+fn starts_with_ignore_case(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes
+        .get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+/// The encoding declared by a `<meta>` at the start of `bytes`, if any.
+/// See: https://html.spec.whatwg.org/multipage/parsing.html#prescan-a-byte-stream-to-determine-its-encoding
+fn prescan(bytes: &[u8]) -> Option<&'static Encoding> {
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let rest = &bytes[pos..];
+        if rest.starts_with(b"<!--") {
+            // The dashes of `<!--` may end it too
+            let end = find(&bytes[pos + 2..], b"-->")?;
+            pos += 2 + end + 3;
+            continue;
+        }
+        if starts_with_ignore_case(rest, b"<meta")
+            && rest.get(5).is_some_and(|&b| is_space(b) || b == b'/')
+        {
+            pos += 5;
+            if let Some(encoding) = meta_encoding(bytes, &mut pos)? {
+                return Some(encoding);
+            }
+            continue;
+        }
+        let tag_start = match rest {
+            [b'<', b, ..] if b.is_ascii_alphabetic() => Some(1),
+            [b'<', b'/', b, ..] if b.is_ascii_alphabetic() => Some(2),
+            _ => None,
+        };
+        if let Some(tag_start) = tag_start {
+            pos += tag_start;
+            while bytes.get(pos).is_some_and(|&b| !is_space(b) && b != b'>') {
+                pos += 1;
+            }
+            while attribute(bytes, &mut pos)?.is_some() {}
+        } else if rest.starts_with(b"<!") || rest.starts_with(b"</") || rest.starts_with(b"<?") {
+            pos += find(rest, b">")?;
+        }
+        pos += 1;
+    }
+    None
+}
+
+/// Read the attributes of a `<meta>` tag, for the encoding it declares.
+/// `None` if the input ends first.
+fn meta_encoding(bytes: &[u8], pos: &mut usize) -> Option<Option<&'static Encoding>> {
+    let mut seen: Vec<Vec<u8>> = Vec::new();
+    let mut got_pragma = false;
+    let mut need_pragma = None;
+    let mut charset = None;
+    while let Some((name, value)) = attribute(bytes, pos)? {
+        if seen.contains(&name) {
+            continue;
+        }
+        match name.as_slice() {
+            b"http-equiv" => got_pragma |= value == b"content-type",
+            b"content" if charset.is_none() => {
+                if let Some(encoding) = content_encoding(&value) {
+                    charset = Some(encoding);
+                    need_pragma = Some(true);
+                }
+            }
+            b"charset" if charset.is_none() => {
+                charset = Encoding::for_label(&value);
+                need_pragma = Some(false);
+            }
+            _ => {}
+        }
+        seen.push(name);
+    }
+    let declared = match need_pragma {
+        Some(true) if !got_pragma => None,
+        Some(_) => charset,
+        None => None,
+    };
+    // A document that could be read this far isn't UTF-16
+    Some(declared.map(|encoding| {
+        if encoding == UTF_16BE || encoding == UTF_16LE {
+            UTF_8
+        } else if encoding == X_USER_DEFINED {
+            WINDOWS_1252
+        } else {
+            encoding
+        }
+    }))
+}
+
+/// An attribute's name and value, lowercased.
+type Attribute = (Vec<u8>, Vec<u8>);
+
+/// The byte at `pos`, moving past it.
+fn next(bytes: &[u8], pos: &mut usize) -> Option<u8> {
+    let b = *bytes.get(*pos)?;
+    *pos += 1;
+    Some(b)
+}
+
+/// The next attribute of a tag, or `Some(None)` at the end of the tag.
+/// `None` if the input ends first.
+/// See: https://html.spec.whatwg.org/multipage/parsing.html#concept-get-attributes-when-sniffing
+fn attribute(bytes: &[u8], pos: &mut usize) -> Option<Option<Attribute>> {
+    let mut b = next(bytes, pos)?;
+    while is_space(b) || b == b'/' {
+        b = next(bytes, pos)?;
+    }
+    if b == b'>' {
+        *pos -= 1;
+        return Some(None);
+    }
+
+    let mut name = Vec::new();
+    let mut value = Vec::new();
+    loop {
+        match b {
+            b'=' if !name.is_empty() => break,
+            b'/' | b'>' => {
+                *pos -= 1;
+                return Some(Some((name, value)));
+            }
+            b if is_space(b) => {
+                while bytes.get(*pos).is_some_and(|&b| is_space(b)) {
+                    *pos += 1;
+                }
+                if bytes.get(*pos) != Some(&b'=') {
+                    return Some(Some((name, value)));
+                }
+                *pos += 1;
+                break;
+            }
+            b => name.push(b.to_ascii_lowercase()),
+        }
+        b = next(bytes, pos)?;
+    }
+
+    let mut b = next(bytes, pos)?;
+    while is_space(b) {
+        b = next(bytes, pos)?;
+    }
+    match b {
+        quote @ (b'"' | b'\'') => loop {
+            match next(bytes, pos)? {
+                b if b == quote => return Some(Some((name, value))),
+                b => value.push(b.to_ascii_lowercase()),
+            }
+        },
+        b'>' => {
+            *pos -= 1;
+            return Some(Some((name, value)));
+        }
+        b => value.push(b.to_ascii_lowercase()),
+    }
+    loop {
+        match *bytes.get(*pos)? {
+            b if is_space(b) || b == b'>' => return Some(Some((name, value))),
+            b => value.push(b.to_ascii_lowercase()),
+        }
+        *pos += 1;
+    }
+}
+
+/// The encoding named in a `content` attribute like `text/html; charset=utf-8`.
+/// See: https://html.spec.whatwg.org/multipage/urls-and-fetching.html#algorithm-for-extracting-a-character-encoding-from-a-meta-element
+fn content_encoding(content: &[u8]) -> Option<&'static Encoding> {
+    let mut pos = 0;
+    loop {
+        pos += find_ignore_case(&content[pos..], b"charset")? + b"charset".len();
+        while content.get(pos).is_some_and(|&b| is_space(b)) {
+            pos += 1;
+        }
+        if content.get(pos) == Some(&b'=') {
+            break;
+        }
+    }
+    pos += 1;
+    while content.get(pos).is_some_and(|&b| is_space(b)) {
+        pos += 1;
+    }
+    let rest = &content[pos..];
+    let label = match rest.first()? {
+        &quote @ (b'"' | b'\'') => &rest[1..1 + find(&rest[1..], &[quote])?],
+        _ => {
+            let end = rest
+                .iter()
+                .position(|&b| is_space(b) || b == b';')
+                .unwrap_or(rest.len());
+            &rest[..end]
+        }
+    };
+    Encoding::for_label(label)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn find_ignore_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{SHIFT_JIS, WINDOWS_1251};
+    use facet_testhelpers::test;
+
+    #[test]
+    fn test_prescan() {
+        let cases: &[(&[u8], Option<&'static Encoding>)] = &[
+            (b"<meta charset=shift_jis>", Some(SHIFT_JIS)),
+            (b"<META CHARSET='Shift_JIS'>", Some(SHIFT_JIS)),
+            (
+                b"<!DOCTYPE html><html lang=ja><head><meta charset=\"sjis\">",
+                Some(SHIFT_JIS),
+            ),
+            (
+                b"<meta http-equiv=Content-Type content=\"text/html; charset=windows-1251\">",
+                Some(WINDOWS_1251),
+            ),
+            // `content` only counts along with `http-equiv`
+            (b"<meta content=\"text/html; charset=windows-1251\">", None),
+            (
+                b"<meta content='charset=\"koi8-r\"' http-equiv=\"content-type\">",
+                Some(encoding_rs::KOI8_R),
+            ),
+            // Not in comments, attribute values or other tags
+            (b"<!-- <meta charset=shift_jis> --><p>", None),
+            (b"<!--><meta charset=shift_jis>", Some(SHIFT_JIS)),
+            (b"<div title='<meta charset=shift_jis>'>", None),
+            (b"<metadata charset=shift_jis>", None),
+            // A document read as ASCII this far isn't UTF-16
+            (b"<meta charset=utf-16le>", Some(UTF_8)),
+            (b"<meta charset=x-user-defined>", Some(WINDOWS_1252)),
+            (b"<meta charset=no-such-thing>", None),
+            (b"<meta charset=shift_jis", None),
+        ];
+        for &(input, expected) in cases {
+            assert_eq!(
+                prescan(input),
+                expected,
+                "{}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_sniff_order() {
+        let options = ParseBytesOptions::default();
+        let meta = b"<meta charset=shift_jis>";
+        assert_eq!(sniff(meta, &options), (SHIFT_JIS, 0));
+        assert_eq!(sniff(b"<p>", &options), (WINDOWS_1252, 0));
+
+        // A byte order mark wins over everything
+        let mut with_bom = b"\xEF\xBB\xBF".to_vec();
+        with_bom.extend_from_slice(meta);
+        let transport = ParseBytesOptions {
+            transport_encoding: Some(WINDOWS_1251),
+            ..ParseBytesOptions::default()
+        };
+        assert_eq!(sniff(&with_bom, &transport), (UTF_8, 3));
+        // Then the transport layer
+        assert_eq!(sniff(meta, &transport), (WINDOWS_1251, 0));
+
+        // Only the start of the input is searched
+        let mut late = vec![b' '; PRESCAN_LEN];
+        late.extend_from_slice(meta);
+        assert_eq!(sniff(&late, &options), (WINDOWS_1252, 0));
+    }
+}
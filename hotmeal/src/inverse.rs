@@ -0,0 +1,252 @@
+//! Recording inverse patch lists while patches are applied.
+//!
+//! The inverse list is replayed with its own slot map, independent of the
+//! slots used by the forward patches: every node the forward patches take
+//! out of the tree gets a slot of its own, and inverse patches address
+//! nodes either from the body/head or from one of those slots.
+
+use std::collections::HashMap;
+
+use cinereus::indextree::NodeId;
+use smallvec::{SmallVec, smallvec};
+
+use crate::diff::{HEAD_SLOT, NodePath, NodeRef, Patch, PropChange, PropKey, create_insert_patch};
+use crate::dom::{Document, NodeKind};
+
+/// Slot the inverse patches park nodes in when they don't need them again.
+const SCRATCH_SLOT: u32 = 1;
+
+/// Where a moved node came from, captured before the move.
+pub(crate) enum MoveOrigin {
+    /// It was replaced by a placeholder, found at this path right after.
+    Placeholder(NodePath),
+    /// It was a slot root taken out of the tree without a placeholder.
+    Attached(NodePath),
+    /// It was a detached slot root.
+    Detached,
+}
+
+/// What inserting a node did to its new parent.
+pub(crate) struct Inserted {
+    /// The inserted node.
+    pub node: NodeId,
+    /// The previous occupant of the position, moved into a slot.
+    pub displaced: Option<NodeId>,
+    /// Empty text nodes added before the node to reach its position.
+    pub padding: usize,
+}
+
+/// Builds the inverse of a patch list, one group of patches per forward patch.
+pub(crate) struct InverseRecorder<'a> {
+    body: NodeId,
+    head: Option<NodeId>,
+    /// Slot of every node that was, at some point, taken out of the tree
+    roots: HashMap<NodeId, u32>,
+    next_slot: u32,
+    groups: Vec<Vec<Patch<'a>>>,
+}
+
+impl<'a> InverseRecorder<'a> {
+    pub(crate) fn new(slots: &HashMap<u32, NodeId>) -> Self {
+        Self {
+            body: slots[&0],
+            head: slots.get(&HEAD_SLOT).copied(),
+            roots: HashMap::new(),
+            next_slot: SCRATCH_SLOT + 1,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Start recording the inverse of the next forward patch.
+    pub(crate) fn begin(&mut self) {
+        self.groups.push(Vec::new());
+    }
+
+    fn push(&mut self, patch: Patch<'a>) {
+        self.groups
+            .last_mut()
+            .expect("begin() must be called first")
+            .push(patch);
+    }
+
+    /// The inverse slot holding `node` while it is out of the tree.
+    fn slot_of(&mut self, node: NodeId) -> u32 {
+        *self.roots.entry(node).or_insert_with(|| {
+            let slot = self.next_slot;
+            self.next_slot += 1;
+            slot
+        })
+    }
+
+    /// Path of `node` as seen by the inverse patches.
+    pub(crate) fn path(&mut self, doc: &Document<'a>, node: NodeId) -> NodePath {
+        let mut rev: SmallVec<[u32; 16]> = SmallVec::new();
+        let mut current = node;
+        let slot = loop {
+            if current == self.body {
+                break 0;
+            }
+            if Some(current) == self.head {
+                break HEAD_SLOT;
+            }
+            let Some(parent) = doc.logical_parent(current) else {
+                break self.slot_of(current);
+            };
+            let position = doc.children(parent).position(|c| c == current).unwrap_or(0);
+            rev.push(position as u32);
+            current = parent;
+        };
+        rev.push(slot);
+        rev.reverse();
+        NodePath(rev)
+    }
+
+    /// Record how to restore the value (text or attributes) of `node`.
+    pub(crate) fn save_value(&mut self, doc: &Document<'a>, node: NodeId) {
+        let path = self.path(doc, node);
+        let patch = match &doc.get(node).kind {
+            NodeKind::Element(elem) => Patch::UpdateProps {
+                path,
+                changes: elem
+                    .attrs
+                    .iter()
+                    .map(|(name, value)| PropChange {
+                        name: PropKey::Attr(name.clone()),
+                        value: Some(value.clone()),
+                    })
+                    .collect(),
+            },
+            NodeKind::Text(text) | NodeKind::Comment(text) => Patch::SetText {
+                path,
+                text: text.clone(),
+            },
+            NodeKind::Document | NodeKind::TemplateContents => return,
+        };
+        self.push(patch);
+    }
+
+    /// Record how to restore the children of an opaque node.
+    pub(crate) fn save_opaque(&mut self, doc: &Document<'a>, node: NodeId) {
+        let path = self.path(doc, node);
+        let content = doc.serialize_inner_html(node).into();
+        self.push(Patch::OpaqueChanged { path, content });
+    }
+
+    /// Record how to undo an insertion. The inserted node goes to `stash`,
+    /// or is dropped if `None`.
+    pub(crate) fn undo_insert(&mut self, doc: &Document<'a>, ins: &Inserted, stash: Option<u32>) {
+        let path = self.path(doc, ins.node);
+        if let Some(displaced) = ins.displaced {
+            let slot = self.slot_of(displaced);
+            self.push(Patch::Move {
+                from: NodeRef(NodePath(smallvec![slot])),
+                to: NodeRef(path),
+                detach_to_slot: Some(stash.unwrap_or(SCRATCH_SLOT)),
+            });
+            return;
+        }
+
+        let position = path.0[path.0.len() - 1];
+        let parent = SmallVec::from_slice(&path.0[..path.0.len() - 1]);
+        self.push(Patch::Detach {
+            node: NodeRef(path),
+            to_slot: stash,
+        });
+        for pad in (position - ins.padding as u32..position).rev() {
+            let mut pad_path = parent.clone();
+            pad_path.push(pad);
+            self.push(Patch::Detach {
+                node: NodeRef(NodePath(pad_path)),
+                to_slot: None,
+            });
+        }
+    }
+
+    /// Record how to undo a move, given what the insertion at the target did.
+    pub(crate) fn undo_move(&mut self, doc: &Document<'a>, origin: MoveOrigin, ins: &Inserted) {
+        let stash = match origin {
+            MoveOrigin::Detached => self.slot_of(ins.node),
+            _ => SCRATCH_SLOT,
+        };
+        self.undo_insert(doc, ins, Some(stash));
+        let from = NodeRef(NodePath(smallvec![SCRATCH_SLOT]));
+        match origin {
+            MoveOrigin::Placeholder(placeholder) => self.push(Patch::Move {
+                from,
+                to: NodeRef(placeholder),
+                detach_to_slot: Some(SCRATCH_SLOT),
+            }),
+            MoveOrigin::Attached(before) => self.push(Patch::Move {
+                from,
+                to: NodeRef(before),
+                detach_to_slot: None,
+            }),
+            MoveOrigin::Detached => {}
+        }
+    }
+
+    /// Record how to put back a node that a move took out of the tree
+    /// before failing to insert it.
+    pub(crate) fn undo_failed_move(&mut self, origin: MoveOrigin, node: NodeId) {
+        match origin {
+            MoveOrigin::Placeholder(at) => self.undo_detach(node, at, true),
+            MoveOrigin::Attached(at) => self.undo_detach(node, at, false),
+            MoveOrigin::Detached => {}
+        }
+    }
+
+    /// Record how to undo taking `node` out of the tree. `at` is where it
+    /// goes back; with `replace`, the node now at `at` is dropped.
+    pub(crate) fn undo_detach(&mut self, node: NodeId, at: NodePath, replace: bool) {
+        let slot = self.slot_of(node);
+        self.push(Patch::Move {
+            from: NodeRef(NodePath(smallvec![slot])),
+            to: NodeRef(at),
+            detach_to_slot: replace.then_some(SCRATCH_SLOT),
+        });
+    }
+
+    /// The patches undoing everything recorded so far, to be applied to the
+    /// same document with the returned slots. Nodes out of the tree are
+    /// still in the arena, so they are handed over as slots instead of
+    /// being recreated, and keep their ids.
+    pub(crate) fn into_rollback(self) -> (Vec<Patch<'a>>, HashMap<u32, NodeId>) {
+        let mut slots: HashMap<u32, NodeId> = self
+            .roots
+            .iter()
+            .map(|(&node, &slot)| (slot, node))
+            .collect();
+        slots.insert(0, self.body);
+        if let Some(head) = self.head {
+            slots.insert(HEAD_SLOT, head);
+        }
+        (self.groups.into_iter().rev().flatten().collect(), slots)
+    }
+
+    /// The inverse patch list: first recreate the nodes that are out of the
+    /// tree now, then undo every forward patch, last one first.
+    pub(crate) fn finish(self, doc: &Document<'a>) -> Vec<Patch<'a>> {
+        let mut roots: Vec<_> = self
+            .roots
+            .iter()
+            .filter(|&(&node, _)| doc.arena[node].parent().is_none())
+            .map(|(&node, &slot)| (slot, node))
+            .collect();
+        roots.sort_unstable();
+
+        let mut patches = Vec::new();
+        let body_len = doc.children(self.body).count();
+        for (slot, node) in roots {
+            let Ok(insert) = create_insert_patch(doc, node, body_len) else {
+                continue;
+            };
+            patches.push(insert);
+            patches.push(Patch::Detach {
+                node: NodeRef(NodePath(smallvec![0, body_len as u32])),
+                to_slot: Some(slot),
+            });
+        }
+        patches.extend(self.groups.into_iter().rev().flatten());
+        patches
+    }
+}
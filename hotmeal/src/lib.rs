@@ -2,8 +2,10 @@
 //!
 //! hotmeal provides:
 //! - **Arena-based DOM**: Efficient arena-allocated tree with zero-copy parsing
-//! - **Parsing**: Browser-compatible HTML5 parsing via html5ever with full error recovery
-//! - **Serialization**: HTML5-correct serialization with proper escaping
+//! - **Parsing**: Browser-compatible HTML5 parsing via html5ever with full error recovery,
+//!   from strings or from bytes in any encoding
+//! - **Serialization**: HTML5-correct serialization with proper escaping, pretty-printed or minified via [`SerializeOptions`]
+//! - **Querying**: CSS selectors via [`Document::select`]
 //! - **Diffing**: DOM patch generation for live-reloading
 //!
 //! # Example
@@ -30,20 +32,34 @@
 
 mod diff;
 mod dom;
+mod encoding;
+mod inverse;
+mod select;
 #[cfg(any(test, feature = "tracing"))]
 mod shadow_tree_dump;
+mod span;
 mod stem;
 mod tracing_macros;
+mod validate;
 
 pub use cinereus::indextree::NodeId;
 pub use diff::{
-    AttrPair, DiffError, HtmlNodeKind, HtmlProps, HtmlTreeTypes, InsertContent, NodePath, NodeRef,
-    Patch, PropChange, PropKey, diff, diff_html,
+    AttrPair, DiffError, DiffOptions, HEAD_SLOT, HtmlNodeKind, HtmlProps, HtmlTreeTypes,
+    InsertContent, NodePath, NodeRef, Patch, PropChange, PropKey, WhitespaceMode, diff, diff_html,
+    diff_html_with_options, diff_subtrees, diff_subtrees_with_options, diff_with_options,
 };
-pub use dom::{Document, ElementData, Namespace, NodeData, NodeKind, parse, parse_body_fragment};
+pub use dom::{
+    Document, ElementData, Namespace, NodeData, NodeKind, ParseBytesOptions, ParseOptions,
+    SerializeOptions, parse, parse_body_fragment, parse_bytes, parse_bytes_with_options,
+    parse_fragment, parse_with_options,
+};
+pub use encoding_rs::{self, Encoding};
 pub use html5ever::{LocalName, QualName, local_name, namespace_url, ns};
+pub use select::{Selector, SelectorError};
+pub use span::{ParseError, Span};
 pub use stem::Stem;
 pub use tendril::StrTendril;
+pub use validate::PatchValidationError;
 
 const _: () = {
     const fn assert_send<T: Send>() {}
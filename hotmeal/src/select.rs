@@ -0,0 +1,957 @@
+//! CSS selectors, for querying a [`Document`].
+//!
+//! Supports selector lists, the four combinators (descendant, `>`, `+`, `~`),
+//! type, universal, class, id and attribute selectors (`=`, `~=`, `|=`, `^=`,
+//! `$=`, `*=`, with the `i` and `s` flags), and the structural pseudo-classes:
+//! `:not()`, `:is()`, `:where()`, `:nth-child(An+B [of S])`,
+//! `:nth-last-child()`, `:nth-of-type()`, `:nth-last-of-type()`, their
+//! `first-`/`last-`/`only-` shorthands, `:empty` and `:root`.
+
+use cinereus::indextree::NodeId;
+use facet::Facet;
+use html5ever::ns;
+
+use crate::dom::{Document, ElementData, Namespace, NodeKind};
+
+/// Errors from parsing a CSS selector.
+#[derive(Facet, Debug, Clone, PartialEq, Eq)]
+#[facet(derive(Error))]
+#[repr(u8)]
+pub enum SelectorError {
+    /// unexpected end of selector
+    UnexpectedEnd,
+
+    /// unexpected character {found:?} at offset {offset}
+    UnexpectedChar { found: char, offset: usize },
+
+    /// unsupported pseudo-class :{name} at offset {offset}
+    UnsupportedPseudoClass { name: String, offset: usize },
+
+    /// pseudo-elements cannot match nodes (offset {offset})
+    PseudoElement { offset: usize },
+
+    /// invalid An+B expression at offset {offset}
+    InvalidNth { offset: usize },
+}
+
+/// A parsed selector list, reusable across queries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    alternatives: Vec<Complex>,
+}
+
+/// Compound selectors joined by combinators, e.g. `ul > li.active`.
+#[derive(Debug, Clone, PartialEq)]
+struct Complex {
+    compounds: Vec<Compound>,
+    /// `combinators[i]` sits between `compounds[i]` and `compounds[i + 1]`
+    combinators: Vec<Combinator>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Combinator {
+    Descendant,
+    Child,
+    NextSibling,
+    SubsequentSibling,
+}
+
+/// Everything that must hold for a single element, e.g. `li.active[data-x]`.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Compound {
+    /// `None` for the universal selector (or no type selector at all)
+    tag: Option<Name>,
+    ids: Vec<String>,
+    classes: Vec<String>,
+    attrs: Vec<AttrSelector>,
+    pseudos: Vec<Pseudo>,
+}
+
+/// A name as written, and lowercased for matching HTML elements.
+#[derive(Debug, Clone, PartialEq)]
+struct Name {
+    written: String,
+    lower: String,
+}
+
+impl Name {
+    fn new(written: String) -> Self {
+        let lower = written.to_ascii_lowercase();
+        Self { written, lower }
+    }
+
+    /// HTML element and attribute names match case-insensitively, foreign ones don't.
+    fn matches(&self, ns: Namespace, name: &str) -> bool {
+        match ns {
+            Namespace::Html => self.lower == name,
+            _ => self.written == name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct AttrSelector {
+    name: Name,
+    test: Option<AttrTest>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct AttrTest {
+    op: AttrOp,
+    value: String,
+    case_insensitive: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AttrOp {
+    /// `=`
+    Equals,
+    /// `~=`
+    Includes,
+    /// `|=`
+    DashMatch,
+    /// `^=`
+    Prefix,
+    /// `$=`
+    Suffix,
+    /// `*=`
+    Substring,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Pseudo {
+    Not(Vec<Complex>),
+    /// `:is()` and `:where()`
+    Is(Vec<Complex>),
+    Nth(Nth),
+    /// `:only-child` and `:only-of-type`
+    Only {
+        of_type: bool,
+    },
+    Empty,
+    Root,
+}
+
+/// An `:nth-*` pseudo-class: matches the elements whose 1-based index is `a*n + b`.
+#[derive(Debug, Clone, PartialEq)]
+struct Nth {
+    a: i64,
+    b: i64,
+    from_end: bool,
+    of_type: bool,
+    /// Only count siblings matching these (`:nth-child(2 of .x)`)
+    of: Option<Vec<Complex>>,
+}
+
+impl Nth {
+    fn matches_index(&self, index: i64) -> bool {
+        if self.a == 0 {
+            return index == self.b;
+        }
+        // Widened, so that extreme `a` and `b` can't overflow
+        let (a, diff) = (i128::from(self.a), i128::from(index) - i128::from(self.b));
+        diff % a == 0 && diff / a >= 0
+    }
+}
+
+impl Selector {
+    /// Parse a selector list, like `ul > li.active, a[href^="https:"]`.
+    pub fn parse(selector: &str) -> Result<Self, SelectorError> {
+        let mut parser = Parser {
+            src: selector,
+            pos: 0,
+        };
+        let alternatives = parser.selector_list(false)?;
+        Ok(Self { alternatives })
+    }
+
+    /// Whether `node` is an element matching this selector.
+    pub fn matches(&self, doc: &Document<'_>, node: NodeId) -> bool {
+        matches_any(&self.alternatives, doc, node)
+    }
+
+    /// The elements of `doc` matching this selector, in document order.
+    ///
+    /// Like `querySelectorAll`, this doesn't look inside `<template>` contents.
+    pub fn select<'d>(&'d self, doc: &'d Document<'_>) -> impl Iterator<Item = NodeId> + 'd {
+        let mut stack = vec![doc.root];
+        std::iter::from_fn(move || {
+            while let Some(node) = stack.pop() {
+                stack.extend(
+                    node.children(&doc.arena)
+                        .rev()
+                        .filter(|&c| !matches!(doc.get(c).kind, NodeKind::TemplateContents)),
+                );
+                if self.matches(doc, node) {
+                    return Some(node);
+                }
+            }
+            None
+        })
+    }
+}
+
+impl std::str::FromStr for Selector {
+    type Err = SelectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+// ==================== Matching ====================
+
+fn element<'d>(doc: &'d Document<'_>, node: NodeId) -> Option<&'d ElementData<'d>> {
+    match &doc.get(node).kind {
+        NodeKind::Element(elem) => Some(elem),
+        _ => None,
+    }
+}
+
+fn parent_element(doc: &Document<'_>, node: NodeId) -> Option<NodeId> {
+    doc.parent(node).filter(|&p| element(doc, p).is_some())
+}
+
+fn prev_element(doc: &Document<'_>, node: NodeId) -> Option<NodeId> {
+    let mut current = doc.prev_sibling(node)?;
+    loop {
+        if element(doc, current).is_some() {
+            return Some(current);
+        }
+        current = doc.prev_sibling(current)?;
+    }
+}
+
+fn matches_any(alternatives: &[Complex], doc: &Document<'_>, node: NodeId) -> bool {
+    alternatives
+        .iter()
+        .any(|complex| matches_complex(complex, complex.compounds.len() - 1, doc, node))
+}
+
+/// Match `compounds[..=idx]` right to left, with `compounds[idx]` on `node`.
+fn matches_complex(complex: &Complex, idx: usize, doc: &Document<'_>, node: NodeId) -> bool {
+    if !matches_compound(&complex.compounds[idx], doc, node) {
+        return false;
+    }
+    if idx == 0 {
+        return true;
+    }
+    let next = |candidate| matches_complex(complex, idx - 1, doc, candidate);
+    match complex.combinators[idx - 1] {
+        Combinator::Child => parent_element(doc, node).is_some_and(next),
+        Combinator::Descendant => {
+            std::iter::successors(parent_element(doc, node), |&n| parent_element(doc, n)).any(next)
+        }
+        Combinator::NextSibling => prev_element(doc, node).is_some_and(next),
+        Combinator::SubsequentSibling => {
+            std::iter::successors(prev_element(doc, node), |&n| prev_element(doc, n)).any(next)
+        }
+    }
+}
+
+fn matches_compound(compound: &Compound, doc: &Document<'_>, node: NodeId) -> bool {
+    let data = doc.get(node);
+    let NodeKind::Element(elem) = &data.kind else {
+        return false;
+    };
+    if let Some(tag) = &compound.tag
+        && !tag.matches(data.ns, &elem.tag)
+    {
+        return false;
+    }
+
+    let attr = |name: &str| {
+        elem.attrs
+            .iter()
+            .find(|(k, _)| k.ns == ns!() && &*k.local == name)
+            .map(|(_, v)| v.as_ref())
+    };
+    if !compound
+        .ids
+        .iter()
+        .all(|id| attr("id") == Some(id.as_str()))
+    {
+        return false;
+    }
+    if !compound.classes.is_empty() {
+        let classes = attr("class").unwrap_or_default();
+        let has_class = |class: &String| classes.split_ascii_whitespace().any(|c| c == class);
+        if !compound.classes.iter().all(has_class) {
+            return false;
+        }
+    }
+
+    compound
+        .attrs
+        .iter()
+        .all(|sel| matches_attr(sel, data.ns, elem))
+        && compound
+            .pseudos
+            .iter()
+            .all(|pseudo| matches_pseudo(pseudo, doc, node))
+}
+
+fn matches_attr(sel: &AttrSelector, ns: Namespace, elem: &ElementData<'_>) -> bool {
+    let Some((_, value)) = elem
+        .attrs
+        .iter()
+        .find(|(k, _)| k.ns == ns!() && sel.name.matches(ns, &k.local))
+    else {
+        return false;
+    };
+    let Some(test) = &sel.test else {
+        return true;
+    };
+
+    let (value, expected) = if test.case_insensitive {
+        (value.to_ascii_lowercase(), test.value.to_ascii_lowercase())
+    } else {
+        (value.to_string(), test.value.clone())
+    };
+    match test.op {
+        AttrOp::Equals => value == expected,
+        AttrOp::Includes => {
+            !expected.is_empty() && value.split_ascii_whitespace().any(|v| v == expected)
+        }
+        AttrOp::DashMatch => {
+            value == expected
+                || value
+                    .strip_prefix(expected.as_str())
+                    .is_some_and(|rest| rest.starts_with('-'))
+        }
+        AttrOp::Prefix => !expected.is_empty() && value.starts_with(&expected),
+        AttrOp::Suffix => !expected.is_empty() && value.ends_with(&expected),
+        AttrOp::Substring => !expected.is_empty() && value.contains(&expected),
+    }
+}
+
+fn matches_pseudo(pseudo: &Pseudo, doc: &Document<'_>, node: NodeId) -> bool {
+    match pseudo {
+        Pseudo::Not(list) => !matches_any(list, doc, node),
+        Pseudo::Is(list) => matches_any(list, doc, node),
+        Pseudo::Nth(nth) => {
+            if let Some(of) = &nth.of
+                && !matches_any(of, doc, node)
+            {
+                return false;
+            }
+            let counted = |sibling| {
+                if nth.of_type {
+                    same_type(doc, node, sibling)
+                } else if let Some(of) = &nth.of {
+                    matches_any(of, doc, sibling)
+                } else {
+                    true
+                }
+            };
+            nth.matches_index(sibling_index(doc, node, nth.from_end, counted))
+        }
+        Pseudo::Only { of_type } => {
+            let counted = |sibling| !*of_type || same_type(doc, node, sibling);
+            sibling_index(doc, node, false, counted) == 1
+                && sibling_index(doc, node, true, counted) == 1
+        }
+        Pseudo::Empty => doc.children(node).all(|child| match &doc.get(child).kind {
+            NodeKind::Text(text) => text.is_empty(),
+            NodeKind::Comment(_) => true,
+            _ => false,
+        }),
+        Pseudo::Root => doc
+            .parent(node)
+            .is_some_and(|p| matches!(doc.get(p).kind, NodeKind::Document)),
+    }
+}
+
+/// 1-based index of `node` among its element siblings for which `counted` holds.
+fn sibling_index(
+    doc: &Document<'_>,
+    node: NodeId,
+    from_end: bool,
+    counted: impl Fn(NodeId) -> bool,
+) -> i64 {
+    let step = |&n: &NodeId| {
+        if from_end {
+            doc.next_sibling(n)
+        } else {
+            doc.prev_sibling(n)
+        }
+    };
+    1 + std::iter::successors(step(&node), step)
+        .filter(|&n| element(doc, n).is_some() && counted(n))
+        .count() as i64
+}
+
+fn same_type(doc: &Document<'_>, a: NodeId, b: NodeId) -> bool {
+    let (a, b) = (doc.get(a), doc.get(b));
+    match (&a.kind, &b.kind) {
+        (NodeKind::Element(x), NodeKind::Element(y)) => a.ns == b.ns && x.tag == y.tag,
+        _ => false,
+    }
+}
+
+// ==================== Parsing ====================
+
+struct Parser<'s> {
+    src: &'s str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.src[self.pos..].chars().nth(n)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), SelectorError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    /// Skip whitespace, returning whether there was any.
+    fn skip_ws(&mut self) -> bool {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    fn unexpected(&self) -> SelectorError {
+        match self.peek() {
+            Some(found) => SelectorError::UnexpectedChar {
+                found,
+                offset: self.pos,
+            },
+            None => SelectorError::UnexpectedEnd,
+        }
+    }
+
+    /// A comma-separated list of complex selectors, up to the end of the
+    /// input, or up to a `)` if `nested`.
+    fn selector_list(&mut self, nested: bool) -> Result<Vec<Complex>, SelectorError> {
+        let mut list = Vec::new();
+        loop {
+            self.skip_ws();
+            list.push(self.complex()?);
+            self.skip_ws();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(')') if nested => return Ok(list),
+                None if !nested => return Ok(list),
+                _ => return Err(self.unexpected()),
+            }
+        }
+    }
+
+    fn complex(&mut self) -> Result<Complex, SelectorError> {
+        let mut compounds = vec![self.compound()?];
+        let mut combinators = Vec::new();
+        loop {
+            let had_ws = self.skip_ws();
+            let combinator = match self.peek() {
+                Some('>') => Combinator::Child,
+                Some('+') => Combinator::NextSibling,
+                Some('~') => Combinator::SubsequentSibling,
+                Some(',' | ')') | None => {
+                    return Ok(Complex {
+                        compounds,
+                        combinators,
+                    });
+                }
+                Some(_) if had_ws => Combinator::Descendant,
+                Some(_) => return Err(self.unexpected()),
+            };
+            if combinator != Combinator::Descendant {
+                self.pos += 1;
+                self.skip_ws();
+            }
+            combinators.push(combinator);
+            compounds.push(self.compound()?);
+        }
+    }
+
+    fn compound(&mut self) -> Result<Compound, SelectorError> {
+        let start = self.pos;
+        let mut compound = Compound::default();
+        if self.eat('*') {
+            // Universal: no constraint
+        } else if self.at_ident() {
+            compound.tag = Some(Name::new(self.ident()?));
+        }
+
+        loop {
+            match self.peek() {
+                Some('#') => {
+                    self.pos += 1;
+                    compound.ids.push(self.ident()?);
+                }
+                Some('.') => {
+                    self.pos += 1;
+                    compound.classes.push(self.ident()?);
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    compound.attrs.push(self.attr()?);
+                }
+                Some(':') => {
+                    self.pos += 1;
+                    compound.pseudos.push(self.pseudo()?);
+                }
+                _ => break,
+            }
+        }
+
+        if self.pos == start {
+            return Err(self.unexpected());
+        }
+        Ok(compound)
+    }
+
+    fn attr(&mut self) -> Result<AttrSelector, SelectorError> {
+        self.skip_ws();
+        let name = Name::new(self.ident()?);
+        self.skip_ws();
+        let op = match self.peek() {
+            Some(']') => {
+                self.pos += 1;
+                return Ok(AttrSelector { name, test: None });
+            }
+            Some('=') => AttrOp::Equals,
+            Some('~') => AttrOp::Includes,
+            Some('|') => AttrOp::DashMatch,
+            Some('^') => AttrOp::Prefix,
+            Some('$') => AttrOp::Suffix,
+            Some('*') => AttrOp::Substring,
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        if op != AttrOp::Equals {
+            self.expect('=')?;
+        }
+        self.skip_ws();
+        let value = match self.peek() {
+            Some(quote @ ('"' | '\'')) => {
+                self.pos += 1;
+                self.string(quote)?
+            }
+            _ => self.ident()?,
+        };
+        self.skip_ws();
+        let case_insensitive = match self.peek() {
+            Some('i' | 'I') => true,
+            Some('s' | 'S') => false,
+            _ => {
+                self.expect(']')?;
+                return Ok(AttrSelector {
+                    name,
+                    test: Some(AttrTest {
+                        op,
+                        value,
+                        case_insensitive: false,
+                    }),
+                });
+            }
+        };
+        self.pos += 1;
+        self.skip_ws();
+        self.expect(']')?;
+        Ok(AttrSelector {
+            name,
+            test: Some(AttrTest {
+                op,
+                value,
+                case_insensitive,
+            }),
+        })
+    }
+
+    fn pseudo(&mut self) -> Result<Pseudo, SelectorError> {
+        let start = self.pos - 1;
+        if self.peek() == Some(':') {
+            return Err(SelectorError::PseudoElement { offset: start });
+        }
+        let name = self.ident()?.to_ascii_lowercase();
+        let nth = |a, b, from_end, of_type| {
+            Pseudo::Nth(Nth {
+                a,
+                b,
+                from_end,
+                of_type,
+                of: None,
+            })
+        };
+        let pseudo = match name.as_str() {
+            "first-child" => nth(0, 1, false, false),
+            "last-child" => nth(0, 1, true, false),
+            "only-child" => Pseudo::Only { of_type: false },
+            "first-of-type" => nth(0, 1, false, true),
+            "last-of-type" => nth(0, 1, true, true),
+            "only-of-type" => Pseudo::Only { of_type: true },
+            "empty" => Pseudo::Empty,
+            "root" => Pseudo::Root,
+            _ if self.peek() == Some('(') => {
+                self.pos += 1;
+                let pseudo = match name.as_str() {
+                    "not" => Pseudo::Not(self.selector_list(true)?),
+                    "is" | "where" => Pseudo::Is(self.selector_list(true)?),
+                    "nth-child" | "nth-last-child" | "nth-of-type" | "nth-last-of-type" => {
+                        let (a, b) = self.nth()?;
+                        let of_type = name.ends_with("of-type");
+                        let of = if !of_type && self.skip_ws() && self.keyword("of") {
+                            Some(self.selector_list(true)?)
+                        } else {
+                            None
+                        };
+                        Pseudo::Nth(Nth {
+                            a,
+                            b,
+                            from_end: name.starts_with("nth-last"),
+                            of_type,
+                            of,
+                        })
+                    }
+                    _ => {
+                        return Err(SelectorError::UnsupportedPseudoClass {
+                            name,
+                            offset: start,
+                        });
+                    }
+                };
+                self.skip_ws();
+                self.expect(')')?;
+                pseudo
+            }
+            _ => {
+                return Err(SelectorError::UnsupportedPseudoClass {
+                    name,
+                    offset: start,
+                });
+            }
+        };
+        Ok(pseudo)
+    }
+
+    /// Consume `word` (ASCII case-insensitively) if it comes next as a whole identifier.
+    fn keyword(&mut self, word: &str) -> bool {
+        let rest = &self.src[self.pos..];
+        let matches = rest.len() >= word.len()
+            && rest.is_char_boundary(word.len())
+            && rest[..word.len()].eq_ignore_ascii_case(word)
+            && !rest[word.len()..]
+                .chars()
+                .next()
+                .is_some_and(|c| is_name_char(c) || c == '\\');
+        if matches {
+            self.pos += word.len();
+        }
+        matches
+    }
+
+    /// An `An+B` expression, or `odd` / `even`.
+    fn nth(&mut self) -> Result<(i64, i64), SelectorError> {
+        self.skip_ws();
+        let offset = self.pos;
+        let invalid = SelectorError::InvalidNth { offset };
+        if self.keyword("odd") {
+            return Ok((2, 1));
+        }
+        if self.keyword("even") {
+            return Ok((2, 0));
+        }
+
+        let sign = |p: &mut Self| {
+            if p.eat('-') {
+                -1
+            } else {
+                p.eat('+');
+                1
+            }
+        };
+        let a_sign = sign(self);
+        let a_digits = self.digits();
+        if !self.eat('n') && !self.eat('N') {
+            return a_digits.map(|b| (0, a_sign * b)).ok_or(invalid);
+        }
+        let a = a_sign * a_digits.unwrap_or(1);
+
+        self.skip_ws();
+        if !matches!(self.peek(), Some('+' | '-')) {
+            return Ok((a, 0));
+        }
+        let b_sign = sign(self);
+        self.skip_ws();
+        let b = self.digits().ok_or(invalid)?;
+        Ok((a, b_sign * b))
+    }
+
+    fn digits(&mut self) -> Option<i64> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.src[start..self.pos].parse().ok()
+    }
+
+    fn at_ident(&self) -> bool {
+        match self.peek() {
+            Some('-') => self
+                .peek_at(1)
+                .is_some_and(|c| is_name_start(c) || c == '-' || c == '\\'),
+            Some('\\') => true,
+            Some(c) => is_name_start(c),
+            None => false,
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, SelectorError> {
+        if !self.at_ident() {
+            return Err(self.unexpected());
+        }
+        let mut ident = String::new();
+        while let Some(c) = self.peek() {
+            if c == '\\' {
+                self.pos += 1;
+                ident.push(self.escape()?);
+            } else if is_name_char(c) {
+                self.pos += c.len_utf8();
+                ident.push(c);
+            } else {
+                break;
+            }
+        }
+        Ok(ident)
+    }
+
+    fn string(&mut self, quote: char) -> Result<String, SelectorError> {
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                None => return Err(SelectorError::UnexpectedEnd),
+                Some(c) if c == quote => return Ok(value),
+                Some('\\') if self.eat('\n') => {}
+                Some('\\') => value.push(self.escape()?),
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    /// The character after a backslash: up to six hex digits and an optional
+    /// whitespace, or any other character taken literally.
+    fn escape(&mut self) -> Result<char, SelectorError> {
+        let start = self.pos;
+        while self.pos - start < 6 && self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return self.bump().ok_or(SelectorError::UnexpectedEnd);
+        }
+        let code = u32::from_str_radix(&self.src[start..self.pos], 16).unwrap_or(0);
+        self.eat(' ');
+        Ok(char::from_u32(code)
+            .filter(|&c| c != '\0')
+            .unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || !c.is_ascii()
+}
+
+fn is_name_char(c: char) -> bool {
+    is_name_start(c) || c.is_ascii_digit() || c == '-'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use facet_testhelpers::test;
+    use tendril::StrTendril;
+
+    /// Ids of the matches, taken from their `id` attributes
+    fn ids(html: &str, selector: &str) -> Vec<String> {
+        let html = StrTendril::from(html);
+        let doc = parse(&html);
+        doc.select(selector)
+            .unwrap()
+            .into_iter()
+            .map(|node| {
+                let NodeKind::Element(elem) = &doc.get(node).kind else {
+                    panic!("matched a non-element");
+                };
+                elem.attrs
+                    .iter()
+                    .find(|(k, _)| &*k.local == "id")
+                    .map(|(_, v)| v.to_string())
+                    .unwrap_or_else(|| elem.tag.to_string())
+            })
+            .collect()
+    }
+
+    const LIST: &str = r#"<html><body>
+        <ul id="u">
+          <li id="a" class="item active" data-x="1">A</li>
+          <li id="b" class="item">B</li>
+          <!-- comment -->
+          <li id="c" class="item active" lang="en-US">C</li>
+          <li id="d" data-x="">D</li>
+        </ul>
+        <ol id="o"><li id="e" class="active"><span id="s"></span></li></ol>
+        </body></html>"#;
+
+    #[test]
+    fn test_combinators() {
+        assert_eq!(ids(LIST, "ul > li.active[data-x]"), ["a"]);
+        assert_eq!(ids(LIST, "body li.active"), ["a", "c", "e"]);
+        assert_eq!(ids(LIST, "ul li span, ol li span"), ["s"]);
+        assert_eq!(ids(LIST, "#a + li"), ["b"]);
+        assert_eq!(ids(LIST, "#b + li"), ["c"]);
+        assert_eq!(ids(LIST, "#b ~ li"), ["c", "d"]);
+        assert_eq!(ids(LIST, "ul>li:not(.item)"), ["d"]);
+        assert_eq!(ids(LIST, "html > body > ol span"), ["s"]);
+    }
+
+    #[test]
+    fn test_attribute_operators() {
+        assert_eq!(ids(LIST, "[data-x]"), ["a", "d"]);
+        assert_eq!(ids(LIST, "[data-x=\"1\"]"), ["a"]);
+        assert_eq!(ids(LIST, "[class~=active]"), ["a", "c", "e"]);
+        assert_eq!(ids(LIST, "[lang|=en]"), ["c"]);
+        assert_eq!(ids(LIST, "[lang|=en-us i]"), ["c"]);
+        assert_eq!(ids(LIST, "[lang|=en-us]"), Vec::<String>::new());
+        assert_eq!(ids(LIST, "li[class^='item a']"), ["a", "c"]);
+        assert_eq!(ids(LIST, "li[class$=em]"), ["b"]);
+        assert_eq!(ids(LIST, "li[class*=\"m a\"]"), ["a", "c"]);
+        assert_eq!(ids(LIST, "[data-x^='']"), Vec::<String>::new());
+        assert_eq!(ids(LIST, "LI[ID=B]"), Vec::<String>::new());
+        assert_eq!(ids(LIST, "LI[ID=b]"), ["b"]);
+    }
+
+    #[test]
+    fn test_structural_pseudo_classes() {
+        assert_eq!(ids(LIST, "ul > :first-child"), ["a"]);
+        assert_eq!(ids(LIST, "ul > :last-child"), ["d"]);
+        assert_eq!(ids(LIST, "li:nth-child(2n+1)"), ["a", "c", "e"]);
+        assert_eq!(ids(LIST, "li:nth-child(even)"), ["b", "d"]);
+        assert_eq!(ids(LIST, "li:nth-child(-n + 2)"), ["a", "b", "e"]);
+        assert_eq!(ids(LIST, "li:nth-last-child(1)"), ["d", "e"]);
+        assert_eq!(ids(LIST, "li:nth-child(2 of .active)"), ["c"]);
+        assert_eq!(ids(LIST, "li:only-child"), ["e"]);
+        assert_eq!(ids(LIST, "ul :nth-of-type(3)"), ["c"]);
+        assert_eq!(ids(LIST, "span:empty"), ["s"]);
+        assert_eq!(ids(LIST, ":root"), ["html"]);
+        assert_eq!(ids(LIST, "li:is(#a, #e):where(.active)"), ["a", "e"]);
+        assert_eq!(ids(LIST, "li:not(:nth-child(odd), [data-x])"), ["b"]);
+    }
+
+    #[test]
+    fn test_nth_extreme_coefficients() {
+        let none = Vec::<String>::new();
+        assert_eq!(ids(LIST, "li:nth-child(-n-9223372036854775807)"), none);
+        assert_eq!(
+            ids(LIST, "li:nth-child(n-9223372036854775807)"),
+            ids(LIST, "li")
+        );
+        assert_eq!(
+            ids(LIST, "li:nth-child(-n+9223372036854775807)"),
+            ids(LIST, "li")
+        );
+        assert_eq!(ids(LIST, "li:nth-child(-9223372036854775807n)"), none);
+        assert_eq!(ids(LIST, "li:nth-child(9223372036854775807)"), none);
+    }
+
+    #[test]
+    fn test_select_first_and_template_contents() {
+        let html = StrTendril::from(
+            "<html><body><template><p id=\"t\"></p></template><p id=\"x\"></p></body></html>",
+        );
+        let doc = parse(&html);
+        let first = doc.select_first("p").unwrap().unwrap();
+        assert_eq!(doc.select("p").unwrap(), [first]);
+        assert_eq!(doc.select_first("div").unwrap(), None);
+
+        let selector: Selector = "body > p".parse().unwrap();
+        assert!(selector.matches(&doc, first));
+        assert!(!selector.matches(&doc, doc.body().unwrap()));
+
+        // Template contents still see the template as their parent
+        let template = doc.children(doc.body().unwrap()).next().unwrap();
+        let inner = doc.children(template).next().unwrap();
+        let selector: Selector = "template > p".parse().unwrap();
+        assert!(selector.matches(&doc, inner));
+    }
+
+    #[test]
+    fn test_empty_template() {
+        assert_eq!(
+            ids(
+                "<html><body><template id=e></template><template id=f><p></p></template></body></html>",
+                "template:empty"
+            ),
+            ["e"]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Selector::parse("").unwrap_err(),
+            SelectorError::UnexpectedEnd
+        );
+        assert_eq!(
+            Selector::parse("ul >").unwrap_err(),
+            SelectorError::UnexpectedEnd
+        );
+        assert_eq!(
+            Selector::parse("a, ,b").unwrap_err().to_string(),
+            "unexpected character ',' at offset 3"
+        );
+        assert_eq!(
+            Selector::parse("a, ,b").unwrap_err(),
+            SelectorError::UnexpectedChar {
+                found: ',',
+                offset: 3
+            }
+        );
+        assert_eq!(
+            Selector::parse("p:hover").unwrap_err(),
+            SelectorError::UnsupportedPseudoClass {
+                name: "hover".into(),
+                offset: 1
+            }
+        );
+        assert_eq!(
+            Selector::parse("p::before").unwrap_err(),
+            SelectorError::PseudoElement { offset: 1 }
+        );
+        assert_eq!(
+            Selector::parse("li:nth-child(x)").unwrap_err(),
+            SelectorError::InvalidNth { offset: 13 }
+        );
+        assert_eq!(
+            Selector::parse("[a=b").unwrap_err(),
+            SelectorError::UnexpectedEnd
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! Source locations for parsed nodes and parse errors.
+//!
+//! html5ever only reports line numbers, so with [`ParseOptions::spans`] the
+//! input is fed to the parser in chunks that end right after each `>`: every
+//! tag then ends a chunk, and the chunk being parsed tells where the tag (or
+//! the text before it) came from.
+//!
+//! [`ParseOptions::spans`]: crate::ParseOptions::spans
+
+use std::borrow::Cow;
+use std::cell::Cell;
+
+use tendril::StrTendril;
+
+/// A byte range into the parsed input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Span {
+    /// 1-based line and column (in chars) of the start of this span in `source`,
+    /// the input it was parsed from.
+    pub fn line_col(&self, source: &str) -> (usize, usize) {
+        let before = &source[..(self.start as usize).min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (line, before[line_start..].chars().count() + 1)
+    }
+
+    /// The smallest span covering both `self` and `other`.
+    pub fn cover(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+/// An error html5ever recovered from while parsing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// What went wrong
+    pub message: Cow<'static, str>,
+    /// 1-based line the parser was on
+    pub line: u64,
+    /// The input being parsed when the error came up, if spans were requested
+    pub span: Option<Span>,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Keeps track of where in the input the parser is, for the tree sink.
+///
+/// Positions are in the input actually fed to html5ever, which may have a
+/// prefix (like an added doctype) in front of the caller's input; spans
+/// handed out are relative to the caller's input.
+pub(crate) struct SpanTracker {
+    fed: StrTendril,
+    prefix: u32,
+    len: u32,
+    /// The chunk being parsed
+    chunk: Cell<(u32, u32)>,
+    /// Start of the last start tag found, so searches never go back further
+    last_tag_start: Cell<u32>,
+}
+
+impl SpanTracker {
+    /// `fed` is the whole input given to html5ever, made of `prefix` bytes
+    /// the caller didn't write, then their `len` bytes.
+    pub(crate) fn new(fed: StrTendril, prefix: u32, len: u32) -> Self {
+        Self {
+            fed,
+            prefix,
+            len,
+            chunk: Cell::new((0, 0)),
+            last_tag_start: Cell::new(0),
+        }
+    }
+
+    /// Record that html5ever is now parsing `start..end` of the fed input.
+    pub(crate) fn set_chunk(&self, start: u32, end: u32) {
+        self.chunk.set((start, end));
+    }
+
+    /// Convert a span of the fed input to one of the caller's input, if it overlaps.
+    fn to_source(&self, start: u32, end: u32) -> Option<Span> {
+        let source_end = self.prefix + self.len;
+        if end <= self.prefix || start >= source_end {
+            return None;
+        }
+        Some(Span {
+            start: start.max(self.prefix) - self.prefix,
+            end: end.min(source_end) - self.prefix,
+        })
+    }
+
+    /// The chunk being parsed.
+    pub(crate) fn chunk(&self) -> Option<Span> {
+        let (start, end) = self.chunk.get();
+        self.to_source(start, end)
+    }
+
+    /// Where `text` comes from: exact if it points into the input, else the
+    /// text at the start of the current chunk (up to its tag, if any).
+    pub(crate) fn text(&self, text: &StrTendril) -> Option<Span> {
+        let base = self.fed.as_ptr() as usize;
+        let ptr = text.as_ptr() as usize;
+        if ptr >= base && ptr + text.len() <= base + self.fed.len() {
+            let start = (ptr - base) as u32;
+            return self.to_source(start, start + text.len32());
+        }
+        let (start, end) = self.chunk.get();
+        let chunk = &self.fed.as_bytes()[start as usize..end as usize];
+        let text_end = chunk
+            .iter()
+            .position(|&b| b == b'<')
+            .map_or(end, |i| start + i as u32);
+        self.to_source(start, text_end.max(start + 1))
+    }
+
+    /// The start tag named `name` that ends the current chunk, if any.
+    ///
+    /// Elements the parser creates on its own (an implied `<tbody>`, say)
+    /// have no start tag of their own and get no span.
+    pub(crate) fn start_tag(&self, name: &str) -> Option<Span> {
+        let bytes = self.fed.as_bytes();
+        let end = self.chunk.get().1 as usize;
+        if end == 0 || bytes[end - 1] != b'>' {
+            return None;
+        }
+        let floor = self.last_tag_start.get() as usize;
+        // Try every `<`, as attribute values may contain some
+        let mut candidates = bytes[floor..end].iter().enumerate().rev();
+        let start = candidates.find_map(|(i, &b)| {
+            let start = floor + i;
+            (b == b'<' && start_tag_end(bytes, start, name) == Some(end)).then_some(start)
+        })?;
+        // The same tag may create several elements (a declarative shadow
+        // root's `<template>`, say), so it stays within reach
+        self.last_tag_start.set(start as u32);
+        self.to_source(start as u32, end as u32)
+    }
+
+    /// The comment with `len` bytes of text that ends the current chunk, if any.
+    pub(crate) fn comment(&self, len: usize) -> Option<Span> {
+        let input: &str = &self.fed;
+        let end = self.chunk.get().1 as usize;
+        let start = end.checked_sub(len + "<!---->".len())?;
+        let matches = input[..end].ends_with("-->") && input.get(start..start + 4) == Some("<!--");
+        if matches {
+            self.to_source(start as u32, end as u32)
+        } else {
+            None
+        }
+    }
+}
+
+/// Split `input` right after each `>`.
+pub(crate) fn chunks(input: &str) -> impl Iterator<Item = (u32, u32)> + '_ {
+    let len = input.len() as u32;
+    let ends = input
+        .bytes()
+        .enumerate()
+        .filter(|&(_, b)| b == b'>')
+        .map(|(i, _)| i as u32 + 1)
+        .filter(move |&end| end < len)
+        .chain(std::iter::once(len));
+    let mut start = 0;
+    ends.map(move |end| {
+        let chunk = (start, end);
+        start = end;
+        chunk
+    })
+}
+
+/// Where the start tag `<name ...>` beginning at `start` ends, following
+/// the tokenizer's rules for attributes.
+fn start_tag_end(bytes: &[u8], start: usize, name: &str) -> Option<usize> {
+    let name_end = start + 1 + name.len();
+    if !bytes
+        .get(start + 1..name_end)?
+        .eq_ignore_ascii_case(name.as_bytes())
+    {
+        return None;
+    }
+    let is_space = |b: u8| matches!(b, b' ' | b'\t' | b'\n' | b'\r' | b'\x0C');
+    let mut i = name_end;
+    if !matches!(bytes.get(i), Some(&b) if is_space(b) || b == b'/' || b == b'>') {
+        return None;
+    }
+
+    loop {
+        while bytes.get(i).is_some_and(|&b| is_space(b) || b == b'/') {
+            i += 1;
+        }
+        match bytes.get(i)? {
+            b'>' => return Some(i + 1),
+            // An attribute name may start with `=`
+            b'=' => i += 1,
+            _ => {}
+        }
+        while bytes
+            .get(i)
+            .is_some_and(|&b| !is_space(b) && !matches!(b, b'/' | b'>' | b'='))
+        {
+            i += 1;
+        }
+        while bytes.get(i).is_some_and(|&b| is_space(b)) {
+            i += 1;
+        }
+        if bytes.get(i) != Some(&b'=') {
+            continue;
+        }
+        i += 1;
+        while bytes.get(i).is_some_and(|&b| is_space(b)) {
+            i += 1;
+        }
+        match *bytes.get(i)? {
+            quote @ (b'"' | b'\'') => {
+                i += 1 + bytes[i + 1..].iter().position(|&b| b == quote)? + 1;
+            }
+            _ => {
+                while bytes.get(i).is_some_and(|&b| !is_space(b) && b != b'>') {
+                    i += 1;
+                }
+            }
+        }
+    }
+}
@@ -39,6 +39,14 @@ impl<'a> Stem<'a> {
         self.as_str().len()
     }
 
+    /// A sub-slice of this string, borrowing from the input when possible.
+    pub fn slice(&self, range: std::ops::Range<usize>) -> Stem<'a> {
+        match self {
+            Self::Borrowed(s) => Self::Borrowed(&s[range]),
+            Self::Owned(s) => Self::Owned(CompactString::new(&s[range])),
+        }
+    }
+
     pub fn push_str(&mut self, s: &str) {
         match self {
             Self::Owned(existing) => {
@@ -0,0 +1,404 @@
+//! Checking a patch list against a document without applying it.
+//!
+//! [`Document::validate_patches`] replays the slot and path logic of
+//! [`Document::apply_patches`] on a lightweight overlay: only the nodes a
+//! patch touches get their children (and text) copied, everything else is
+//! read from the document as is.
+
+use std::collections::HashMap;
+
+use cinereus::indextree::NodeId;
+use compact_str::CompactString;
+use tendril::StrTendril;
+
+use crate::diff::{DiffError, HEAD_SLOT, InsertContent, NodePath, Patch};
+use crate::dom::{self, Document, NodeKind};
+
+/// A patch that would fail to apply.
+#[derive(Debug)]
+pub struct PatchValidationError {
+    /// Index of the failing patch in the list.
+    pub index: usize,
+    /// The path that couldn't be resolved or pointed at the wrong kind of node.
+    pub path: NodePath,
+    /// What applying the patch would have failed with.
+    pub error: DiffError,
+}
+
+impl std::fmt::Display for PatchValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "patch {} at {}: {}", self.index, self.path, self.error)
+    }
+}
+
+impl std::error::Error for PatchValidationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A node as seen by the dry run: one of the document's, or one a patch created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum VNode {
+    Doc(NodeId),
+    New(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    Element,
+    Text,
+    Comment,
+    Other,
+}
+
+pub(crate) struct DryRun<'d, 'a> {
+    doc: &'d Document<'a>,
+    /// Shapes of the nodes created by patches, indexed by `VNode::New`
+    new_shapes: Vec<Shape>,
+    /// Children of every node whose children changed (or that is new)
+    children: HashMap<VNode, Vec<VNode>>,
+    /// Parents of every node that was inserted or taken out of the tree
+    parents: HashMap<VNode, Option<VNode>>,
+    /// Text of every text/comment node that changed (or is new)
+    texts: HashMap<VNode, CompactString>,
+    slots: HashMap<u32, VNode>,
+}
+
+impl<'d, 'a> DryRun<'d, 'a> {
+    /// Start a dry run with slot 0 at `root`, or at the body if `None`.
+    pub(crate) fn new(doc: &'d Document<'a>, root: Option<NodeId>) -> Self {
+        let mut run = Self {
+            doc,
+            new_shapes: Vec::new(),
+            children: HashMap::new(),
+            parents: HashMap::new(),
+            texts: HashMap::new(),
+            slots: HashMap::new(),
+        };
+        // Mirrors `init_patch_slots`, which creates an empty body if needed
+        let body = match root.or_else(|| doc.body()) {
+            Some(body) => VNode::Doc(body),
+            None => run.new_node(Shape::Element),
+        };
+        run.slots.insert(0, body);
+        if let Some(head) = doc.head() {
+            run.slots.insert(HEAD_SLOT, VNode::Doc(head));
+        }
+        run
+    }
+
+    /// Check every patch in order, stopping at the first one that would fail.
+    pub(crate) fn run(mut self, patches: &[Patch<'_>]) -> Result<(), PatchValidationError> {
+        for (index, patch) in patches.iter().enumerate() {
+            self.check(patch)
+                .map_err(|(path, error)| PatchValidationError {
+                    index,
+                    path: path.clone(),
+                    error,
+                })?;
+        }
+        Ok(())
+    }
+
+    fn new_node(&mut self, shape: Shape) -> VNode {
+        let node = VNode::New(self.new_shapes.len());
+        self.new_shapes.push(shape);
+        self.children.insert(node, Vec::new());
+        self.parents.insert(node, None);
+        node
+    }
+
+    fn shape(&self, node: VNode) -> Shape {
+        match node {
+            VNode::New(idx) => self.new_shapes[idx],
+            VNode::Doc(id) => match &self.doc.get(id).kind {
+                NodeKind::Element(_) => Shape::Element,
+                NodeKind::Text(_) => Shape::Text,
+                NodeKind::Comment(_) => Shape::Comment,
+                NodeKind::Document | NodeKind::TemplateContents => Shape::Other,
+            },
+        }
+    }
+
+    fn text(&self, node: VNode) -> &str {
+        if let Some(text) = self.texts.get(&node) {
+            return text;
+        }
+        match node {
+            VNode::Doc(id) => match &self.doc.get(id).kind {
+                NodeKind::Text(t) | NodeKind::Comment(t) => t,
+                _ => "",
+            },
+            VNode::New(_) => "",
+        }
+    }
+
+    fn child_count(&self, node: VNode) -> usize {
+        match (self.children.get(&node), node) {
+            (Some(children), _) => children.len(),
+            (None, VNode::Doc(id)) => self.doc.children(id).count(),
+            (None, VNode::New(_)) => 0,
+        }
+    }
+
+    fn child(&self, node: VNode, idx: usize) -> Option<VNode> {
+        match (self.children.get(&node), node) {
+            (Some(children), _) => children.get(idx).copied(),
+            (None, VNode::Doc(id)) => self.doc.children(id).nth(idx).map(VNode::Doc),
+            (None, VNode::New(_)) => None,
+        }
+    }
+
+    fn children_mut(&mut self, node: VNode) -> &mut Vec<VNode> {
+        let doc = self.doc;
+        self.children.entry(node).or_insert_with(|| match node {
+            VNode::Doc(id) => doc.children(id).map(VNode::Doc).collect(),
+            VNode::New(_) => Vec::new(),
+        })
+    }
+
+    fn parent(&self, node: VNode) -> Option<VNode> {
+        match (self.parents.get(&node), node) {
+            (Some(parent), _) => *parent,
+            (None, VNode::Doc(id)) => self.doc.logical_parent(id).map(VNode::Doc),
+            (None, VNode::New(_)) => None,
+        }
+    }
+
+    fn navigate(&self, path: &[u32]) -> Result<VNode, DiffError> {
+        let (&slot, rest) = path.split_first().ok_or(DiffError::EmptyPath)?;
+        let mut current = *self
+            .slots
+            .get(&slot)
+            .ok_or(DiffError::SlotNotFound { slot })?;
+        for &idx in rest {
+            current = self
+                .child(current, idx as usize)
+                .ok_or(DiffError::PathOutOfBounds {
+                    index: idx as usize,
+                })?;
+        }
+        Ok(current)
+    }
+
+    /// Take `node` out of its parent, leaving nothing in its place.
+    fn detach(&mut self, node: VNode) {
+        if let Some(parent) = self.parent(node) {
+            self.children_mut(parent).retain(|&c| c != node);
+        }
+        self.parents.insert(node, None);
+    }
+
+    /// Take `node` out of its parent, leaving an empty text node in its place.
+    fn replace_with_placeholder(&mut self, node: VNode) {
+        let Some(parent) = self.parent(node) else {
+            return;
+        };
+        let placeholder = self.new_node(Shape::Text);
+        let children = self.children_mut(parent);
+        if let Some(pos) = children.iter().position(|&c| c == node) {
+            children[pos] = placeholder;
+        }
+        self.parents.insert(placeholder, Some(parent));
+        self.parents.insert(node, None);
+    }
+
+    /// Mirrors `Document::insert_at`.
+    fn insert_at(
+        &mut self,
+        path: &[u32],
+        node: VNode,
+        detach_to_slot: Option<u32>,
+    ) -> Result<(), DiffError> {
+        if path.len() < 2 {
+            return Err(DiffError::EmptyPath);
+        }
+        let position = path[path.len() - 1] as usize;
+        let parent = self.navigate(&path[..path.len() - 1])?;
+
+        if let Some(slot) = detach_to_slot
+            && let Some(occupant) = self.child(parent, position)
+        {
+            self.detach(occupant);
+            self.slots.insert(slot, occupant);
+        }
+
+        let len = self.child_count(parent);
+        let padding: Vec<_> = (len..position)
+            .map(|_| self.new_node(Shape::Text))
+            .collect();
+        for &pad in &padding {
+            self.parents.insert(pad, Some(parent));
+        }
+        let children = self.children_mut(parent);
+        children.extend(padding);
+        children.insert(position.min(children.len()), node);
+        self.parents.insert(node, Some(parent));
+        Ok(())
+    }
+
+    fn new_content(&mut self, content: &InsertContent<'_>) -> VNode {
+        match content {
+            InsertContent::Element { children, .. } => {
+                let node = self.new_node(Shape::Element);
+                for child in children {
+                    let child = self.new_content(child);
+                    self.children_mut(node).push(child);
+                    self.parents.insert(child, Some(node));
+                }
+                node
+            }
+            InsertContent::Text(text) => self.new_text(Shape::Text, text),
+            InsertContent::Comment(text) => self.new_text(Shape::Comment, text),
+        }
+    }
+
+    fn new_text(&mut self, shape: Shape, text: &str) -> VNode {
+        let node = self.new_node(shape);
+        self.texts.insert(node, text.into());
+        node
+    }
+
+    /// Copy the shape of a node from another document (parsed opaque content).
+    fn copy_from(&mut self, doc: &Document<'_>, id: NodeId) -> VNode {
+        let node = match &doc.get(id).kind {
+            NodeKind::Element(_) => self.new_node(Shape::Element),
+            NodeKind::Text(t) => self.new_text(Shape::Text, t),
+            NodeKind::Comment(t) => self.new_text(Shape::Comment, t),
+            NodeKind::Document | NodeKind::TemplateContents => self.new_node(Shape::Other),
+        };
+        for child in doc.children(id) {
+            let child = self.copy_from(doc, child);
+            self.children_mut(node).push(child);
+            self.parents.insert(child, Some(node));
+        }
+        node
+    }
+
+    fn expect_text(&self, node: VNode) -> Result<(), DiffError> {
+        match self.shape(node) {
+            Shape::Text | Shape::Comment => Ok(()),
+            _ => Err(DiffError::NotATextNode),
+        }
+    }
+
+    fn expect_element(&self, node: VNode) -> Result<(), DiffError> {
+        match self.shape(node) {
+            Shape::Element => Ok(()),
+            _ => Err(DiffError::NotAnElement),
+        }
+    }
+
+    /// Dry-run one patch. On failure, returns the offending path with the error.
+    fn check<'p>(&mut self, patch: &'p Patch<'_>) -> Result<(), (&'p NodePath, DiffError)> {
+        match patch {
+            Patch::InsertElement {
+                at,
+                children,
+                detach_to_slot,
+                ..
+            } => {
+                let node = self.new_node(Shape::Element);
+                for child in children {
+                    let child = self.new_content(child);
+                    self.children_mut(node).push(child);
+                    self.parents.insert(child, Some(node));
+                }
+                self.insert_at(&at.0.0, node, *detach_to_slot)
+                    .map_err(|e| (&at.0, e))
+            }
+            Patch::InsertText {
+                at,
+                text,
+                detach_to_slot,
+            } => {
+                let node = self.new_text(Shape::Text, text);
+                self.insert_at(&at.0.0, node, *detach_to_slot)
+                    .map_err(|e| (&at.0, e))
+            }
+            Patch::InsertComment {
+                at,
+                text,
+                detach_to_slot,
+            } => {
+                let node = self.new_text(Shape::Comment, text);
+                self.insert_at(&at.0.0, node, *detach_to_slot)
+                    .map_err(|e| (&at.0, e))
+            }
+            Patch::Remove { node } => {
+                let target = self.navigate(&node.0.0).map_err(|e| (&node.0, e))?;
+                self.replace_with_placeholder(target);
+                Ok(())
+            }
+            Patch::SetText { path, text } => {
+                let node = self.navigate(&path.0).map_err(|e| (path, e))?;
+                self.expect_text(node).map_err(|e| (path, e))?;
+                self.texts.insert(node, text.as_str().into());
+                Ok(())
+            }
+            Patch::SpliceText {
+                path,
+                offset,
+                delete_len,
+                insert,
+            } => {
+                let node = self.navigate(&path.0).map_err(|e| (path, e))?;
+                self.expect_text(node).map_err(|e| (path, e))?;
+                let text = dom::splice_utf16(self.text(node), *offset, *delete_len, insert)
+                    .map_err(|e| (path, e))?;
+                self.texts.insert(node, text);
+                Ok(())
+            }
+            Patch::SetAttribute { path, .. } | Patch::RemoveAttribute { path, .. } => {
+                let node = self.navigate(&path.0).map_err(|e| (path, e))?;
+                self.expect_element(node).map_err(|e| (path, e))
+            }
+            Patch::UpdateProps { path, .. } => {
+                self.navigate(&path.0).map_err(|e| (path, e))?;
+                Ok(())
+            }
+            Patch::Move {
+                from,
+                to,
+                detach_to_slot,
+            } => {
+                let node = self.navigate(&from.0.0).map_err(|e| (&from.0, e))?;
+                if from.0.0.len() > 1 {
+                    self.replace_with_placeholder(node);
+                } else {
+                    self.detach(node);
+                }
+                self.insert_at(&to.0.0, node, *detach_to_slot)
+                    .map_err(|e| (&to.0, e))
+            }
+            Patch::OpaqueChanged { path, content } => {
+                let node = self.navigate(&path.0).map_err(|e| (path, e))?;
+                // Mirror the applier: the content replaces the children as parsed
+                let wrapper =
+                    StrTendril::from(format!("<html><body><div>{}</div></body></html>", content));
+                let parsed = dom::parse(&wrapper);
+                let div = parsed.body().and_then(|body| parsed.children(body).next());
+                for child in std::mem::take(self.children_mut(node)) {
+                    self.parents.insert(child, None);
+                }
+                if let Some(div) = div {
+                    for child in parsed.children(div) {
+                        let child = self.copy_from(&parsed, child);
+                        self.children_mut(node).push(child);
+                        self.parents.insert(child, Some(node));
+                    }
+                }
+                Ok(())
+            }
+            Patch::Detach { node, to_slot } => {
+                let target = self.navigate(&node.0.0).map_err(|e| (&node.0, e))?;
+                self.detach(target);
+                if let Some(slot) = to_slot {
+                    self.slots.insert(*slot, target);
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -5,7 +5,7 @@ use facet_testhelpers::test;
 use hotmeal::{debug, trace};
 
 use hotmeal::{
-    AttrPair, InsertContent, NodeKind, NodePath, NodeRef, Patch, Stem, StrTendril, parse,
+    AttrPair, InsertContent, Namespace, NodeKind, NodePath, NodeRef, Patch, Stem, StrTendril, parse,
 };
 use html5ever::{LocalName, QualName, local_name, ns};
 use smallvec::smallvec;
@@ -41,6 +41,39 @@ fn test_apply_set_text() {
     );
 }
 
+#[test]
+fn test_apply_splice_text() {
+    // "héllo 👋 world": the emoji is two UTF-16 code units
+    let html = t("<html><body><p>héllo 👋 world</p></body></html>");
+    let mut node = parse(&html);
+    node.apply_patches(vec![Patch::SpliceText {
+        path: NodePath(smallvec![0, 0, 0]),
+        offset: 9,
+        delete_len: 5,
+        insert: Stem::from("there"),
+    }])
+    .unwrap();
+    assert_eq!(
+        node.to_html(),
+        "<html><head></head><body><p>héllo 👋 there</p></body></html>"
+    );
+}
+
+#[test]
+fn test_apply_splice_text_out_of_bounds() {
+    let html = t("<html><body><p>a👋b</p></body></html>");
+    for (offset, delete_len) in [(5, 0), (2, 2), (0, 5)] {
+        let mut node = parse(&html);
+        let result = node.apply_patches(vec![Patch::SpliceText {
+            path: NodePath(smallvec![0, 0, 0]),
+            offset,
+            delete_len,
+            insert: Stem::from("x"),
+        }]);
+        assert!(result.is_err(), "{offset}+{delete_len} should be rejected");
+    }
+}
+
 #[test]
 fn test_apply_set_attribute() {
     let html = t("<html><body><div>Content</div></body></html>");
@@ -73,6 +106,190 @@ fn test_apply_remove() {
     );
 }
 
+#[test]
+fn test_apply_detach() {
+    let html = t("<html><body><p>First</p><p>Second</p><p>Third</p></body></html>");
+    let mut node = parse(&html);
+    // Unlike Remove, no placeholder is left: the third <p> moves up to [0, 1]
+    node.apply_patches(vec![
+        Patch::Detach {
+            node: NodeRef(NodePath(smallvec![0, 1])),
+            to_slot: None,
+        },
+        Patch::SetText {
+            path: NodePath(smallvec![0, 1, 0]),
+            text: Stem::from("Last"),
+        },
+    ])
+    .unwrap();
+    assert_eq!(
+        node.to_html(),
+        "<html><head></head><body><p>First</p><p>Last</p></body></html>"
+    );
+}
+
+#[test]
+fn test_apply_patches_with_inverse_restores_slots() {
+    let html = t("<html><body><p class=\"a\">First</p><div><i>x</i></div></body></html>");
+    let original = parse(&html);
+    let mut node = parse(&html);
+    let patches = vec![
+        // Displace the first <p> into slot 1
+        Patch::InsertElement {
+            at: NodeRef(NodePath(smallvec![0, 0])),
+            tag: LocalName::from("h1"),
+            ns: Namespace::Html,
+            attrs: vec![],
+            children: vec![InsertContent::Text(Stem::from("Title"))],
+            detach_to_slot: Some(1),
+        },
+        // Edit it while it's detached, then move it past the end of <div>
+        Patch::SetText {
+            path: NodePath(smallvec![1, 0]),
+            text: Stem::from("Moved"),
+        },
+        Patch::Move {
+            from: NodeRef(NodePath(smallvec![1])),
+            to: NodeRef(NodePath(smallvec![0, 1, 3])),
+            detach_to_slot: None,
+        },
+        Patch::RemoveAttribute {
+            path: NodePath(smallvec![0, 1, 3]),
+            name: QualName::new(None, ns!(), local_name!("class")),
+        },
+        Patch::Move {
+            from: NodeRef(NodePath(smallvec![0, 1, 0])),
+            to: NodeRef(NodePath(smallvec![0, 2])),
+            detach_to_slot: None,
+        },
+        Patch::Remove {
+            node: NodeRef(NodePath(smallvec![0, 1, 0])),
+        },
+    ];
+
+    let inverse = node.apply_patches_with_inverse(patches.clone()).unwrap();
+    let patched = node.to_html();
+    assert_eq!(
+        patched,
+        "<html><head></head><body><h1>Title</h1><div><p>Moved</p></div><i>x</i></body></html>"
+    );
+
+    node.apply_patches(inverse).unwrap();
+    assert_eq!(
+        node.dump_subtree(node.root),
+        original.dump_subtree(original.root)
+    );
+
+    node.apply_patches(patches).unwrap();
+    assert_eq!(node.to_html(), patched);
+}
+
+#[test]
+fn test_apply_patches_atomic_rolls_back() {
+    let html = t("<html><body><p class=\"a\">First</p><div><i>x</i></div></body></html>");
+    let mut node = parse(&html);
+    let before = node.dump_subtree(node.root);
+    let body = node.body().unwrap();
+    let first = node.children(body).next().unwrap();
+
+    let patches = vec![
+        Patch::InsertElement {
+            at: NodeRef(NodePath(smallvec![0, 0])),
+            tag: LocalName::from("h1"),
+            ns: Namespace::Html,
+            attrs: vec![],
+            children: vec![],
+            detach_to_slot: Some(1),
+        },
+        Patch::SetText {
+            path: NodePath(smallvec![1, 0]),
+            text: Stem::from("Changed"),
+        },
+        Patch::Move {
+            from: NodeRef(NodePath(smallvec![1])),
+            to: NodeRef(NodePath(smallvec![0, 1, 4])),
+            detach_to_slot: None,
+        },
+        Patch::Remove {
+            node: NodeRef(NodePath(smallvec![0, 1, 0])),
+        },
+        // Fails after taking the node out of the tree
+        Patch::Move {
+            from: NodeRef(NodePath(smallvec![0, 1, 4])),
+            to: NodeRef(NodePath(smallvec![0, 7, 0])),
+            detach_to_slot: None,
+        },
+    ];
+
+    let err = node.apply_patches_atomic(patches.clone()).unwrap_err();
+    assert!(matches!(
+        err,
+        hotmeal::DiffError::PathOutOfBounds { index: 7 }
+    ));
+    assert_eq!(node.dump_subtree(node.root), before);
+    assert_eq!(node.children(body).next(), Some(first));
+
+    // Without the failing patch, everything applies
+    node.apply_patches_atomic(patches[..4].to_vec()).unwrap();
+    assert_eq!(
+        node.to_html(),
+        "<html><head></head><body><h1></h1><div><p class=\"a\">Changed</p></div></body></html>"
+    );
+}
+
+#[test]
+fn test_validate_patches_reports_index_and_path() {
+    let html = t("<html><body><p>First</p><p>Second</p></body></html>");
+    let node = parse(&html);
+    let before = node.to_html();
+
+    // Slot 1 only exists once the first patch has displaced a node into it
+    let patches = vec![
+        Patch::InsertText {
+            at: NodeRef(NodePath(smallvec![0, 0])),
+            text: Stem::from("Intro"),
+            detach_to_slot: Some(1),
+        },
+        Patch::SetText {
+            path: NodePath(smallvec![1, 0]),
+            text: Stem::from("Displaced"),
+        },
+        Patch::Remove {
+            node: NodeRef(NodePath(smallvec![0, 5])),
+        },
+    ];
+    node.validate_patches(&patches[..2]).unwrap();
+
+    let err = node.validate_patches(&patches).unwrap_err();
+    assert_eq!(err.index, 2);
+    assert_eq!(err.path, NodePath(smallvec![0, 5]));
+    assert!(matches!(
+        err.error,
+        hotmeal::DiffError::PathOutOfBounds { index: 5 }
+    ));
+    assert_eq!(node.to_html(), before);
+
+    let err = node
+        .validate_patches(&[Patch::SetText {
+            path: NodePath(smallvec![2, 0]),
+            text: Stem::from("x"),
+        }])
+        .unwrap_err();
+    assert!(matches!(
+        err.error,
+        hotmeal::DiffError::SlotNotFound { slot: 2 }
+    ));
+
+    let err = node
+        .validate_patches(&[Patch::SetAttribute {
+            path: NodePath(smallvec![0, 0, 0]),
+            name: QualName::new(None, ns!(), local_name!("class")),
+            value: Stem::from("x"),
+        }])
+        .unwrap_err();
+    assert_eq!(err.to_string(), "patch 0 at 0.0.0: node is not an element");
+}
+
 #[test]
 fn test_apply_insert_element() {
     let html = t("<html><body><p>First</p></body></html>");
@@ -81,6 +298,7 @@ fn test_apply_insert_element() {
     node.apply_patches(vec![Patch::InsertElement {
         at: NodeRef(NodePath(smallvec![0, 0])),
         tag: LocalName::from("p"),
+        ns: Namespace::Html,
         attrs: vec![],
         children: vec![],
         detach_to_slot: Some(1),
@@ -100,6 +318,7 @@ fn test_apply_insert_element_no_displacement() {
     node.apply_patches(vec![Patch::InsertElement {
         at: NodeRef(NodePath(smallvec![0, 1])),
         tag: LocalName::from("p"),
+        ns: Namespace::Html,
         attrs: vec![],
         children: vec![],
         detach_to_slot: None,
@@ -119,6 +338,7 @@ fn test_apply_insert_element_with_children() {
     node.apply_patches(vec![Patch::InsertElement {
         at: NodeRef(NodePath(smallvec![0, 1])),
         tag: LocalName::from("p"),
+        ns: Namespace::Html,
         attrs: vec![],
         children: vec![InsertContent::Text(Stem::from("Second"))],
         detach_to_slot: None,
@@ -138,6 +358,7 @@ fn test_apply_insert_element_with_attrs() {
     node.apply_patches(vec![Patch::InsertElement {
         at: NodeRef(NodePath(smallvec![0, 1])),
         tag: LocalName::from("p"),
+        ns: Namespace::Html,
         attrs: vec![AttrPair {
             name: QualName::new(None, ns!(), local_name!("class")),
             value: Stem::from("highlight"),
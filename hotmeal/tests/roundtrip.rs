@@ -7,7 +7,10 @@
 //! <new HTML>
 //! ```
 //!
-//! The test verifies: apply(old, diff(old, new)) == new
+//! The test verifies: apply(old, diff(old, new)) == new, that the inverse
+//! patches recorded while applying restore old exactly, that normalizing the
+//! patched tree gives the tree of new, and that an atomic apply failing on
+//! its last patch leaves old untouched.
 
 use hotmeal::StrTendril;
 use hotmeal::diff;
@@ -40,7 +43,10 @@ fn run_roundtrip_test(path: &Path) -> datatest_stable::Result<()> {
     let patches = diff(&old_doc, &new_doc).map_err(|e| format!("diff failed: {e:?}"))?;
 
     let mut tree = parse(&old_tendril);
-    tree.apply_patches(patches)
+    tree.validate_patches(&patches)
+        .map_err(|e| format!("validation failed: {e}"))?;
+    let inverse = tree
+        .apply_patches_with_inverse(patches.clone())
         .map_err(|e| format!("apply failed: {e:?}"))?;
     let result = tree.to_html();
 
@@ -53,6 +59,63 @@ fn run_roundtrip_test(path: &Path) -> datatest_stable::Result<()> {
         .into());
     }
 
+    // Undo: the exact old tree, down to placeholder text nodes
+    tree.validate_patches(&inverse)
+        .map_err(|e| format!("inverse validation failed: {e}"))?;
+    tree.apply_patches(inverse.clone())
+        .map_err(|e| format!("applying inverse failed: {e:?}\nInverse: {inverse:#?}"))?;
+    let restored = tree.dump_subtree(tree.root);
+    let original = old_doc.dump_subtree(old_doc.root);
+    if restored != original {
+        return Err(format!(
+            "Undo failed!\nOld: {old}\nNew: {new}\nInverse: {inverse:#?}\nRestored:\n{restored}\nExpected:\n{original}"
+        )
+        .into());
+    }
+
+    // Redo
+    let patches_for_rollback = patches.clone();
+    tree.apply_patches(patches)
+        .map_err(|e| format!("redo failed: {e:?}"))?;
+    if tree.to_html() != expected {
+        return Err(format!("Redo failed!\nOld: {old}\nNew: {new}").into());
+    }
+
+    // Normalize: placeholders gone, nothing left to diff
+    tree.normalize();
+    let normalized = tree.dump_subtree(tree.root);
+    let target = new_doc.dump_subtree(new_doc.root);
+    if tree.to_html() != expected || normalized != target {
+        return Err(format!(
+            "Normalize failed!\nOld: {old}\nNew: {new}\nNormalized:\n{normalized}\nExpected:\n{target}"
+        )
+        .into());
+    }
+    let leftover = diff(&tree, &new_doc).map_err(|e| format!("diff failed: {e:?}"))?;
+    if !leftover.is_empty() {
+        return Err(format!(
+            "Normalized tree still differs!\nOld: {old}\nNew: {new}\nPatches: {leftover:#?}"
+        )
+        .into());
+    }
+
+    // Atomic apply: a failing last patch rolls everything back
+    let mut tree = parse(&old_tendril);
+    let mut doomed = patches_for_rollback;
+    doomed.push(hotmeal::Patch::Remove {
+        node: hotmeal::NodeRef(hotmeal::NodePath(smallvec::smallvec![0, u32::MAX - 1])),
+    });
+    if tree.apply_patches_atomic(doomed).is_ok() {
+        return Err("atomic apply of a failing patch list succeeded".into());
+    }
+    let rolled_back = tree.dump_subtree(tree.root);
+    if rolled_back != original {
+        return Err(format!(
+            "Rollback failed!\nOld: {old}\nNew: {new}\nRolled back:\n{rolled_back}\nExpected:\n{original}"
+        )
+        .into());
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,161 @@
+//! Serializer tests for every `SerializeOptions` mode, using datatest-stable.
+//!
+//! Each page in `tests/fixtures/`, and both sides of each case in
+//! `tests/roundtrip-cases/`, is serialized in every mode and parsed again,
+//! next to what its compact form parses to.
+//! Leaving out optional tags and quotes must give the exact same tree back.
+//! Pretty-printing and collapsing whitespace only change whitespace, so those
+//! trees are compared with whitespace-only text dropped and whitespace
+//! collapsed; minifying must give the exact tree collapsing whitespace does.
+
+use hotmeal::{Document, NodeId, NodeKind, SerializeOptions, StrTendril, parse};
+use std::path::Path;
+
+/// The tree under `node_id`, whitespace differences aside.
+fn normalized(doc: &Document, node_id: NodeId, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    match &doc.get(node_id).kind {
+        NodeKind::Element(elem) => {
+            out.push_str(&format!("{indent}<{}", elem.tag));
+            for (name, value) in &elem.attrs {
+                out.push_str(&format!(" {name:?}={value:?}"));
+            }
+            out.push_str(">\n");
+        }
+        NodeKind::Text(text) => {
+            let words: Vec<&str> = text.split_ascii_whitespace().collect();
+            if !words.is_empty() {
+                let lead = if text.starts_with(|c: char| c.is_ascii_whitespace()) {
+                    " "
+                } else {
+                    ""
+                };
+                let trail = if text.ends_with(|c: char| c.is_ascii_whitespace()) {
+                    " "
+                } else {
+                    ""
+                };
+                let text = format!("{lead}{}{trail}", words.join(" "));
+                out.push_str(&format!("{indent}{text:?}\n"));
+            }
+            return;
+        }
+        NodeKind::Comment(text) => {
+            out.push_str(&format!("{indent}<!--{text}-->\n"));
+            return;
+        }
+        NodeKind::Document | NodeKind::TemplateContents => {}
+    }
+    for child in doc.children(node_id) {
+        normalized(doc, child, depth + 1, out);
+    }
+}
+
+/// `doc` serialized with `options`, and what that parses to.
+fn reparse(
+    doc: &Document,
+    options: &SerializeOptions,
+) -> datatest_stable::Result<(String, Document<'static>)> {
+    let output = doc.to_html_with(options);
+    let reparsed = parse(&StrTendril::from(output.as_str())).into_owned();
+    if reparsed.doctype != doc.doctype {
+        return Err(format!("doctype changed\nOutput: {output}").into());
+    }
+    Ok((output, reparsed))
+}
+
+fn check_tree(
+    mode: &str,
+    output: &str,
+    expected: &str,
+    actual: &str,
+) -> datatest_stable::Result<()> {
+    if actual != expected {
+        return Err(format!(
+            "{mode}: reparsed tree differs\nOutput: {output}\nExpected:\n{expected}\nActual:\n{actual}"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn check_modes(html: &str) -> datatest_stable::Result<()> {
+    let input = StrTendril::from(html);
+    let doc = parse(&input);
+    if doc.to_html_with(&SerializeOptions::default()) != doc.to_html() {
+        return Err("default options must match to_html".into());
+    }
+
+    // Compare with what the compact form parses to, so that inputs it
+    // doesn't roundtrip don't get in the way
+    let compact = StrTendril::from(doc.to_html());
+    let doc = parse(&compact);
+    let exact = doc.dump_subtree(doc.root);
+    let mut loose = String::new();
+    normalized(&doc, doc.root, 0, &mut loose);
+
+    let omit_tags = SerializeOptions {
+        omit_optional_tags: true,
+        ..SerializeOptions::default()
+    };
+    let unquoted = SerializeOptions {
+        unquoted_attributes: true,
+        ..SerializeOptions::default()
+    };
+    let both = SerializeOptions {
+        omit_optional_tags: true,
+        unquoted_attributes: true,
+        ..SerializeOptions::default()
+    };
+    let collapse = SerializeOptions {
+        collapse_whitespace: true,
+        ..SerializeOptions::default()
+    };
+    for (mode, options) in [
+        ("omit tags", omit_tags),
+        ("unquoted", unquoted),
+        ("omit tags and unquoted", both),
+    ] {
+        let (output, reparsed) = reparse(&doc, &options)?;
+        check_tree(mode, &output, &exact, &reparsed.dump_subtree(reparsed.root))?;
+    }
+    for (mode, options) in [
+        ("pretty", &SerializeOptions::pretty()),
+        ("collapse", &collapse),
+    ] {
+        let (output, reparsed) = reparse(&doc, options)?;
+        let mut actual = String::new();
+        normalized(&reparsed, reparsed.root, 0, &mut actual);
+        check_tree(mode, &output, &loose, &actual)?;
+    }
+
+    // Minifying only changes whitespace the way collapsing it does
+    let (_, collapsed) = reparse(&doc, &collapse)?;
+    let (output, reparsed) = reparse(&doc, &SerializeOptions::minify())?;
+    check_tree(
+        "minify",
+        &output,
+        &collapsed.dump_subtree(collapsed.root),
+        &reparsed.dump_subtree(reparsed.root),
+    )?;
+    Ok(())
+}
+
+fn run_fixture(path: &Path) -> datatest_stable::Result<()> {
+    facet_testhelpers::setup();
+    check_modes(&std::fs::read_to_string(path)?)
+}
+
+fn run_roundtrip_case(path: &Path) -> datatest_stable::Result<()> {
+    facet_testhelpers::setup();
+    let content = std::fs::read_to_string(path)?;
+    for part in content.split("\n===\n") {
+        check_modes(part.trim())?;
+    }
+    Ok(())
+}
+
+datatest_stable::harness! {
+    { test = run_fixture, root = "tests/fixtures", pattern = r".*\.html$" },
+    { test = run_roundtrip_case, root = "tests/roundtrip-cases", pattern = r".*\.html$" },
+}
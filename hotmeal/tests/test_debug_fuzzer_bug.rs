@@ -1,11 +1,11 @@
 //! Debug the specific fuzzer bug
 
 use facet_testhelpers::test;
-use hotmeal::{NodePath, NodeRef, Patch, StrTendril};
-#[allow(unused_imports)]
-use hotmeal::{debug, trace};
+use hotmeal::{Namespace, NodePath, NodeRef, Patch, StrTendril};
 use html5ever::LocalName;
 use smallvec::smallvec;
+#[allow(unused_imports)]
+use tracing::{debug, trace};
 
 /// Helper to create a StrTendril from a string
 fn t(s: &str) -> StrTendril {
@@ -23,6 +23,7 @@ fn test_minimal_repro() {
     let patches = vec![Patch::InsertElement {
         at: NodeRef(NodePath(smallvec![0, 1])),
         tag: LocalName::from("p"),
+        ns: Namespace::Html,
         attrs: vec![],
         children: vec![],
         detach_to_slot: None,
@@ -52,6 +53,24 @@ fn test_move_within_same_parent() {
     trace!(html = %doc.to_html(), "HTML");
 }
 
+#[test]
+fn test_move_within_body_slot() {
+    // Body paths keep working now that the head has its own slot
+    let html = t("<html><head></head><body><div>1</div><div>2</div></body></html>");
+    let mut doc = hotmeal::parse(&html);
+
+    // The source keeps an empty placeholder, so the end of the body is position 2
+    let patches = vec![Patch::Move {
+        from: NodeRef(NodePath(smallvec![0, 0])),
+        to: NodeRef(NodePath(smallvec![0, 2])),
+        detach_to_slot: None,
+    }];
+
+    doc.apply_patches(patches)
+        .expect("should be able to swap siblings");
+    assert_eq!(doc.to_body_html(), "<div>2</div><div>1</div>");
+}
+
 /// Regression test for fuzzer crash-f9f4d0f90a4824d024b1aedd19e5ac64afefed5f
 /// Issue: img elements losing/gaining alt attribute incorrectly
 #[test]